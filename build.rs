@@ -1,4 +1,10 @@
+use std::path::PathBuf;
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tonic_build::compile_protos("proto/path_oram.proto")?;
+    // The encoded descriptors are what the server's optional reflection service hands out
+    let out_dir = PathBuf::from(std::env::var("OUT_DIR")?);
+    tonic_build::configure()
+        .file_descriptor_set_path(out_dir.join("path_oram_descriptor.bin"))
+        .compile_protos(&["proto/path_oram.proto"], &["proto"])?;
     Ok(())
-}
\ No newline at end of file
+}
@@ -1,17 +1,49 @@
-use clap::Parser;
+// tonic::Status is the error type for every backend call; boxing it buys nothing here.
+#![allow(clippy::result_large_err)]
+
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use clap::{Parser, Subcommand};
 use path_oram::{
-    path_oram_client::PathOramClient, Block, PrintRequest, ReadBlockRequest, ReadBlockResponse,
-    SetupRequest, SetupResponse, WriteBlockRequest,
+    path_oram_client::PathOramClient, Block, BucketChunk, FlushRequest, GetConfigRequest,
+    GetConfigResponse, PingRequest, PingResponse, PrintRequest, ReadBlockRequest,
+    ReadBlockResponse, SetupBulkResponse, SetupRequest, SetupResponse, StatsRequest, StatsResponse,
+    WriteBlockRequest, WriteBlockResponse,
 };
+use prost::Message;
 use rand::rngs::StdRng;
 use rand::{Rng, SeedableRng};
-use std::collections::HashMap;
-use std::fs::OpenOptions;
-use std::io::Write;
-use tokio::runtime::Runtime;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::future::Future;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::marker::PhantomData;
+use std::net::Ipv6Addr;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc as std_mpsc;
+use std::time::Duration;
+use tokio::runtime::{Handle, Runtime};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
 use tokio::time::Instant;
-use tonic::transport::Channel;
-use tonic::Request;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::metadata::{AsciiMetadataKey, AsciiMetadataValue};
+use tonic::service::interceptor::InterceptedService;
+use tonic::service::Interceptor;
+use tonic::transport::{Certificate, Channel, ClientTlsConfig, Endpoint, Uri};
+use tonic::{Request, Response, Status};
+use tracing::{debug, info, instrument, trace, warn, Level};
+use tracing_subscriber::EnvFilter;
+
+mod convert;
+mod error;
+#[cfg(test)]
+mod tests;
+#[cfg(test)]
+mod testutil;
+
+use error::OramError;
 
 pub mod path_oram {
     tonic::include_proto!("path_oram");
@@ -19,305 +51,5447 @@ pub mod path_oram {
 
 #[derive(Parser, Debug)]
 #[command(name = "Path ORAM Client", about = "Path ORAM gRPC Client in Rust")]
+#[command(subcommand_negates_reqs = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// log2 of the number of addresses
+    #[arg(long, required = true, value_parser = clap::value_parser!(i32).range(0..=62))]
+    n: Option<i32>,
+    #[arg(long, required = true)]
+    z: Option<i32>,
+    /// Payload bytes per block (values are stored little-endian in the first 8)
+    #[arg(long, required = true, value_parser = clap::value_parser!(i32).range(1..))]
+    b: Option<i32>,
+    /// Seed for the client's leaf choices; part of the stash-size log's file name
+    #[arg(long, default_value_t = 11)]
+    seed: u64,
+    /// Untimed reads before the measured ones (the full experiment uses 3000000)
+    #[arg(long, default_value_t = 30_000)]
+    warmup: u64,
+    /// Measured reads after warmup (the full experiment uses 7000000). Resuming from a
+    /// checkpoint needs the same --warmup, --iters and --pattern as the original run.
+    #[arg(long, default_value_t = 70_000)]
+    iters: u64,
+    /// Order in which the experiment reads addresses
+    #[arg(long, value_enum, default_value_t = Pattern::Sequential)]
+    pattern: Pattern,
+    /// Server host name or IP address
+    #[arg(long, default_value = "localhost")]
+    host: String,
+    /// Port for the server to listen on
+    #[arg(short, long, default_value = "50061")]
+    port: u16,
+    /// Full server URL (e.g. `http://gateway:8080/oram`), overriding `--host` and
+    /// `--port`. A path component is used as the RPC path prefix.
+    #[arg(long)]
+    endpoint: Option<Uri>,
+    /// `:authority` to send instead of the endpoint's host, for proxies that route on it
+    #[arg(long)]
+    authority: Option<String>,
+    /// Prefix for every RPC path (overrides any path in `--endpoint`)
+    #[arg(long)]
+    path_prefix: Option<String>,
+    /// PEM CA certificate to verify the server with; connects over TLS when given
+    #[arg(long)]
+    ca_cert: Option<PathBuf>,
+    /// Name to verify the server certificate against (defaults to the endpoint's host)
+    #[arg(long, requires = "ca_cert")]
+    domain: Option<String>,
+    /// Extra `name=value` metadata sent with every RPC, e.g. for gateway routing or auth
+    #[arg(long = "header", value_parser = parse_header)]
+    headers: Vec<(AsciiMetadataKey, AsciiMetadataValue)>,
+    /// File holding a 256-bit key as 64 hex characters; blocks are AES-GCM encrypted with
+    /// it before they reach the server
+    #[arg(long)]
+    key_file: Option<PathBuf>,
+    /// Log filter in `RUST_LOG` syntax, e.g. `debug` or `client=trace`. Spans cover each
+    /// access and its stash read/write-back; `debug` adds stash sizes, leaf remaps and RPC
+    /// latency, `trace` adds stash dumps and the server's tree after every access
+    #[arg(long, default_value = "info", value_parser = parse_log_filter)]
+    log_level: String,
+    /// File of `address,value` lines loaded straight into the stash after setup
+    #[arg(long)]
+    preload_stash: Option<PathBuf>,
+    /// Write the logical access sequence as an `R <addr>` / `W <addr>` trace
+    #[arg(long)]
+    export_trace: Option<PathBuf>,
+    /// After setup, replay this file of `R <addr>` / `W <addr> <value>` lines instead of
+    /// running the warmup and measured reads
+    #[arg(
+        long,
+        conflicts_with_all = ["warmup", "iters", "pattern", "checkpoint_every", "max_ops_per_sec", "padded_ops_per_sec"]
+    )]
+    trace: Option<PathBuf>,
+    /// Don't rewrite buckets whose contents didn't change (NOT oblivious: leaks occupancy).
+    /// Without effect on encrypted blocks, which are resealed under a fresh nonce each time
+    #[arg(long, conflicts_with = "key_file")]
+    skip_unchanged: bool,
+    /// Take path buckets the previous write-back just wrote from memory instead of the
+    /// server (NOT oblivious: the server sees which buckets each read skips)
+    #[arg(long)]
+    path_cache: bool,
+    /// Checkpoint client state and a server snapshot every N operations (0 = never)
+    #[arg(long, default_value_t = 0)]
+    checkpoint_every: u64,
+    /// Checkpoint base path. Checkpoints alternate between two slots: client state goes to
+    /// `<path>.<slot>.client`, the server writes `<path>.<slot>.server` inside its
+    /// --snapshot-dir (so the path must be relative), and `<path>.latest` names the slot
+    /// of the last complete pair
+    #[arg(long, default_value = "checkpoint")]
+    checkpoint_path: PathBuf,
+    /// Resume from the last complete checkpoint at this base path instead of running
+    /// setup. Start the server with `--restore-from <snapshot-dir>/<path>.<slot>.server`
+    /// first, for the slot `<path>.latest` names.
+    #[arg(long)]
+    resume_from: Option<PathBuf>,
+    /// File for the measured reads' stash sizes, one per line (default:
+    /// `stash_sizes_<run>.txt`). Truncated at the start of a run, appended to on resume.
+    #[arg(long)]
+    stash_log: Option<PathBuf>,
+    /// fsync the stash log every time it is flushed (progress reports and checkpoints),
+    /// not just at the end of the run, so a machine crash loses fewer sizes
     #[arg(long)]
+    stash_log_fsync: bool,
+    /// Cap the experiment at this many operations per second
+    #[arg(long, value_parser = parse_positive_rate)]
+    max_ops_per_sec: Option<f64>,
+    /// Access the server at exactly this many operations per second, with a dummy access
+    /// in every slot where no read is due under --max-ops-per-sec. Hides the real rate.
+    #[arg(long, value_parser = parse_positive_rate)]
+    padded_ops_per_sec: Option<f64>,
+    /// INSECURE: keep an address's leaf on read, so repeated reads hit the same path
+    #[arg(long)]
+    no_remap_on_read: bool,
+    /// Wait for each position-map level's write-back before reading the next level's
+    /// path, instead of overlapping the two
+    #[arg(long, requires = "posmap_fanout")]
+    no_posmap_pipeline: bool,
+    /// Fail the run once the stash holds more than this many blocks after a write-back
+    #[arg(long)]
+    max_stash: Option<usize>,
+    /// Evict at most this many real blocks into each bucket (Z' <= Z; defaults to Z)
+    #[arg(long)]
+    eviction_limit: Option<usize>,
+    /// Build the tree this many levels high (2^L leaves) instead of ceil(log2 N), to trade
+    /// longer paths for a smaller stash
+    #[arg(
+        long,
+        value_parser = clap::value_parser!(i32).range(0..=62),
+        conflicts_with = "resume_from"
+    )]
+    levels: Option<i32>,
+    /// Retry a path read or write this many times while the server is unreachable
+    #[arg(long, default_value_t = 0)]
+    max_retries: u32,
+    /// Wait before the first retry; doubles with every further retry
+    #[arg(long, default_value_t = 100)]
+    retry_backoff_ms: u64,
+    /// Server-side tree to use, so several clients can share one server ("" is the default)
+    #[arg(long, default_value = "")]
+    namespace: String,
+    /// Replace the namespace's trees if they are already set up, instead of refusing to
+    #[arg(long)]
+    force_setup: bool,
+    /// Keep the position map in recursive ORAMs on the server (in namespaces
+    /// `<namespace>/posmap1`, `/posmap2`, ...) with this many leaves per block
+    #[arg(
+        long,
+        value_parser = clap::value_parser!(i32).range(2..),
+        conflicts_with_all = ["resume_from", "checkpoint_every", "preload_stash"]
+    )]
+    posmap_fanout: Option<i32>,
+    /// Position-map size at which recursion stops and the client keeps a plain table
+    #[arg(
+        long,
+        default_value_t = 64,
+        value_parser = clap::value_parser!(i32).range(1..),
+        requires = "posmap_fanout"
+    )]
+    posmap_cutoff: i32,
+    /// Load the initial data by filling the whole tree in one pass instead of N writes
+    #[arg(long, conflicts_with = "posmap_fanout")]
+    bulk_setup: bool,
+    /// Check every read against an in-memory reference model and abort on a mismatch. The
+    /// model assumes the values setup wrote, which a preloaded stash may or may not replace
+    #[arg(long, conflicts_with = "preload_stash")]
+    shadow_check: bool,
+    /// Right after setup, write and read back a few addresses and check the ORAM
+    /// invariant over the whole tree, aborting before the run if anything is off
+    #[arg(long, conflicts_with = "resume_from")]
+    validate: bool,
+    /// Send path blocks as one Block message each instead of packed into a single bytes
+    /// field, e.g. to inspect them with protobuf tooling (costs more bandwidth)
+    #[arg(long)]
+    per_block_wire: bool,
+    /// Have the server send each bucket's checksum with every path read and fail on a
+    /// bucket that doesn't match (catches server-side corruption, not tampering)
+    #[arg(long)]
+    checksums: bool,
+    /// Largest gRPC message to send or accept, in bytes (default: tonic's 4 MiB limit on
+    /// received messages). Raise it on the server too for paths that don't fit.
+    #[arg(long, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    max_message_bytes: Option<usize>,
+}
+
+// Tools that run instead of the default experiment
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Run one workload through the flat and recursive position-map ORAMs (in process)
+    /// and compare client memory and round trips; with --server, also time the recursive
+    /// layout against the server with the position-map pipeline on and off
+    CompareRecursion(CompareArgs),
+    /// Render a stash-size log (one size per line, or `size count` histogram lines) as an
+    /// ASCII sparkline and histogram
+    PlotStash {
+        path: PathBuf,
+        /// Sparkline width in columns
+        #[arg(long, default_value_t = 80)]
+        width: usize,
+    },
+    /// Print a gnuplot script plotting the stash logs and occupancy CSVs found in a run
+    /// directory
+    GenPlotScript {
+        #[arg(long)]
+        run_dir: PathBuf,
+        /// Write the script here instead of stdout
+        #[arg(long)]
+        out: Option<PathBuf>,
+    },
+    /// Time a fixed number of accesses for every combination of the swept N, Z and B and
+    /// write one CSV row per run. Connects like the default experiment unless --local.
+    Bench(BenchArgs),
+    /// Connect like the default experiment and run commands read from stdin, one per
+    /// line (`help` lists them)
+    Repl {
+        /// Client state file: loaded at startup if it exists, written back on exit
+        #[arg(long)]
+        state: Option<PathBuf>,
+    },
+    /// Run the fixed eviction workload and compare its stash sizes against the golden trace
+    CheckGolden {
+        #[arg(long, default_value = "golden/stash_sizes.txt")]
+        path: PathBuf,
+        /// Overwrite the golden trace with this run's sizes (for intentional changes)
+        #[arg(long)]
+        update_golden: bool,
+    },
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Pattern {
+    /// 0, 1, ..., N-1, then around again
+    Sequential,
+    /// Uniformly random, drawn from a generator seeded by --seed
+    Random,
+}
+
+#[derive(clap::Args, Debug)]
+struct CompareArgs {
+    /// log2 of the number of addresses
+    #[arg(long, value_parser = clap::value_parser!(i32).range(0..=62))]
     n: i32,
     #[arg(long)]
     z: i32,
+    /// Accesses to run after setup
+    #[arg(long, default_value_t = 10_000)]
+    ops: i32,
+    #[arg(long, default_value_t = 11)]
+    seed: u64,
+    /// Leaves packed into each position-map block
+    #[arg(long, default_value_t = 16)]
+    fanout: i32,
+    /// Position-map size at which recursion stops and a client-side table is kept
+    #[arg(long, default_value_t = 64)]
+    cutoff: i32,
+    /// Also time the recursive layout against the server, with position-map write-backs
+    /// overlapping the next level's read and without (--no-posmap-pipeline), in
+    /// `<namespace>/pipelined` and `<namespace>/sequential`
     #[arg(long)]
-    b: i32,
-    /// Port for the server to listen on
-    #[arg(short, long, default_value = "50061")]
-    port: u16,
+    server: bool,
 }
 
-macro_rules! debug_rpc_call {
-    ($client:expr, $rt:expr) => {
-        if cfg!(debug_assertions) {
-            let request = Request::new(PrintRequest {});
-            $rt.block_on(async {
-                if let Err(e) = $client.print(request).await {
-                    println!("Debug RPC call failed: {:?}", e);
-                }
-            });
+#[derive(clap::Args, Debug)]
+struct BenchArgs {
+    /// log2 of the number of addresses, e.g. `10,12,14` or `10-16`
+    #[arg(long, value_parser = parse_sweep)]
+    n: Sweep,
+    /// Bucket sizes, e.g. `3-5`
+    #[arg(long, value_parser = parse_sweep)]
+    z: Sweep,
+    /// Payload bytes per block
+    #[arg(long, value_parser = parse_sweep, default_value = "8")]
+    b: Sweep,
+    /// Timed accesses per run
+    #[arg(long, default_value_t = 10_000)]
+    ops: u64,
+    /// Untimed accesses before the timed ones, to let the stash settle
+    #[arg(long, default_value_t = 0)]
+    warmup: u64,
+    #[arg(long, default_value_t = 11)]
+    seed: u64,
+    /// Load each tree in one pass instead of N writes (setup_secs changes accordingly)
+    #[arg(long)]
+    bulk_setup: bool,
+    /// Use an in-process tree instead of the server
+    #[arg(long)]
+    local: bool,
+    /// Columns: n,z,b,setup_secs,avg_access_us,peak_stash,rpcs_per_access,blocks_per_access
+    #[arg(long, default_value = "bench.csv")]
+    out: PathBuf,
+}
+
+// Values swept by `bench`: comma-separated positive numbers and inclusive `lo-hi` ranges.
+#[derive(Clone, Debug)]
+struct Sweep(Vec<i32>);
+
+fn parse_sweep(s: &str) -> Result<Sweep, String> {
+    let mut values = Vec::new();
+    for item in s.split(',') {
+        let number = |v: &str| match v.trim().parse::<i32>() {
+            Ok(v) if v > 0 => Ok(v),
+            _ => Err(format!(
+                "`{}` is not a positive number or lo-hi range",
+                item
+            )),
+        };
+        match item.split_once('-') {
+            Some((lo, hi)) => values.extend(number(lo)?..=number(hi)?),
+            None => values.push(number(item)?),
         }
-    };
+    }
+    if values.is_empty() {
+        return Err(format!("`{}` selects no values", s));
+    }
+    Ok(Sweep(values))
 }
 
-macro_rules! debug_println {
-    ($($arg:tt)*) => (if ::std::cfg!(debug_assertions) { ::std::println!($($arg)*); })
+fn parse_header(s: &str) -> Result<(AsciiMetadataKey, AsciiMetadataValue), String> {
+    let (name, value) = s
+        .split_once('=')
+        .ok_or_else(|| format!("`{}` is not a name=value header", s))?;
+    let name = name
+        .parse()
+        .map_err(|_| format!("bad header name `{}`", name))?;
+    let value = value
+        .parse()
+        .map_err(|_| format!("bad header value `{}`", value))?;
+    Ok((name, value))
 }
 
-pub struct PathORAMHandler<'a> {
-    client: PathOramClient<Channel>,
-    n: i32,
-    l: i32,
-    z: i32,
-    stash: HashMap<i32, i32>,
-    pmap: Vec<i32>,
-    num_leaves: i32,
-    rt: &'a Runtime, // Single runtime for all async calls
-    rng: StdRng,     // RNG as a struct member
+fn parse_positive_rate(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(rate) if rate > 0.0 && rate.is_finite() => Ok(rate),
+        _ => Err(format!("`{}` is not a positive rate", s)),
+    }
 }
 
-impl<'a> PathORAMHandler<'a> {
-    pub fn new(client: PathOramClient<Channel>, z: i32, rt: &'a Runtime, rng_seed: u64) -> Self {
-        PathORAMHandler {
-            client,
-            n: -1,
-            l: -1,
-            z,
-            stash: HashMap::new(),
-            pmap: Vec::new(),
-            num_leaves: 0,
-            rt,
-            rng: StdRng::seed_from_u64(rng_seed),
+fn parse_log_filter(s: &str) -> Result<String, String> {
+    EnvFilter::try_new(s)
+        .map(|_| s.to_string())
+        .map_err(|e| format!("bad log filter `{}`: {}", s, e))
+}
+
+// Position-map slot for address `a`, with a clear panic for a negative address instead of
+// a wrapped index.
+fn slot(a: i64) -> usize {
+    convert::idx(a).unwrap_or_else(|e| panic!("invalid address: {}", e))
+}
+
+// Storage the handler talks to. Bucket indices and block layout follow the proto:
+// `read_path` returns the buckets for `indices` concatenated in order, and
+// `write_path` expects exactly `bucket_size` blocks per index.
+pub trait OramBackend {
+    fn setup(&mut self, num_layers: i32, bucket_size: i32, block_size: i32)
+        -> Result<bool, Status>;
+    fn read_path(&mut self, indices: Vec<i64>) -> Result<Vec<Block>, Status>;
+    fn write_path(&mut self, indices: Vec<i64>, blocks: Vec<Block>) -> Result<bool, Status>;
+    fn print(&mut self) -> Result<bool, Status>;
+    fn get_config(&mut self) -> Result<GetConfigResponse, Status>;
+
+    // `read_path`, plus each bucket's checksum (`bucket_crc32`) as the storage recorded it
+    // when the bucket was written, if it keeps them. By default it doesn't.
+    fn read_path_checked(
+        &mut self,
+        indices: Vec<i64>,
+    ) -> Result<(Vec<Block>, Option<Vec<u32>>), Status> {
+        Ok((self.read_path(indices)?, None))
+    }
+
+    // Like `print`, but the storage renders the tree into `path` (on its side; for the
+    // server, a name relative to its --print-dir) and returns where it went.
+    fn print_to_file(&mut self, _path: String) -> Result<String, Status> {
+        Err(Status::unimplemented("backend can't print to a file"))
+    }
+
+    // Asks the storage to snapshot itself to `path` (interpreted by the storage side; the
+    // server takes it relative to its --snapshot-dir).
+    fn flush(&mut self, _path: String) -> Result<bool, Status> {
+        Err(Status::unimplemented("backend has no persistent storage"))
+    }
+
+    // How much memory the storage's tree takes up (see `StatsResponse` in the proto).
+    fn stats(&mut self) -> Result<StatsResponse, Status> {
+        Err(Status::unimplemented("backend doesn't report its size"))
+    }
+
+    // Encoded bytes (sent, received) of every path read and write so far. Storage that
+    // isn't behind a wire has nothing to count.
+    fn wire_bytes(&self) -> (u64, u64) {
+        (0, 0)
+    }
+
+    // Cheap liveness check. In-process storage is always up, so by default this only
+    // reports whether setup has run.
+    fn ping(&mut self) -> Result<PingResponse, Status> {
+        Ok(PingResponse {
+            uptime_ms: 0,
+            initialized: self.get_config()?.initialized,
+        })
+    }
+
+    // Loading a whole tree after `setup`: `begin_bulk`, then `send_buckets` with runs of
+    // `count` consecutive buckets starting at `first`, then `finish_bulk`. The tree isn't
+    // guaranteed to hold any of them until `finish_bulk` returns. By default each run is
+    // just a `write_path`.
+    fn begin_bulk(&mut self) -> Result<(), Status> {
+        Ok(())
+    }
+
+    fn send_buckets(&mut self, first: i64, count: usize, blocks: Vec<Block>) -> Result<(), Status> {
+        let indices = (first..).take(count).collect();
+        self.write_path(indices, blocks).and_then(write_landed)
+    }
+
+    fn finish_bulk(&mut self) -> Result<(), Status> {
+        Ok(())
+    }
+
+    // Sends a `write_path` without waiting for it to land; `finish_writes` waits for it
+    // and returns its error, if any. Later requests on the same backend still reach the storage
+    // after it. By default the write simply happens here.
+    fn start_write_path(&mut self, indices: Vec<i64>, blocks: Vec<Block>) -> Result<(), Status> {
+        self.write_path(indices, blocks).and_then(write_landed)
+    }
+
+    fn finish_writes(&mut self) -> Result<(), Status> {
+        Ok(())
+    }
+}
+
+// A `write_path` that answered `false` instead of failing, as an error.
+fn write_landed(landed: bool) -> Result<(), Status> {
+    if landed {
+        Ok(())
+    } else {
+        Err(Status::internal("storage reported that the write failed"))
+    }
+}
+
+// Adds fixed metadata (`--header`) to every outgoing RPC.
+#[derive(Clone, Default)]
+pub struct ProxyHeaders {
+    headers: Vec<(AsciiMetadataKey, AsciiMetadataValue)>,
+}
+
+impl Interceptor for ProxyHeaders {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        for (name, value) in &self.headers {
+            request.metadata_mut().insert(name.clone(), value.clone());
         }
+        Ok(request)
     }
+}
 
-    pub fn initialize_server(&mut self, num_layers: i32, bucket_size: i32) {
-        let request = Request::new(SetupRequest {
-            num_layers,
-            bucket_size,
-        });
+pub type OramClient = PathOramClient<InterceptedService<Channel, ProxyHeaders>>;
 
-        let result = self.rt.block_on(self.client.setup(request));
-        match result {
-            Ok(response) => {
-                let setup_response: SetupResponse = response.into_inner();
-                if setup_response.success {
-                    println!("Server initialized.");
-                } else {
-                    println!("Initialization failed.");
-                }
-            }
-            Err(e) => println!("Failed to initialize server: {:?}", e),
+// Connects to `--endpoint` (or localhost:`--port`). `:authority` and the path prefix are
+// pseudo-headers, so they go through the channel origin and the client origin rather
+// than the interceptor, which only sees ordinary metadata.
+fn connect(rt: &Runtime, args: &Args) -> Result<OramClient, String> {
+    let uri = match &args.endpoint {
+        Some(uri) => uri.clone(),
+        None => {
+            // A bare IPv6 address needs brackets before a port can follow it
+            let host = match args.host.parse::<Ipv6Addr>() {
+                Ok(_) => format!("[{}]", args.host),
+                Err(_) => args.host.clone(),
+            };
+            format!("http://{}:{}", host, args.port)
+                .parse()
+                .map_err(|e| format!("bad host `{}`: {}", args.host, e))?
+        }
+    };
+    let scheme = match args.ca_cert {
+        Some(_) => "https",
+        None => uri.scheme_str().unwrap_or("http"),
+    };
+    let host = uri
+        .authority()
+        .ok_or_else(|| format!("endpoint `{}` has no host", uri))?;
+
+    let mut endpoint = Endpoint::from_shared(format!("{}://{}", scheme, host))
+        .map_err(|e| format!("bad endpoint `{}`: {}", uri, e))?;
+    if let Some(path) = &args.ca_cert {
+        let pem = std::fs::read(path)
+            .map_err(|e| format!("failed to read CA certificate {}: {}", path.display(), e))?;
+        let mut tls = ClientTlsConfig::new().ca_certificate(Certificate::from_pem(pem));
+        if let Some(domain) = &args.domain {
+            tls = tls.domain_name(domain);
         }
+        endpoint = endpoint
+            .tls_config(tls)
+            .map_err(|e| format!("bad TLS configuration: {}", e))?;
+    }
+    if let Some(authority) = &args.authority {
+        let origin = format!("{}://{}", scheme, authority)
+            .parse()
+            .map_err(|e| format!("bad authority `{}`: {}", authority, e))?;
+        endpoint = endpoint.origin(origin);
     }
+    let channel = rt
+        .block_on(endpoint.connect())
+        .map_err(|e| format!("failed to connect to {}: {}", uri, e))?;
 
-    pub fn setup(&mut self, data: Vec<i32>) {
-        self.n = data.len() as i32;
-        self.l = (self.n as f64).log2().ceil() as i32;
-        self.num_leaves = if self.l > 0 {
-            2_i32.pow(self.l as u32)
-        } else {
-            0
-        };
+    let prefix = match &args.path_prefix {
+        Some(prefix) => prefix.as_str(),
+        None => uri.path(),
+    };
+    let origin: Uri = format!("/{}", prefix.trim_matches('/'))
+        .parse()
+        .map_err(|e| format!("bad path prefix `{}`: {}", prefix, e))?;
+    let interceptor = ProxyHeaders {
+        headers: args.headers.clone(),
+    };
+    let client = PathOramClient::with_origin(InterceptedService::new(channel, interceptor), origin);
+    Ok(match args.max_message_bytes {
+        Some(limit) => client
+            .max_decoding_message_size(limit)
+            .max_encoding_message_size(limit),
+        None => client,
+    })
+}
 
-        self.initialize_server(self.l + 1, self.z);
+// The tonic client, driven from a shared blocking runtime.
+pub struct GrpcBackend {
+    client: OramClient,
+    rt: Handle,        // Single runtime for all async calls
+    namespace: String, // Server-side tree this backend works on ("" is the default)
+    // Path reads and writes failing with UNAVAILABLE are retried this many times, waiting
+    // `retry_backoff` before the first retry and twice as long before each further one
+    max_retries: u32,
+    retry_backoff: Duration,
+    bulk: Option<BulkUpload>, // SetupBulk stream between begin_bulk and finish_bulk
+    force_setup: bool,        // Whether `setup` may replace a tree that is already set up
+    // Whether path reads and writes use the packed wire format, which needs the tree's
+    // block size; that is learned from `setup` or `get_config`
+    packed: bool,
+    block_size: Option<usize>,
+    checksums: bool, // Whether path reads ask for each bucket's checksum
+    pending_write: Option<PendingWrite>, // From `start_write_path`, until `finish_writes`
+    // Encoded ReadBlock/WriteBlock message bytes so far: (sent, received)
+    wire_bytes: (u64, u64),
+}
 
-        self.pmap = (0..self.n)
-            .map(|_| self.rng.gen_range(0..self.num_leaves))
-            .collect();
+// A write_block call running on the runtime, the number of buckets it writes, and a copy
+// of its request to resend if it fails transiently (only kept when retries are on).
+struct PendingWrite {
+    call: JoinHandle<Result<Response<WriteBlockResponse>, Status>>,
+    buckets: usize,
+    retry: Option<WriteBlockRequest>,
+}
 
-        for (a, value) in data.iter().enumerate() {
-            self.write(a as i32, *value);
-        }
-        println!("Data written to server");
+// Fails unless the server says it wrote every one of the `buckets` sent.
+fn check_write(response: &WriteBlockResponse, buckets: usize) -> Result<(), Status> {
+    write_landed(response.success)?;
+    if response.buckets_written != buckets as i64 {
+        return Err(Status::data_loss(format!(
+            "sent {} buckets but the server wrote {}",
+            buckets, response.buckets_written
+        )));
     }
+    Ok(())
+}
 
-    pub fn update_stash(&mut self, _a: i32, x: i32) {
-        let mut indices = Vec::new();
+// Chunks buffered ahead of a SetupBulk stream before `send_buckets` waits for the server.
+const BULK_QUEUE_CHUNKS: usize = 2;
 
-        // Collect all indices for the RPC call
-        for l in 0..=self.l {
-            let index = self.get_index(x, l);
-            indices.push(index);
+// An open SetupBulk call, running on the runtime and fed chunks through `chunks`. The
+// server only keeps what it got once a chunk marked `last` arrives, so dropping this
+// without `finish_bulk` leaves the tree as it was.
+struct BulkUpload {
+    chunks: mpsc::Sender<BucketChunk>,
+    call: JoinHandle<Result<Response<SetupBulkResponse>, Status>>,
+    buckets_sent: i64,
+}
+
+impl BulkUpload {
+    // Ends the stream, with the `last` marker if `last` is given, and waits for the
+    // server's answer.
+    fn finish(self, rt: &Handle, last: Option<BucketChunk>) -> Result<SetupBulkResponse, Status> {
+        let BulkUpload { chunks, call, .. } = self;
+        if let Some(last) = last {
+            // If the call already ended, its result below says why
+            let _ = rt.block_on(chunks.send(last));
         }
+        drop(chunks);
+        let response = rt
+            .block_on(call)
+            .map_err(|e| Status::internal(format!("SetupBulk task failed: {}", e)))??;
+        Ok(response.into_inner())
+    }
+}
 
-        // Create and send a single ReadBlockRequest with the list of indices
-        let request = Request::new(ReadBlockRequest { indices });
+impl GrpcBackend {
+    pub fn new(client: OramClient, rt: Handle) -> Self {
+        GrpcBackend {
+            client,
+            rt,
+            namespace: String::new(),
+            max_retries: 0,
+            retry_backoff: Duration::ZERO,
+            bulk: None,
+            force_setup: false,
+            packed: true,
+            block_size: None,
+            checksums: false,
+            pending_write: None,
+            wire_bytes: (0, 0),
+        }
+    }
 
-        let result = self.rt.block_on(self.client.read_block(request));
-        match result {
-            Ok(response) => {
-                let read_response: ReadBlockResponse = response.into_inner();
-                for block in read_response.blocks {
-                    if block.index != -1 {
-                        self.stash.insert(block.index, block.value);
-                    }
+    // Points every request at the tree named `namespace`; `setup` creates it.
+    pub fn set_namespace(&mut self, namespace: String) {
+        self.namespace = namespace;
+    }
+
+    // Lets `setup` replace a tree that is already set up. Off by default, so a client
+    // can't wipe out a tree another one is using; the server refuses with
+    // FAILED_PRECONDITION instead.
+    pub fn set_force_setup(&mut self, force: bool) {
+        self.force_setup = force;
+    }
+
+    // Chooses between the packed wire format for path blocks (the default; see the
+    // proto) and one Block message per block.
+    pub fn set_packed(&mut self, packed: bool) {
+        self.packed = packed;
+    }
+
+    // Asks for each bucket's checksum with every path read, so `read_path_checked` can
+    // return them. Off by default; it costs four bytes per bucket.
+    pub fn set_checksums(&mut self, checksums: bool) {
+        self.checksums = checksums;
+    }
+
+    // Payload width to pack path blocks with, if packing is on and the width is known.
+    fn packed_width(&self) -> Option<usize> {
+        self.block_size.filter(|_| self.packed)
+    }
+
+    fn write_request(
+        &self,
+        indices: Vec<i64>,
+        blocks: Vec<Block>,
+    ) -> Result<WriteBlockRequest, Status> {
+        Ok(match self.packed_width() {
+            Some(width) => WriteBlockRequest {
+                indices,
+                blocks: Vec::new(),
+                namespace: self.namespace.clone(),
+                packed: pack_blocks(&blocks, width)?,
+            },
+            None => WriteBlockRequest {
+                indices,
+                blocks,
+                namespace: self.namespace.clone(),
+                packed: Vec::new(),
+            },
+        })
+    }
+
+    // Retries path reads and writes that fail because the server is unreachable, e.g.
+    // while it restarts. The channel reconnects on its own at the next call, so a retry
+    // is just the same request sent again. That is safe for the ORAM state: a read has
+    // no effect, and the server applies a write's buckets in one uninterrupted pass, so
+    // it either never landed or landed completely and resending it writes the same bytes.
+    pub fn set_retry(&mut self, max_retries: u32, backoff: Duration) {
+        self.max_retries = max_retries;
+        self.retry_backoff = backoff;
+    }
+
+    // Sends `request` through `call`, resending it as configured by `set_retry`. The
+    // request is only cloned while a retry is still possible.
+    fn with_retries<R: Clone, T>(
+        &mut self,
+        what: &str,
+        request: R,
+        mut call: impl FnMut(&mut OramClient, &Handle, R) -> Result<T, Status>,
+    ) -> Result<T, Status> {
+        let mut request = Some(request);
+        let mut backoff = self.retry_backoff;
+        let mut retries = 0;
+        loop {
+            let attempt = if retries < self.max_retries {
+                request.clone()
+            } else {
+                request.take()
+            };
+            let attempt = attempt.expect("the request is kept until the last attempt");
+            match call(&mut self.client, &self.rt, attempt) {
+                Err(status) if is_transient(&status) && retries < self.max_retries => {
+                    retries += 1;
+                    warn!(
+                        retry = retries,
+                        backoff_ms = backoff.as_millis() as u64,
+                        "{} failed: {}",
+                        what,
+                        status.message()
+                    );
+                    std::thread::sleep(backoff);
+                    backoff *= 2;
                 }
+                result => return result,
             }
-            Err(e) => println!("Failed to read block: {:?}", e),
         }
     }
+}
+
+// Lays `blocks` out back to back as in a `packed` field, refusing any whose payload
+// isn't `width` bytes, which would shift every block after it.
+fn pack_blocks(blocks: &[Block], width: usize) -> Result<Vec<u8>, Status> {
+    let mut packed = Vec::with_capacity(blocks.len() * (8 + width));
+    for block in blocks {
+        if block.data.len() != width {
+            return Err(Status::invalid_argument(format!(
+                "Block for address {} has {} bytes, expected {}",
+                block.index,
+                block.data.len(),
+                width
+            )));
+        }
+        packed.extend_from_slice(&block.index.to_le_bytes());
+        packed.extend_from_slice(&block.data);
+    }
+    Ok(packed)
+}
+
+// CRC-32 of a bucket's blocks laid out as in a `packed` field, as the server computes it
+// (see the proto).
+fn bucket_crc32(bucket: &[Block]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    for block in bucket {
+        hasher.update(&block.index.to_le_bytes());
+        hasher.update(&block.data);
+    }
+    hasher.finalize()
+}
+
+// Splits a `packed` field back into blocks with `width`-byte payloads.
+fn unpack_blocks(packed: &[u8], width: usize) -> Result<Vec<Block>, Status> {
+    let stride = 8 + width;
+    if !packed.len().is_multiple_of(stride) {
+        return Err(Status::internal(format!(
+            "Server packed {} bytes, not a multiple of {} for {}-byte blocks",
+            packed.len(),
+            stride,
+            width
+        )));
+    }
+    Ok(packed
+        .chunks_exact(stride)
+        .map(|block| Block {
+            index: decode_i64(block),
+            data: block[8..].to_vec(),
+        })
+        .collect())
+}
+
+// Whether `status` means the server could not be reached, rather than that it rejected
+// the request. A connection dropped mid-call comes back as UNKNOWN with the transport
+// error as its source, not as UNAVAILABLE.
+fn is_transient(status: &Status) -> bool {
+    status.code() == tonic::Code::Unavailable
+        || std::error::Error::source(status).is_some_and(|e| e.is::<tonic::transport::Error>())
+}
+
+impl OramBackend for GrpcBackend {
+    fn setup(
+        &mut self,
+        num_layers: i32,
+        bucket_size: i32,
+        block_size: i32,
+    ) -> Result<bool, Status> {
+        let request = Request::new(SetupRequest {
+            num_layers,
+            bucket_size,
+            block_size,
+            namespace: self.namespace.clone(),
+            force: self.force_setup,
+        });
+        let response: SetupResponse = self.rt.block_on(self.client.setup(request))?.into_inner();
+        self.block_size = usize::try_from(block_size).ok();
+        Ok(response.success)
+    }
+
+    fn read_path(&mut self, indices: Vec<i64>) -> Result<Vec<Block>, Status> {
+        Ok(self.read_path_checked(indices)?.0)
+    }
 
-    pub fn write_back_stash(&mut self, x: i32) {
-        let mut write_block_request = WriteBlockRequest {
-            indices: Vec::new(),
-            blocks: Vec::new(),
+    fn read_path_checked(
+        &mut self,
+        indices: Vec<i64>,
+    ) -> Result<(Vec<Block>, Option<Vec<u32>>), Status> {
+        self.finish_writes()?;
+        let width = self.packed_width();
+        let buckets = indices.len();
+        let request = ReadBlockRequest {
+            indices,
+            namespace: self.namespace.clone(),
+            packed: width.is_some(),
+            checksums: self.checksums,
         };
-    
-        for l in (0..=self.l).rev() {
-            let target_index = self.get_index(x, l);
-            let valid_leaves: std::collections::HashSet<i32> = self.get_on_path_indices(x, l).collect();
-            debug_println!("{:?}", valid_leaves);
-    
-            let mut write_back = Vec::new();
-            for &a in self.stash.keys() {
-                if valid_leaves.contains(&self.pmap[a as usize]) {
-                    write_back.push(a);
-                }
-                if write_back.len() == self.z as usize {
-                    break;
-                }
-            }
-    
-            // Add the target index to the request
-            write_block_request.indices.push(target_index);
-    
-            // Collect blocks for this index, filling with dummy blocks if needed
-            let mut blocks_for_index = Vec::new();
-            for a in &write_back {
-                blocks_for_index.push(Block {
-                    value: self.stash[a],
-                    index: *a,
-                });
-                self.stash.remove(a);
-            }
-    
-            while blocks_for_index.len() < self.z as usize {
-                blocks_for_index.push(Block {
-                    value: -1,
-                    index: -1,
-                });
+        self.wire_bytes.0 += request.encoded_len() as u64;
+        let response: ReadBlockResponse = self
+            .with_retries("read_block", request, |client, rt, request| {
+                rt.block_on(client.read_block(Request::new(request)))
+            })?
+            .into_inner();
+        self.wire_bytes.1 += response.encoded_len() as u64;
+        // Checksums asked for must come back, one per bucket: a server that leaves them out
+        // would otherwise turn corruption detection off without a sign
+        let crcs = match response.crc32.len() {
+            _ if !self.checksums => None,
+            n if n == buckets => Some(response.crc32),
+            n => {
+                return Err(Status::internal(format!(
+                    "Server sent {} checksums for {} buckets",
+                    n, buckets
+                )))
             }
+        };
+        let blocks = match width {
+            // A server that doesn't pack answers with `blocks` as before
+            Some(width) if response.blocks.is_empty() => unpack_blocks(&response.packed, width)?,
+            _ => response.blocks,
+        };
+        Ok((blocks, crcs))
+    }
+
+    fn write_path(&mut self, indices: Vec<i64>, blocks: Vec<Block>) -> Result<bool, Status> {
+        self.finish_writes()?;
+        let buckets = indices.len();
+        let request = self.write_request(indices, blocks)?;
+        self.wire_bytes.0 += request.encoded_len() as u64;
+        let response = self
+            .with_retries("write_block", request, |client, rt, request| {
+                rt.block_on(client.write_block(Request::new(request)))
+            })?
+            .into_inner();
+        self.wire_bytes.1 += response.encoded_len() as u64;
+        check_write(&response, buckets)?;
+        Ok(true)
+    }
+
+    // Spawns the write_block call on the runtime. Only one is in flight at a time: a
+    // previous one is waited for first, so writes land in the order they were started.
+    fn start_write_path(&mut self, indices: Vec<i64>, blocks: Vec<Block>) -> Result<(), Status> {
+        self.finish_writes()?;
+        let buckets = indices.len();
+        let request = self.write_request(indices, blocks)?;
+        self.wire_bytes.0 += request.encoded_len() as u64;
+        let retry = (self.max_retries > 0).then(|| request.clone());
+        let mut client = self.client.clone();
+        let call = self
+            .rt
+            .spawn(async move { client.write_block(Request::new(request)).await });
+        self.pending_write = Some(PendingWrite {
+            call,
+            buckets,
+            retry,
+        });
+        Ok(())
+    }
 
-            // Append blocks for this index to the main blocks list
-            write_block_request.blocks.extend(blocks_for_index);
+    // A started write that failed transiently is resent as `set_retry` allows, like any
+    // other write.
+    fn finish_writes(&mut self) -> Result<(), Status> {
+        let Some(PendingWrite {
+            call,
+            buckets,
+            retry,
+        }) = self.pending_write.take()
+        else {
+            return Ok(());
+        };
+        let result = self
+            .rt
+            .block_on(call)
+            .map_err(|e| Status::internal(format!("write_block task failed: {}", e)))?;
+        let response = match (result, retry) {
+            (Err(status), Some(request)) if is_transient(&status) => {
+                warn!("write_block failed: {}", status.message());
+                self.with_retries("write_block", request, |client, rt, request| {
+                    rt.block_on(client.write_block(Request::new(request)))
+                })?
+            }
+            (result, _) => result?,
         }
+        .into_inner();
+        self.wire_bytes.1 += response.encoded_len() as u64;
+        check_write(&response, buckets)
+    }
+
+    fn print(&mut self) -> Result<bool, Status> {
+        self.finish_writes()?;
+        let request = Request::new(PrintRequest {
+            namespace: self.namespace.clone(),
+            ..Default::default()
+        });
+        let response = self.rt.block_on(self.client.print(request))?.into_inner();
+        Ok(response.success)
+    }
+
+    fn print_to_file(&mut self, path: String) -> Result<String, Status> {
+        self.finish_writes()?;
+        let request = Request::new(PrintRequest {
+            namespace: self.namespace.clone(),
+            path,
+        });
+        let response = self.rt.block_on(self.client.print(request))?.into_inner();
+        Ok(response.path)
+    }
 
-        debug_println!("write request: {:?}", write_block_request);
-    
-        // Send the batched write request
-        if let Err(e) = self
+    fn get_config(&mut self) -> Result<GetConfigResponse, Status> {
+        let request = Request::new(GetConfigRequest {
+            namespace: self.namespace.clone(),
+        });
+        let response = self
             .rt
-            .block_on(self.client.write_block(Request::new(write_block_request)))
-        {
-            println!("Failed to write block: {:?}", e);
+            .block_on(self.client.get_config(request))?
+            .into_inner();
+        if response.initialized {
+            self.block_size = usize::try_from(response.block_size).ok();
         }
+        Ok(response)
+    }
+
+    fn flush(&mut self, path: String) -> Result<bool, Status> {
+        self.finish_writes()?;
+        let request = Request::new(FlushRequest {
+            path,
+            namespace: self.namespace.clone(),
+        });
+        let response = self.rt.block_on(self.client.flush(request))?.into_inner();
+        Ok(response.success)
     }
-    
-    
 
-    pub fn read(&mut self, a: i32) -> Option<i32> {
-        debug_println!("\nread");
-        let x = self.pmap[a as usize];
-        self.pmap[a as usize] = self.rng.gen_range(0..self.num_leaves);
-        self.update_stash(a, x);
-        debug_println!("stash: {:?}", self.stash);
-        debug_println!("pmap: {:?}", self.pmap);
+    fn wire_bytes(&self) -> (u64, u64) {
+        self.wire_bytes
+    }
+
+    // Retried like path reads, so with `set_retry` it can wait out a server that is still
+    // starting.
+    fn ping(&mut self) -> Result<PingResponse, Status> {
+        let request = PingRequest {
+            namespace: self.namespace.clone(),
+        };
+        let response = self.with_retries("ping", request, |client, rt, request| {
+            rt.block_on(client.ping(Request::new(request)))
+        })?;
+        Ok(response.into_inner())
+    }
 
-        let out = self.stash.get(&a).cloned();
-        debug_println!("a: {}; x: {}; pmap[{}]: {}", a, x, a, self.pmap[a as usize]);
-        self.write_back_stash(x);
+    fn stats(&mut self) -> Result<StatsResponse, Status> {
+        let request = Request::new(StatsRequest {
+            namespace: self.namespace.clone(),
+        });
+        Ok(self.rt.block_on(self.client.stats(request))?.into_inner())
+    }
+
+    // Streams the runs over one SetupBulk call instead of separate write_block requests.
+    // Chunks aren't retried: a failed stream leaves the tree as it was, so rerun the load.
+    fn begin_bulk(&mut self) -> Result<(), Status> {
+        self.finish_writes()?;
+        let (chunks, queue) = mpsc::channel(BULK_QUEUE_CHUNKS);
+        let mut client = self.client.clone();
+        let call = self
+            .rt
+            .spawn(async move { client.setup_bulk(ReceiverStream::new(queue)).await });
+        self.bulk = Some(BulkUpload {
+            chunks,
+            call,
+            buckets_sent: 0,
+        });
+        Ok(())
+    }
 
-        debug_rpc_call!(self.client, self.rt);
+    fn send_buckets(&mut self, first: i64, count: usize, blocks: Vec<Block>) -> Result<(), Status> {
+        let upload = self
+            .bulk
+            .as_mut()
+            .ok_or_else(|| Status::failed_precondition("send_buckets before begin_bulk"))?;
+        let chunk = BucketChunk {
+            index: first,
+            blocks,
+            namespace: self.namespace.clone(),
+            last: false,
+        };
+        if self.rt.block_on(upload.chunks.send(chunk)).is_ok() {
+            upload.buckets_sent += count as i64;
+            return Ok(());
+        }
+        // The call already ended, so the server rejected the stream; report why
+        let upload = self.bulk.take().expect("checked above");
+        Err(upload
+            .finish(&self.rt, None)
+            .err()
+            .unwrap_or_else(|| Status::internal("SetupBulk ended before the last chunk")))
+    }
 
-        out
+    fn finish_bulk(&mut self) -> Result<(), Status> {
+        let upload = self
+            .bulk
+            .take()
+            .ok_or_else(|| Status::failed_precondition("finish_bulk before begin_bulk"))?;
+        let sent = upload.buckets_sent;
+        let last = BucketChunk {
+            namespace: self.namespace.clone(),
+            last: true,
+            ..Default::default()
+        };
+        let response = upload.finish(&self.rt, Some(last))?;
+        if response.buckets_written != sent {
+            return Err(Status::data_loss(format!(
+                "sent {} buckets but the server stored {}",
+                sent, response.buckets_written
+            )));
+        }
+        Ok(())
     }
+}
 
-    pub fn write(&mut self, a: i32, data: i32) -> Option<i32> {
-        debug_println!("\nwrite");
-        let x = self.pmap[a as usize];
-        self.pmap[a as usize] = self.rng.gen_range(0..self.num_leaves);
-        self.update_stash(a, x);
-        debug_println!("stash: {:?}", self.stash);
-        debug_println!("pmap: {:?}", self.pmap);
+// In-process tree with the same semantics as the server's `data_store`.
+#[derive(Debug, Default, Clone)]
+pub struct LocalBackend {
+    data_store: Vec<Vec<Block>>,
+    bucket_size: i32,
+    block_size: i32,
+}
 
-        let out = self.stash.insert(a, data);
+impl LocalBackend {
+    pub fn new() -> Self {
+        LocalBackend::default()
+    }
 
-        debug_println!("a: {}; x: {}; pmap[{}]: {}", a, x, a, self.pmap[a as usize]);
-        self.write_back_stash(x);
+    // Positions of `indices` in `data_store`, failing as the server does on the first one
+    // outside the tree.
+    fn check_indices(&self, indices: &[i64]) -> Result<Vec<usize>, Status> {
+        indices
+            .iter()
+            .map(|&index| {
+                convert::idx(index)
+                    .ok()
+                    .filter(|&i| i < self.data_store.len())
+                    .ok_or_else(|| {
+                        Status::invalid_argument(format!(
+                            "Index {} is outside the tree of {} buckets",
+                            index,
+                            self.data_store.len()
+                        ))
+                    })
+            })
+            .collect()
+    }
+}
 
-        debug_rpc_call!(self.client, self.rt);
+impl OramBackend for LocalBackend {
+    fn setup(
+        &mut self,
+        num_layers: i32,
+        bucket_size: i32,
+        block_size: i32,
+    ) -> Result<bool, Status> {
+        // At least the root, as on the server
+        let num_buckets = convert::bucket_count(num_layers)
+            .ok()
+            .filter(|&count| count > 0)
+            .ok_or_else(|| {
+                Status::invalid_argument(format!(
+                    "Layer count must be at least 1, got {}",
+                    num_layers
+                ))
+            })?;
+        let width = usize::try_from(block_size)
+            .map_err(|_| Status::invalid_argument("Block size must not be negative"))?;
+        let z = usize::try_from(bucket_size)
+            .ok()
+            .filter(|&z| z > 0)
+            .ok_or_else(|| Status::invalid_argument("Bucket size must be positive"))?;
+        num_buckets.checked_mul(z).ok_or_else(|| {
+            Status::invalid_argument(format!(
+                "{} buckets of {} blocks don't fit in memory",
+                num_buckets, z
+            ))
+        })?;
+        // All-zero dummies, like the server's fill before setup writes every bucket
+        let empty = Block {
+            index: -1,
+            data: vec![0; width],
+        };
+        self.data_store = vec![vec![empty; z]; num_buckets];
+        self.bucket_size = bucket_size;
+        self.block_size = block_size;
+        Ok(true)
+    }
 
-        out
+    fn read_path(&mut self, indices: Vec<i64>) -> Result<Vec<Block>, Status> {
+        let positions = self.check_indices(&indices)?;
+        Ok(positions
+            .into_iter()
+            .flat_map(|i| self.data_store[i].iter().cloned())
+            .collect())
     }
 
-    fn get_index(&self, x: i32, l: i32) -> i32 {
-        let x = if self.l > 0 { (1 << self.l) + x } else { 1 };
-        (x >> (self.l - l)) - 1
+    // Checks the whole request before touching any bucket, so a bad one changes nothing.
+    fn write_path(&mut self, indices: Vec<i64>, blocks: Vec<Block>) -> Result<bool, Status> {
+        let positions = self.check_indices(&indices)?;
+        let bucket_size = self.bucket_size as usize;
+        if blocks.len() != indices.len() * bucket_size {
+            return Err(Status::invalid_argument(format!(
+                "Got {} blocks for {} buckets of {}, expected {}",
+                blocks.len(),
+                indices.len(),
+                bucket_size,
+                indices.len() * bucket_size
+            )));
+        }
+        let block_size = self.block_size as usize;
+        if let Some(block) = blocks.iter().find(|block| block.data.len() != block_size) {
+            return Err(Status::invalid_argument(format!(
+                "Block for address {} has {} bytes, expected {}",
+                block.index,
+                block.data.len(),
+                block_size
+            )));
+        }
+        let mut blocks = blocks.into_iter();
+        for i in positions {
+            for slot in self.data_store[i].iter_mut() {
+                *slot = blocks.next().expect("block count checked above");
+            }
+        }
+        Ok(true)
     }
 
-    fn get_on_path_indices(&self, x: i32, l: i32) -> impl Iterator<Item = i32> {
-        if l == self.l {
-            return x..x + 1;
+    fn print(&mut self) -> Result<bool, Status> {
+        for (i, bucket) in self.data_store.iter().enumerate() {
+            let blocks: Vec<String> = bucket
+                .iter()
+                .map(|block| {
+                    if block.index == -1 {
+                        "(_,_)".to_string()
+                    } else {
+                        format!("({},{})", hex_prefix(&block.data), block.index)
+                    }
+                })
+                .collect();
+            println!("{}: {}", i, blocks.join(" "));
         }
+        Ok(true)
+    }
 
-        let l = self.l - l;
-        let mask = (1 << l) - 1;
-        let start = x & !mask;
-        let end = x | mask;
-        start..(end + 1)
+    fn get_config(&mut self) -> Result<GetConfigResponse, Status> {
+        let num_buckets = self.data_store.len();
+        Ok(GetConfigResponse {
+            num_layers: (num_buckets + 1).trailing_zeros() as i32,
+            bucket_size: self.bucket_size,
+            num_buckets: num_buckets as i64,
+            initialized: num_buckets > 0,
+            block_size: self.block_size,
+        })
+    }
+
+    // Counted like the server's `Arena`, without its checksums
+    fn stats(&mut self) -> Result<StatsResponse, Status> {
+        let num_buckets = self.data_store.len();
+        let total_blocks = num_buckets * self.bucket_size as usize;
+        let block = std::mem::size_of::<Block>() + self.block_size as usize;
+        Ok(StatsResponse {
+            num_buckets: num_buckets as i64,
+            bucket_size: self.bucket_size,
+            total_blocks: total_blocks as i64,
+            estimated_bytes: (total_blocks * block) as u64,
+        })
     }
 }
 
-fn run_client(port: u16, n: i32, z: i32, rng_seed: u64) {
-    let exp = n;
-    let n = 1 << exp;
-    let rt = Runtime::new().unwrap();
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BackendCall {
+    Setup {
+        num_layers: i32,
+        bucket_size: i32,
+        block_size: i32,
+    },
+    ReadPath(Vec<i64>),
+    WritePath(Vec<i64>),
+    Print,
+    PrintToFile(String),
+    GetConfig,
+    Flush(String),
+    Stats,
+}
 
-    let channel = rt
-        .block_on(Channel::from_shared(format!("http://localhost:{}", port)).unwrap().connect())
-        .unwrap();
-    let client = PathOramClient::new(channel);
-    let mut handler = PathORAMHandler::new(client, z, &rt, rng_seed);
+// A `LocalBackend` that records every call, so callers can assert exactly which
+// bucket indices an operation touched.
+#[derive(Debug, Default)]
+pub struct MockBackend {
+    inner: LocalBackend,
+    pub calls: Vec<BackendCall>,
+}
 
-    let data: Vec<i32> = (0..n).collect();
-    let start = Instant::now();
-    handler.setup(data);
-    let elapsed = start.elapsed().as_secs_f64();
-    println!("\nsetup time taken: {:.4} seconds", elapsed);
+impl MockBackend {
+    pub fn new() -> Self {
+        MockBackend::default()
+    }
 
-    run_experiment(handler, n, z, rng_seed);
+    // Returns and clears the calls recorded so far.
+    pub fn take_calls(&mut self) -> Vec<BackendCall> {
+        std::mem::take(&mut self.calls)
+    }
 }
 
-fn run_experiment(mut handler: PathORAMHandler<'_>, n: i32, z: i32, rng_seed: u64) {
-    let mut start = Instant::now();
-    for i in 0..3_000_000 {
-        handler.read(i % n); // Use modulo to stay within the range of `n`
+impl OramBackend for MockBackend {
+    fn setup(
+        &mut self,
+        num_layers: i32,
+        bucket_size: i32,
+        block_size: i32,
+    ) -> Result<bool, Status> {
+        self.calls.push(BackendCall::Setup {
+            num_layers,
+            bucket_size,
+            block_size,
+        });
+        self.inner.setup(num_layers, bucket_size, block_size)
+    }
 
-        if i % 10_000 == 0 && i > 0 {
-            let elapsed = start.elapsed().as_secs_f64();
-            println!(
-                "Warmup: {} reads completed, time for last 10,000: {:.4} seconds",
-                i, elapsed
-            );
-            start = Instant::now(); // Reset timer
-        }
+    fn read_path(&mut self, indices: Vec<i64>) -> Result<Vec<Block>, Status> {
+        self.calls.push(BackendCall::ReadPath(indices.clone()));
+        self.inner.read_path(indices)
     }
 
-    let mut stash_file = OpenOptions::new()
-        .create(true)
-        .append(false)
-        .open(format!("stash_sizes_n={}_z={}_b={}.txt", n, z, rng_seed))
-        .expect("Unable to open file");
+    fn write_path(&mut self, indices: Vec<i64>, blocks: Vec<Block>) -> Result<bool, Status> {
+        self.calls.push(BackendCall::WritePath(indices.clone()));
+        self.inner.write_path(indices, blocks)
+    }
 
-    // Perform 7 million read operations
-    let mut start = Instant::now();
-    for i in 0..7_000_000 {
-        handler.read(i % n); // Use modulo to stay within the range of `n`
+    fn print(&mut self) -> Result<bool, Status> {
+        self.calls.push(BackendCall::Print);
+        self.inner.print()
+    }
 
-        // Write stash size to the file
-        writeln!(stash_file, "{}", handler.stash.len()).expect("Unable to write to file");
+    fn print_to_file(&mut self, path: String) -> Result<String, Status> {
+        self.calls.push(BackendCall::PrintToFile(path.clone()));
+        self.inner.print_to_file(path)
+    }
 
-        // Display time taken for every 10,000 operations
-        if i % 10 == 0 && i > 0 {
-            let elapsed = start.elapsed().as_secs_f64();
-            println!(
-                "test: {} reads completed, time for last 10,000: {:.4} seconds",
-                i, elapsed
-            );
-            stash_file.flush().expect("Unable to flush file"); // Flush to ensure data is saved
-            start = Instant::now(); // Reset timer
-        }
+    fn get_config(&mut self) -> Result<GetConfigResponse, Status> {
+        self.calls.push(BackendCall::GetConfig);
+        self.inner.get_config()
+    }
+
+    fn flush(&mut self, path: String) -> Result<bool, Status> {
+        self.calls.push(BackendCall::Flush(path.clone()));
+        self.inner.flush(path)
+    }
+
+    fn stats(&mut self) -> Result<StatsResponse, Status> {
+        self.calls.push(BackendCall::Stats);
+        self.inner.stats()
     }
 }
 
-fn main() {
-    let args = Args::parse();
-    let rng_seed = 11;
+// Payload bytes per block when no block size is set: exactly one i64.
+pub const DEFAULT_BLOCK_SIZE: i32 = 8;
 
-    run_client(args.port, args.n, args.z, rng_seed);
-}
\ No newline at end of file
+// AES-256-GCM key length, and what sealing adds to a block on the wire: the nonce, the
+// encrypted index and the authentication tag.
+const KEY_BYTES: usize = 32;
+const NONCE_BYTES: usize = 12;
+const SEAL_OVERHEAD: usize = NONCE_BYTES + 8 + 16;
+
+// Rough size cap for each chunk of a bulk load (and each request of a full-tree read),
+// well under the default 4 MiB gRPC message limit that applies to every message of a
+// stream separately. `set_max_message_bytes` lowers it for smaller limits.
+const BULK_WRITE_BYTES: usize = 1 << 20;
+
+// Bytes of a leaf as stored in position-map payloads and recursive-mode leaf tags.
+const LEAF_BYTES: usize = 8;
+
+fn recursive_unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "not supported with a recursive position map",
+    )
+}
+
+// A dummy block: index -1 and a payload of `width` random bytes from `rng`, so a padded
+// bucket never repeats and its dummies carry bytes like any real block's. The index alone
+// marks a slot empty; payloads are never inspected, so a stored -1 is an ordinary value.
+fn dummy_block(width: usize, rng: &mut impl Rng) -> Block {
+    let mut data = vec![0; width];
+    rng.fill(&mut data[..]);
+    Block { index: -1, data }
+}
+
+// Pads with zeros or truncates `data` to exactly `width` bytes.
+fn fit_payload(data: &[u8], width: usize) -> Vec<u8> {
+    let mut payload = data[..data.len().min(width)].to_vec();
+    payload.resize(width, 0);
+    payload
+}
+
+// The i64 stored little-endian at the start of a payload. Payloads narrower than eight
+// bytes read as if zero-padded, so only the low bytes of the original value survive.
+fn decode_i64(data: &[u8]) -> i64 {
+    let mut word = [0u8; 8];
+    let len = data.len().min(8);
+    word[..len].copy_from_slice(&data[..len]);
+    i64::from_le_bytes(word)
+}
+
+// Parses an even-length hex string.
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            hex.get(i..i + 2)
+                .and_then(|h| u8::from_str_radix(h, 16).ok())
+        })
+        .collect()
+}
+
+// Reads a 256-bit AES key written as 64 hex characters (surrounding whitespace ignored).
+fn read_key(path: &Path) -> io::Result<[u8; KEY_BYTES]> {
+    let hex = std::fs::read_to_string(path)?;
+    decode_hex(hex.trim())
+        .and_then(|key| key.try_into().ok())
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("expected {} hex characters", KEY_BYTES * 2),
+            )
+        })
+}
+
+// The first few payload bytes in hex, with `..` when there are more.
+fn hex_prefix(data: &[u8]) -> String {
+    const SHOWN: usize = 4;
+    let mut hex: String = data
+        .iter()
+        .take(SHOWN)
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    if data.len() > SHOWN {
+        hex.push_str("..");
+    }
+    hex
+}
+
+// One bucket of a read_block response. The server returns the requested buckets' blocks
+// concatenated in `indices` order; `split` recovers the per-bucket structure.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PathBuckets {
+    pub level: usize, // Depth in the tree, root = 0
+    pub blocks: Vec<Block>,
+}
+
+impl PathBuckets {
+    // Cuts `blocks` into one Z-block bucket per entry of `indices`, failing if the
+    // response doesn't hold exactly Z blocks for every bucket asked for.
+    pub fn split(indices: &[i64], blocks: Vec<Block>, z: i32) -> Result<Vec<Self>, Status> {
+        let z = convert::idx(z.into()).map_err(|e| Status::invalid_argument(e.to_string()))?;
+        if z == 0 || blocks.len() != indices.len() * z {
+            return Err(Status::data_loss(format!(
+                "expected {} buckets of {} blocks, got {} blocks",
+                indices.len(),
+                z,
+                blocks.len()
+            )));
+        }
+
+        indices
+            .iter()
+            .zip(blocks.chunks(z))
+            .map(|(&index, blocks)| {
+                let index =
+                    convert::idx(index).map_err(|e| Status::invalid_argument(e.to_string()))?;
+                Ok(PathBuckets {
+                    level: (index + 1).ilog2() as usize,
+                    blocks: blocks.to_vec(),
+                })
+            })
+            .collect()
+    }
+}
+
+// Checks each bucket read from `indices` against the checksum the storage sent for it, if
+// it sent any, failing on the first that differs.
+fn verify_checksums(
+    indices: &[i64],
+    buckets: &[PathBuckets],
+    crcs: Option<Vec<u32>>,
+) -> Result<(), OramError> {
+    let Some(crcs) = crcs else {
+        return Ok(());
+    };
+    for ((&index, bucket), crc) in indices.iter().zip(buckets).zip(crcs) {
+        if bucket_crc32(&bucket.blocks) != crc {
+            return Err(OramError::Corruption { index });
+        }
+    }
+    Ok(())
+}
+
+// Backend traffic since setup finished loading, summed over every position-map level.
+// Each path read or write-back is one RPC; blocks count dummies too.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Metrics {
+    pub rpc_reads: u64,
+    pub rpc_writes: u64,
+    pub blocks_sent: u64,
+    pub blocks_received: u64,
+    pub cached_buckets: u64, // Path buckets taken from the path cache instead
+    // Encoded path read/write message bytes; zero for backends with no wire
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+}
+
+impl Metrics {
+    fn plus(self, other: Metrics) -> Metrics {
+        Metrics {
+            rpc_reads: self.rpc_reads + other.rpc_reads,
+            rpc_writes: self.rpc_writes + other.rpc_writes,
+            blocks_sent: self.blocks_sent + other.blocks_sent,
+            blocks_received: self.blocks_received + other.blocks_received,
+            cached_buckets: self.cached_buckets + other.cached_buckets,
+            bytes_sent: self.bytes_sent + other.bytes_sent,
+            bytes_received: self.bytes_received + other.bytes_received,
+        }
+    }
+}
+
+// Stash occupancy in blocks: now, and the most seen after any write-back since setup.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StashInfo {
+    pub current: usize,
+    pub peak: usize,
+}
+
+// A logical access, as passed to `access`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Read,
+    Write,
+}
+
+impl Op {
+    // Letter used for the op in access traces
+    fn code(self) -> char {
+        match self {
+            Op::Read => 'R',
+            Op::Write => 'W',
+        }
+    }
+}
+
+// The paths a batch of accesses read: (address, old leaf, new leaf) for each distinct
+// address, and the old leaves in leaf order without repeats.
+struct BatchPaths {
+    remapped: Vec<(i64, i64, i64)>,
+    leaves: Vec<i64>,
+}
+
+impl BatchPaths {
+    // The leaf `a` was remapped to; `a` must be one of the batch's addresses.
+    fn new_leaf(&self, a: i64) -> i64 {
+        self.remapped
+            .iter()
+            .find(|&&(b, _, _)| b == a)
+            .map_or(0, |&(_, _, leaf)| leaf)
+    }
+}
+
+// Picks the leaf each address is (re)mapped to, in 0..num_leaves. The handler's seeded RNG
+// is the default; tests can supply a scripted chooser to drive the tree into a known state.
+pub trait LeafChooser {
+    fn next_leaf(&mut self, num_leaves: i64) -> i64;
+}
+
+impl LeafChooser for StdRng {
+    fn next_leaf(&mut self, num_leaves: i64) -> i64 {
+        // Trees with fewer than 2^31 leaves draw exactly as they did when leaves were i32,
+        // so seeded runs (and the golden trace) are unchanged
+        match i32::try_from(num_leaves) {
+            Ok(num_leaves) => self.gen_range(0..num_leaves).into(),
+            Err(_) => self.gen_range(0..num_leaves),
+        }
+    }
+}
+
+pub struct PathORAMHandler<B: OramBackend> {
+    backend: B,
+    n: i64,
+    l: i32,
+    z: i32,
+    b: i32, // Payload bytes per block
+    stash: HashMap<i64, Vec<u8>>,
+    pmap: Vec<i64>,
+    // Recursive mode: the position map lives in this smaller ORAM instead of `pmap`, packed
+    // `posmap_fanout` leaves per block, and every payload here starts with its block's
+    // leaf (the client no longer has a table to look it up in)
+    posmap: Option<Box<PathORAMHandler<B>>>,
+    posmap_fanout: i32,
+    num_leaves: i64,
+    rng: StdRng, // RNG as a struct member
+    // Fills dummy payloads (see `dummy_block`). Seeded alongside `rng` but kept apart from
+    // it, so the leaves a seed draws don't depend on how many dummies were made.
+    dummy_rng: StdRng,
+    // Replaces `rng` for leaf choices when set (it still seeds position-map levels)
+    leaf_chooser: Option<Box<dyn LeafChooser + Send>>,
+    // Stash blocks evicted by write-backs, and every block written back (dummies included)
+    real_blocks_written: u64,
+    total_blocks_written: u64,
+    // Logical access trace, if exporting
+    trace: Option<BufWriter<File>>,
+    // Skip writing buckets that come back as they were read (see `same_contents`). Leaks
+    // bucket occupancy to the server, so only for trusted-server performance runs.
+    skip_unchanged: bool,
+    last_read: HashMap<i64, Vec<Block>>, // Bucket contents from the latest path read
+    skipped_buckets: u64,
+    // When set, the buckets of the latest write-back exactly as sent (sealed, if
+    // encrypting), by bucket index. Path reads take buckets found here instead of asking
+    // the server, which is only correct while this handler is the tree's sole writer, and
+    // NOT oblivious: the server sees which buckets each read leaves out.
+    path_cache: Option<HashMap<i64, Vec<Block>>>,
+    // When false, `read` leaves pmap[a] alone. This is NOT oblivious: reading the same
+    // address twice touches the same path, which the server sees directly. It exists only
+    // to isolate how re-randomization affects stash growth.
+    remap_on_read: bool,
+    // When set, writes, deletes and setup fail with `ReadOnly` before touching the
+    // backend. Reads still remap their address and write the path back, so the server
+    // sees the same traffic as ever and no logical value changes.
+    read_only: bool,
+    // Whether each address should hold a value: set by setup and writes, cleared by
+    // deletes. An access that finds no block for a set address fails with `MissingBlock`
+    // rather than reading as empty. Only kept alongside a flat position map, since a
+    // recursive one is there to keep client memory small, and `None` after `attach` or
+    // `load_checkpoint`, which can't know; absent addresses then just read as `None`.
+    written: Option<Vec<bool>>,
+    // Position-map levels only: write-backs are started without waiting for them
+    // (`OramBackend::start_write_path`), so the level above reads its path while this
+    // level's write is still in flight. That level waits for them at the end of its own
+    // write-back, so an access still returns only once all of its writes have landed.
+    // This is the only overlap: the lookups themselves run one level after another, since
+    // each level's path read needs the leaf the level below it returned.
+    overlap_write_back: bool,
+    // Whether `setup_recursive` gives its position-map levels `overlap_write_back`
+    pipeline_posmap: bool,
+    metrics: Metrics,
+    // `backend.wire_bytes()` as of the last metrics reset, which only the backend can count
+    wire_baseline: (u64, u64),
+    // Seals every block (dummies included) under a fresh nonce on write-back. Sealed blocks
+    // all carry index -1 on the wire; the real index travels inside the ciphertext.
+    cipher: Option<Aes256Gcm>,
+    // Write-backs fail with `StashOverflow` once the stash holds more blocks than this
+    max_stash: Option<usize>,
+    peak_stash: usize, // Largest stash after a write-back since setup
+    // Write-backs since setup that left the stash holding exactly i blocks, by i
+    stash_histogram: Vec<u64>,
+    // Most real blocks a write-back puts in one bucket (Z' in the paper); the rest stay in
+    // the stash. Buckets are still padded to Z with dummies.
+    eviction_limit: usize,
+    bulk_bytes: usize, // Target size of each bulk-load chunk or full-tree read request
+    // Tree height `setup` uses instead of the smallest one that fits N; None picks that
+    levels: Option<i32>,
+    // Leaves whose last write-back failed. Their blocks went back into the stash, and the
+    // paths are written again from it before the next path read, since the tree may still
+    // hold stale copies there.
+    unwritten: Vec<i64>,
+    // Leaves and evicted blocks of a write-back started without waiting (see
+    // `overlap_write_back`), until `finish_write_back` confirms it landed
+    unconfirmed: Option<(Vec<i64>, Vec<Block>)>,
+}
+
+impl<B: OramBackend> PathORAMHandler<B> {
+    // Like `new`, but leaves come from `leaf_chooser` instead of the seeded RNG.
+    pub fn with_leaf_chooser(
+        backend: B,
+        z: i32,
+        rng_seed: u64,
+        leaf_chooser: Box<dyn LeafChooser + Send>,
+    ) -> Self {
+        PathORAMHandler {
+            leaf_chooser: Some(leaf_chooser),
+            ..PathORAMHandler::new(backend, z, rng_seed)
+        }
+    }
+
+    pub fn new(backend: B, z: i32, rng_seed: u64) -> Self {
+        PathORAMHandler {
+            backend,
+            n: -1,
+            l: -1,
+            z,
+            b: DEFAULT_BLOCK_SIZE,
+            stash: HashMap::new(),
+            pmap: Vec::new(),
+            posmap: None,
+            posmap_fanout: 0,
+            num_leaves: 0,
+            rng: StdRng::seed_from_u64(rng_seed),
+            dummy_rng: StdRng::seed_from_u64(rng_seed.wrapping_add(1)),
+            leaf_chooser: None,
+            real_blocks_written: 0,
+            total_blocks_written: 0,
+            trace: None,
+            skip_unchanged: false,
+            last_read: HashMap::new(),
+            skipped_buckets: 0,
+            path_cache: None,
+            remap_on_read: true,
+            read_only: false,
+            written: None,
+            overlap_write_back: false,
+            pipeline_posmap: true,
+            metrics: Metrics::default(),
+            wire_baseline: (0, 0),
+            cipher: None,
+            max_stash: None,
+            peak_stash: 0,
+            stash_histogram: Vec::new(),
+            eviction_limit: z as usize,
+            bulk_bytes: BULK_WRITE_BYTES,
+            levels: None,
+            unwritten: Vec::new(),
+            unconfirmed: None,
+        }
+    }
+
+    // Connects to a tree that is already set up, taking L, Z and B from the server instead
+    // of running `setup`, which would wipe it. `key` must be the one the tree was written
+    // with, since it decides how much of each block is payload. The position map and
+    // stash stay with the client that wrote the tree, so until `load_checkpoint` brings
+    // them over every access fails with `NotSetup`.
+    pub fn attach(
+        backend: B,
+        rng_seed: u64,
+        key: Option<&[u8; KEY_BYTES]>,
+    ) -> Result<Self, OramError> {
+        let mut handler = PathORAMHandler::new(backend, 0, rng_seed);
+        if let Some(key) = key {
+            handler.set_key(key);
+        }
+        handler.fetch_config()?;
+        Ok(handler)
+    }
+
+    // Encrypts blocks with `key` from the next `setup` (or checkpoint resume) on.
+    pub fn set_key(&mut self, key: &[u8; KEY_BYTES]) {
+        self.cipher = Some(Aes256Gcm::new(key.into()));
+    }
+
+    // Turns the overlap of position-map write-backs with the next level's path read (see
+    // `overlap_write_back`) on or off for the next `setup_recursive`. On by default; the
+    // server sees the same requests either way, only sooner. Path reads are never
+    // overlapped with each other.
+    pub fn set_pipeline_posmap(&mut self, enabled: bool) {
+        self.pipeline_posmap = enabled;
+    }
+
+    // INSECURE when false; see `remap_on_read`.
+    pub fn set_remap_on_read(&mut self, remap_on_read: bool) {
+        self.remap_on_read = remap_on_read;
+    }
+
+    // Guards a tree built elsewhere, e.g. while replaying a trace against it: once set,
+    // only reads (and dummy accesses) go through. Those still draw fresh leaves and write
+    // their paths back, and in recursive mode they still update the position-map levels,
+    // so the tree keeps changing and accesses stay unlinkable; what can't change is the
+    // value stored at any address.
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    // Payload width for the next `setup`; values are padded or truncated to it.
+    pub fn set_block_size(&mut self, b: i32) {
+        self.b = b;
+    }
+
+    pub fn block_size(&self) -> i32 {
+        self.b
+    }
+
+    // Bound on the stash size after each write-back; `None` lets it grow without limit.
+    pub fn set_max_stash(&mut self, max_stash: Option<usize>) {
+        self.max_stash = max_stash;
+    }
+
+    // Caps the real blocks evicted into each bucket at `limit` (at most Z). Lower limits
+    // leave more blocks in the stash without changing what the server sees.
+    pub fn set_eviction_limit(&mut self, limit: usize) {
+        self.eviction_limit = limit;
+    }
+
+    // Builds the tree `levels` high (2^levels leaves, levels + 1 layers on the server) on
+    // the next `setup` instead of ceil(log2 N) high. Taller trees spread the same blocks
+    // over more buckets, so the stash stays smaller at the cost of longer paths. Setup
+    // fails with `TreeTooShort` if `levels` leaves some address without a leaf of its
+    // own. Recursive position-map levels still use the smallest height that fits.
+    pub fn set_levels(&mut self, levels: Option<i32>) {
+        self.levels = levels;
+    }
+
+    // Keeps bulk-load chunks and full-tree reads to half of `limit` bytes, for a backend
+    // whose messages are capped below the default; the other half absorbs protobuf
+    // framing. Limits above the default don't make the requests any larger.
+    pub fn set_max_message_bytes(&mut self, limit: usize) {
+        self.bulk_bytes = BULK_WRITE_BYTES.min(limit / 2);
+    }
+
+    // Buckets per bulk-load chunk or full-tree read request, sized to stay near
+    // `bulk_bytes` on the wire.
+    fn buckets_per_request(&self) -> usize {
+        let wire_width = self.block_width() + self.seal_overhead();
+        (self.bulk_bytes / (self.z as usize * (wire_width + 8))).max(1)
+    }
+
+    // Adopts a bucket size from the server or a checkpoint. An eviction limit that was
+    // still the default follows the new Z.
+    fn set_z(&mut self, z: i32) {
+        if self.eviction_limit == self.z as usize {
+            self.eviction_limit = z as usize;
+        }
+        self.z = z;
+    }
+
+    pub fn set_skip_unchanged(&mut self, skip_unchanged: bool) {
+        self.skip_unchanged = skip_unchanged;
+    }
+
+    // Turns the path cache (see `path_cache`) on or off, for this level and any
+    // position-map levels set up afterwards.
+    pub fn set_path_cache(&mut self, enabled: bool) {
+        self.path_cache = enabled.then(HashMap::new);
+    }
+
+    // Forgets cached buckets, for when the tree may have changed behind the cache's back.
+    fn clear_path_cache(&mut self) {
+        if let Some(cache) = self.path_cache.as_mut() {
+            cache.clear();
+        }
+    }
+
+    pub fn skipped_buckets(&self) -> u64 {
+        self.skipped_buckets
+    }
+
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    pub fn backend_mut(&mut self) -> &mut B {
+        &mut self.backend
+    }
+
+    // Creates the tree on the backend. Loading data into a tree that wasn't set up (or
+    // that belongs to someone else) would go wrong later and less clearly, so a refusal
+    // is returned rather than just reported.
+    pub fn initialize_server(
+        &mut self,
+        num_layers: i32,
+        bucket_size: i32,
+        block_size: i32,
+    ) -> Result<(), OramError> {
+        if self.backend.setup(num_layers, bucket_size, block_size)? {
+            info!(num_layers, bucket_size, block_size, "server initialized");
+            Ok(())
+        } else {
+            Err(Status::internal("server reported that setup failed").into())
+        }
+    }
+
+    // Checks that the backend answers, e.g. before a long run, and reports whether its
+    // tree has been set up. Touches neither the tree nor the metrics.
+    pub fn ping(&mut self) -> Result<PingResponse, OramError> {
+        Ok(self.backend.ping()?)
+    }
+
+    // The server-side footprint of this level's tree. Position-map levels have trees of
+    // their own, which this leaves out.
+    pub fn server_stats(&mut self) -> Result<StatsResponse, OramError> {
+        Ok(self.backend.stats()?)
+    }
+
+    // Adopts the server's current tree geometry (L, Z, B and the leaf count) instead of
+    // running setup, which would wipe it. The position map and stash are not on the server,
+    // so they still have to come from a checkpoint before accesses make sense.
+    pub fn fetch_config(&mut self) -> Result<GetConfigResponse, OramError> {
+        let config = self.backend.get_config()?;
+        self.clear_path_cache();
+        if !config.initialized {
+            return Err(OramError::NotSetup);
+        }
+
+        self.l = config.num_layers - 1;
+        self.set_z(config.bucket_size);
+        self.b = config.block_size - self.seal_overhead() as i32;
+        self.num_leaves =
+            convert::leaf_count(self.l).map_err(|e| Status::out_of_range(e.to_string()))?;
+        Ok(config)
+    }
+
+    // Stores `data[a]` at address `a` for every a in 0..N, for any N >= 1 (empty `data`
+    // fails with INVALID_ARGUMENT and changes nothing). The tree gets
+    // L = ceil(log2 N) levels below the root, 2^L leaves and 2^(L+1) - 1 buckets:
+    //
+    //   N = 1     L = 0    1 leaf        1 bucket (the root is the whole path)
+    //   N = 2     L = 1    2 leaves      3 buckets
+    //   N = 3     L = 2    4 leaves      7 buckets
+    //   N = 5     L = 3    8 leaves     15 buckets
+    //   N = 1024  L = 10   1024 leaves  2047 buckets
+    //
+    // When N isn't a power of two, addresses N..2^L don't exist (accessing one fails with
+    // `AddressOutOfRange`), but blocks are still mapped to all 2^L leaves, so the spare
+    // leaves only spread them out further.
+    //
+    // A setup that fails before the server accepts the new tree (a refusal included)
+    // leaves the handler as it was, old data and all. Once the server has accepted it the
+    // old tree is gone, so a later failure leaves the handler not set up.
+    pub fn setup(&mut self, data: Vec<i64>) -> Result<(), OramError> {
+        let payloads = data.iter().map(|v| v.to_le_bytes().to_vec()).collect();
+        self.setup_levels(payloads, None, false)
+    }
+
+    // Same result as `setup`, without the N accesses: every block goes straight into the
+    // stash and the whole tree is filled in one pass, each bucket greedily taking blocks
+    // from below (leaves first, up to the eviction limit), then written out with a few
+    // large `write_path` calls. Every block ends up on its leaf's path or in the stash,
+    // as after sequential writes, and the server sees every bucket written once
+    // regardless of the data.
+    pub fn setup_bulk(&mut self, data: Vec<i64>) -> Result<(), OramError> {
+        let payloads = data.iter().map(|v| v.to_le_bytes().to_vec()).collect();
+        self.setup_levels(payloads, None, true)
+    }
+
+    // Like `setup`, but the position map is itself stored in an ORAM (on a backend from
+    // `make_backend`) holding `fanout` leaves per block, recursively, until a level has at
+    // most `cutoff` addresses and keeps a plain client-side table. Client memory drops
+    // from O(N) to O(cutoff) plus the stashes, at the price of one extra access per level
+    // on every operation.
+    pub fn setup_recursive(
+        &mut self,
+        data: Vec<i64>,
+        fanout: i32,
+        cutoff: i32,
+        make_backend: &mut dyn FnMut() -> B,
+    ) -> Result<(), OramError> {
+        assert!(
+            fanout >= 2,
+            "a fanout below 2 never shrinks the position map"
+        );
+        assert!(
+            cutoff >= 1,
+            "the base position map needs at least one entry"
+        );
+        let payloads = data.iter().map(|v| v.to_le_bytes().to_vec()).collect();
+        self.setup_levels(payloads, Some((fanout, cutoff, make_backend)), false)
+    }
+
+    fn setup_levels(
+        &mut self,
+        payloads: Vec<Vec<u8>>,
+        recursion: Option<(i32, i32, &mut dyn FnMut() -> B)>,
+        bulk: bool,
+    ) -> Result<(), OramError> {
+        if self.read_only {
+            return Err(OramError::ReadOnly);
+        }
+        let bad_size = |e: convert::ConvertError| {
+            Status::invalid_argument(format!("can't set up the tree: {}", e))
+        };
+        let n = convert::to_i64(payloads.len()).map_err(bad_size)?;
+        // No tree holds zero addresses
+        let needed = convert::tree_height(n).map_err(bad_size)?;
+        let l = match self.levels {
+            Some(levels) if levels < needed => {
+                return Err(OramError::TreeTooShort { levels, needed, n });
+            }
+            Some(levels) => levels,
+            None => needed,
+        };
+        let num_leaves = convert::leaf_count(l).map_err(bad_size)?;
+        let recursive = matches!(recursion, Some((_, cutoff, _)) if n > cutoff.into());
+
+        // The server is asked before anything else changes, so a refusal (a populated tree
+        // without force, say) leaves this handler, and every position-map tree under it,
+        // exactly as it was
+        let tag = if recursive { LEAF_BYTES } else { 0 };
+        let wire_width = self.b as usize + tag + self.seal_overhead();
+        self.initialize_server(l + 1, self.z, wire_width as i32)?;
+
+        // The old tree is gone, so its state goes too. Until the new state is complete the
+        // handler is not set up, and a failure below leaves accesses failing with
+        // `NotSetup` rather than following a position map into the new tree.
+        self.n = -1;
+        self.stash.clear();
+        self.pmap = Vec::new();
+        self.posmap = None;
+        self.written = None;
+        self.unwritten.clear();
+        self.unconfirmed = None;
+        self.clear_path_cache();
+        self.l = l;
+        self.num_leaves = num_leaves;
+
+        let leaves: Vec<i64> = (0..n).map(|_| self.next_leaf()).collect();
+        match recursion {
+            Some((fanout, cutoff, make_backend)) if recursive => {
+                let mut posmap = PathORAMHandler::new(make_backend(), self.z, self.rng.gen());
+                posmap.set_block_size(fanout * LEAF_BYTES as i32);
+                posmap.cipher = self.cipher.clone();
+                posmap.bulk_bytes = self.bulk_bytes;
+                posmap.set_path_cache(self.path_cache.is_some());
+                posmap.pipeline_posmap = self.pipeline_posmap;
+                let packed = leaves
+                    .chunks(fanout as usize)
+                    .map(|chunk| chunk.iter().flat_map(|x| x.to_le_bytes()).collect())
+                    .collect();
+                posmap.setup_levels(packed, Some((fanout, cutoff, make_backend)), bulk)?;
+                posmap.overlap_write_back = self.pipeline_posmap;
+                self.posmap = Some(Box::new(posmap));
+                self.posmap_fanout = fanout;
+            }
+            _ => self.pmap = leaves.clone(),
+        }
+        self.n = n;
+
+        // Each address is marked as its payload goes in
+        self.written = self.posmap.is_none().then(|| vec![false; payloads.len()]);
+        if bulk {
+            for (a, payload) in payloads.iter().enumerate() {
+                let a = convert::to_i64(a).expect("address fits in i64");
+                self.put_payload(a, leaves[slot(a)], payload);
+            }
+            if let Some(written) = self.written.as_mut() {
+                written.fill(true);
+            }
+            self.evict_all()?;
+        } else {
+            // Seal the empty tree first, so every block this client ever reads back is one
+            // it wrote and `open` has nothing to take on trust
+            if self.cipher.is_some() {
+                self.evict_all()?;
+            }
+            for (a, payload) in payloads.iter().enumerate() {
+                self.write_bytes(convert::to_i64(a).expect("address fits in i64"), payload)?;
+            }
+        }
+        // Loading isn't part of the workload being measured
+        self.reset_stash_stats();
+        self.reset_metrics();
+        info!(n = self.n, bulk, "data written to server");
+        Ok(())
+    }
+
+    // Writes every bucket of the tree from the stash, bottom-up: a bucket takes up to the
+    // eviction limit of the blocks that reached it from its children's subtrees (lowest
+    // addresses first) and passes the rest up. Whatever the root can't take stays stashed.
+    // The buckets go out as one bulk load (`OramBackend::begin_bulk`), in chunks of about
+    // `buckets_per_request` that never span two levels.
+    fn evict_all(&mut self) -> Result<(), OramError> {
+        let limit = self.eviction_limit.min(self.z as usize);
+        let mut addresses: Vec<i64> = self.stash.keys().copied().collect();
+        addresses.sort_unstable();
+        // Blocks still looking for a bucket, per node of the level being filled
+        let mut pending = vec![Vec::new(); self.num_leaves as usize];
+        for a in addresses {
+            pending[self.leaf_of(a) as usize].push(a);
+        }
+
+        let per_request = self.buckets_per_request();
+        let mut blocks = Vec::with_capacity(per_request * self.z as usize);
+        self.backend.begin_bulk()?;
+        for l in (0..=self.l).rev() {
+            let first_index = (1 << l) - 1;
+            // First bucket of the chunk being filled, and how many it holds so far
+            let (mut first, mut count) = (first_index, 0);
+            let mut parents = vec![Vec::new(); pending.len().div_ceil(2)];
+            for (j, mut waiting) in pending.into_iter().enumerate() {
+                let target_index = first_index + j as i64;
+                let rest = waiting.split_off(limit.min(waiting.len()));
+                parents[j / 2].extend(rest);
+
+                let real: Vec<Block> = waiting
+                    .iter()
+                    .map(|&a| Block {
+                        index: a,
+                        data: self.stash.remove(&a).expect("pending blocks are stashed"),
+                    })
+                    .collect();
+                let bucket = self.fill_bucket(target_index, real);
+                self.real_blocks_written += waiting.len() as u64;
+                self.total_blocks_written += bucket.len() as u64;
+                count += 1;
+                blocks.extend(bucket);
+
+                if count == per_request {
+                    self.send_buckets(first, count, std::mem::take(&mut blocks))?;
+                    (first, count) = (target_index + 1, 0);
+                }
+            }
+            if count > 0 {
+                self.send_buckets(first, count, std::mem::take(&mut blocks))?;
+            }
+            pending = parents;
+        }
+        self.backend.finish_bulk()?;
+        Ok(())
+    }
+
+    // Sends one write_path request, counting it in the metrics.
+    fn write_buckets(&mut self, indices: Vec<i64>, blocks: Vec<Block>) -> Result<(), OramError> {
+        let (buckets, sent) = (indices.len(), blocks.len() as u64);
+        let started = Instant::now();
+        if self.overlap_write_back {
+            self.backend.start_write_path(indices, blocks)?;
+        } else {
+            self.backend
+                .write_path(indices, blocks)
+                .and_then(write_landed)?;
+        }
+        debug!(
+            buckets,
+            elapsed_us = started.elapsed().as_micros() as u64,
+            "write_path"
+        );
+        self.metrics.rpc_writes += 1;
+        self.metrics.blocks_sent += sent;
+        Ok(())
+    }
+
+    // Waits for the write-backs this level and the position-map levels under it started
+    // without waiting (see `overlap_write_back`).
+    fn finish_writes(&mut self) -> Result<(), OramError> {
+        self.finish_write_back()?;
+        match self.posmap.as_mut() {
+            Some(posmap) => posmap.finish_writes(),
+            None => Ok(()),
+        }
+    }
+
+    fn finish_write_back(&mut self) -> Result<(), OramError> {
+        let unconfirmed = self.unconfirmed.take();
+        if let Err(e) = self.backend.finish_writes() {
+            // The write may or may not have landed
+            self.clear_path_cache();
+            if let Some((leaves, evicted)) = unconfirmed {
+                self.requeue(&leaves, evicted);
+            }
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
+    // Takes back the blocks a failed write-back of the paths to `leaves` evicted, and marks
+    // those paths to be written again before the next read.
+    fn requeue(&mut self, leaves: &[i64], evicted: Vec<Block>) {
+        self.stash
+            .extend(evicted.into_iter().map(|block| (block.index, block.data)));
+        for &x in leaves {
+            if !self.unwritten.contains(&x) {
+                self.unwritten.push(x);
+            }
+        }
+    }
+
+    // Writes the paths of `unwritten` again from the stash, so none of their buckets keeps
+    // a copy of a block that has moved on since. Fails (leaving them marked) if the write
+    // does.
+    fn rewrite_unwritten(&mut self) -> Result<(), OramError> {
+        if self.unwritten.is_empty() {
+            return Ok(());
+        }
+        let leaves = std::mem::take(&mut self.unwritten);
+        debug!(?leaves, "rewriting paths after a failed write-back");
+        self.evict_to_paths(&leaves)?;
+        self.finish_write_back()
+    }
+
+    // Sends one chunk of a bulk load, counting it in the metrics like a write_path request.
+    fn send_buckets(
+        &mut self,
+        first: i64,
+        count: usize,
+        blocks: Vec<Block>,
+    ) -> Result<(), OramError> {
+        let sent = blocks.len() as u64;
+        let started = Instant::now();
+        self.backend.send_buckets(first, count, blocks)?;
+        debug!(
+            first,
+            buckets = count,
+            elapsed_us = started.elapsed().as_micros() as u64,
+            "send_buckets"
+        );
+        self.metrics.rpc_writes += 1;
+        self.metrics.blocks_sent += sent;
+        Ok(())
+    }
+
+    // Starts recording every logical access to `path`, one `R <addr>` or `W <addr>` per
+    // line (the plain text trace format most ORAM simulators accept). Only the logical
+    // sequence is written; the protocol itself is unaffected.
+    pub fn export_trace(&mut self, path: &Path) -> Result<(), OramError> {
+        self.trace = Some(BufWriter::new(File::create(path)?));
+        Ok(())
+    }
+
+    pub fn flush_trace(&mut self) -> Result<(), OramError> {
+        if let Some(trace) = self.trace.as_mut() {
+            trace.flush()?;
+        }
+        Ok(())
+    }
+
+    fn record_access(&mut self, op: char, a: i64) {
+        if let Some(trace) = self.trace.as_mut() {
+            if let Err(e) = writeln!(trace, "{} {}", op, a) {
+                warn!("failed to write trace, disabling it: {}", e);
+                self.trace = None;
+            }
+        }
+    }
+
+    // Saves the client half of a checkpoint: geometry, pmap, stash (payloads in hex) and
+    // `ops_done`. The RNG is reseeded from itself and the new seed stored, so a run resumed
+    // from this file draws exactly the leaves the uninterrupted run would have. Write-backs
+    // still in flight or marked `unwritten` are settled first, as `reshuffle` does, so the
+    // server's tree holds every path the saved stash expects. The file is replaced
+    // atomically (see `write_atomically`): a crash leaves the old one or the new.
+    pub fn save_checkpoint(&mut self, path: &Path, ops_done: u64) -> Result<(), OramError> {
+        if self.posmap.is_some() {
+            return Err(recursive_unsupported().into());
+        }
+        if self.leaf_chooser.is_some() {
+            // Only the RNG's state can be written down
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "not supported with a custom leaf chooser",
+            )
+            .into());
+        }
+        self.finish_write_back()?;
+        self.rewrite_unwritten()?;
+        let next_seed: u64 = self.rng.gen();
+        self.rng = StdRng::seed_from_u64(next_seed);
+        self.dummy_rng = StdRng::seed_from_u64(next_seed.wrapping_add(1));
+
+        write_atomically(path, |out| {
+            writeln!(
+                out,
+                "{} {} {} {} {} {} {}",
+                self.n, self.l, self.z, self.b, self.num_leaves, next_seed, ops_done
+            )?;
+            let pmap: Vec<String> = self.pmap.iter().map(|x| x.to_string()).collect();
+            writeln!(out, "{}", pmap.join(" "))?;
+            for (a, data) in &self.stash {
+                let hex: String = data.iter().map(|b| format!("{:02x}", b)).collect();
+                writeln!(out, "{} {}", a, hex)?;
+            }
+            Ok(())
+        })?;
+        Ok(())
+    }
+
+    // Restores state written by `save_checkpoint`, returning the saved `ops_done`. The
+    // server must hold the tree snapshotted at the same checkpoint. Which addresses were
+    // ever written isn't saved (see `written`), so after a load a block missing from the
+    // tree reads as absent instead of failing with `MissingBlock`.
+    pub fn load_checkpoint(&mut self, path: &Path) -> Result<u64, OramError> {
+        let invalid = |msg: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {}", path.display(), msg),
+            )
+        };
+        let mut lines = BufReader::new(File::open(path)?).lines();
+
+        let header = lines.next().ok_or_else(|| invalid("missing header"))??;
+        let header: Vec<u64> = header
+            .split_whitespace()
+            .map(|f| f.parse().map_err(|_| invalid("bad header")))
+            .collect::<io::Result<_>>()?;
+        let [n, l, z, b, num_leaves, seed, ops_done] = header[..] else {
+            return Err(invalid("bad header").into());
+        };
+
+        let pmap = lines.next().ok_or_else(|| invalid("missing pmap"))??;
+        let pmap: Vec<i64> = pmap
+            .split_whitespace()
+            .map(|x| x.parse().map_err(|_| invalid("bad pmap entry")))
+            .collect::<io::Result<_>>()?;
+
+        let mut stash: HashMap<i64, Vec<u8>> = HashMap::new();
+        for line in lines {
+            let line = line?;
+            let (a, value) = line
+                .split_once(' ')
+                .ok_or_else(|| invalid("bad stash entry"))?;
+            let a = a.parse().map_err(|_| invalid("bad stash address"))?;
+            let data = decode_hex(value).ok_or_else(|| invalid("bad stash payload"))?;
+            stash.insert(a, data);
+        }
+
+        let field = |value: u64| i32::try_from(value).map_err(|_| invalid("header out of range"));
+        let count = |value: u64| i64::try_from(value).map_err(|_| invalid("header out of range"));
+        let (n, num_leaves) = (count(n)?, count(num_leaves)?);
+        // Accesses index the position map by address and follow its leaves into the tree,
+        // so a mismatched checkpoint is refused here rather than panicking mid-experiment
+        if pmap.len() as i64 != n {
+            return Err(invalid("pmap length doesn't match n").into());
+        }
+        if pmap.iter().any(|&leaf| leaf < 0 || leaf >= num_leaves) {
+            return Err(invalid("pmap leaf out of range").into());
+        }
+        if stash.keys().any(|&a| a < 0 || a >= n) {
+            return Err(invalid("stash address out of range").into());
+        }
+        self.n = n;
+        self.l = field(l)?;
+        self.set_z(field(z)?);
+        self.b = field(b)?;
+        self.num_leaves = num_leaves;
+        self.rng = StdRng::seed_from_u64(seed);
+        self.dummy_rng = StdRng::seed_from_u64(seed.wrapping_add(1));
+        self.pmap = pmap;
+        self.stash = stash;
+        self.unwritten.clear();
+        self.unconfirmed = None;
+        self.written = None;
+        self.clear_path_cache();
+        Ok(ops_done)
+    }
+
+    // Saves the client state (geometry, position map, stash and RNG) so another process
+    // can carry on with the same server tree through `load_state`. This is the client half
+    // of a checkpoint; the server keeps the tree as it is, so nothing is sent to it.
+    // Saving reseeds the RNG from itself (see `save_checkpoint`), so a handler restored
+    // from the file draws exactly the leaves this one goes on to draw: the two continue
+    // identically as long as they see the same accesses against the same tree.
+    pub fn save_state(&mut self, path: &Path) -> Result<(), OramError> {
+        self.save_checkpoint(path, 0)
+    }
+
+    // Restores state written by `save_state` and checks that the server's tree has the
+    // saved L, Z and B. A tree of the right shape that was changed or replaced since the
+    // save can't be detected here and will read wrong values.
+    pub fn load_state(&mut self, path: &Path) -> Result<(), OramError> {
+        self.load_checkpoint(path)?;
+        let (l, z, b) = (self.l, self.z, self.b);
+        self.fetch_config()?;
+        if (self.l, self.z, self.b) != (l, z, b) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "server has L={}, Z={}, B={} but {} expects L={}, Z={}, B={}",
+                    self.l,
+                    self.z,
+                    self.b,
+                    path.display(),
+                    l,
+                    z,
+                    b
+                ),
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    // Loads `address,value` lines from `path` directly into the stash, bypassing the tree,
+    // so the next write-backs have to evict them. Returns the number of entries loaded.
+    // Meant for stash-pressure experiments: a preloaded address that already lives in the
+    // tree is replaced by the tree's copy the next time its path is read.
+    pub fn preload_stash(&mut self, path: &Path) -> Result<usize, OramError> {
+        if self.read_only {
+            return Err(OramError::ReadOnly);
+        }
+        if self.posmap.is_some() {
+            return Err(recursive_unsupported().into());
+        }
+        let reader = BufReader::new(File::open(path)?);
+        let mut loaded = 0;
+        for (line_no, line) in reader.lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let invalid = |msg: &str| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{}:{}: {}", path.display(), line_no + 1, msg),
+                )
+            };
+            let (a, value) = line
+                .split_once(',')
+                .ok_or_else(|| invalid("expected `address,value`"))?;
+            let a: i64 = a.trim().parse().map_err(|_| invalid("bad address"))?;
+            let value: i64 = value.trim().parse().map_err(|_| invalid("bad value"))?;
+            if a < 0 || a >= self.n {
+                return Err(invalid("address out of range").into());
+            }
+
+            self.put_payload(a, 0, &value.to_le_bytes());
+            if let Some(written) = self.written.as_mut() {
+                written[slot(a)] = true;
+            }
+            loaded += 1;
+        }
+        Ok(loaded)
+    }
+
+    #[instrument(level = "debug", skip(self, _a))]
+    pub fn update_stash(&mut self, _a: i64, x: i64) -> Result<(), OramError> {
+        self.read_paths(&[x])
+    }
+
+    // Reads the union of the paths to `leaves` with a single request and pulls every real
+    // block into the stash. Shared buckets are requested once. Fails as a whole: the server
+    // rejects a request with any index outside the tree before reading anything, and every
+    // block is checked and opened before the first one enters the stash, so an error
+    // leaves the stash (and the skip-unchanged snapshot) as they were. A write-back still
+    // in flight (see `overlap_write_back`) is waited for first, so the read never races it.
+    fn read_paths(&mut self, leaves: &[i64]) -> Result<(), OramError> {
+        self.finish_write_back()?;
+        self.rewrite_unwritten()?;
+        let mut indices = Vec::new();
+
+        // Collect all indices for the RPC call
+        for &x in leaves {
+            for index in self.path_bucket_indices(x) {
+                if !indices.contains(&index) {
+                    indices.push(index);
+                }
+            }
+        }
+
+        // Read the whole path with a single request, leaving out cached buckets
+        let cache = self.path_cache.as_ref();
+        let missing: Vec<i64> = indices
+            .iter()
+            .copied()
+            .filter(|index| !cache.is_some_and(|cache| cache.contains_key(index)))
+            .collect();
+        let mut fetched = Vec::new();
+        if !missing.is_empty() {
+            let started = Instant::now();
+            let (blocks, crcs) = self.backend.read_path_checked(missing.clone())?;
+            self.metrics.rpc_reads += 1;
+            self.metrics.blocks_received += blocks.len() as u64;
+            debug!(
+                buckets = missing.len(),
+                elapsed_us = started.elapsed().as_micros() as u64,
+                "read_path"
+            );
+            fetched = PathBuckets::split(&missing, blocks, self.z)?;
+            verify_checksums(&missing, &fetched, crcs)?;
+        }
+        self.metrics.cached_buckets += (indices.len() - missing.len()) as u64;
+        let mut fetched = fetched.into_iter();
+        let buckets: Vec<Vec<Block>> = indices
+            .iter()
+            .map(|index| match cache.and_then(|cache| cache.get(index)) {
+                Some(blocks) => blocks.clone(),
+                None => fetched.next().expect("one bucket per missing index").blocks,
+            })
+            .collect();
+        let last_read = self
+            .skip_unchanged
+            .then(|| indices.iter().copied().zip(buckets.clone()).collect());
+        let mut real = Vec::new();
+        for (&index, bucket) in indices.iter().zip(buckets) {
+            for block in bucket {
+                let block = self.open(index, block)?;
+                if block.index != -1 {
+                    real.push(block);
+                }
+            }
+        }
+        if let Some(last_read) = last_read {
+            self.last_read = last_read;
+        }
+        self.stash
+            .extend(real.into_iter().map(|block| (block.index, block.data)));
+        Ok(())
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    pub fn write_back_stash(&mut self, x: i64) -> Result<(), OramError> {
+        self.write_back_paths(&[x])
+    }
+
+    // Refills every bucket on the paths to `leaves` from the stash and writes them all back
+    // in a single request, deepest level first.
+    //
+    // Eviction is the greedy one from the Path ORAM paper: each stashed block goes into the
+    // deepest bucket that is on both its own path and one of the written paths and still
+    // has room under the eviction limit. Blocks are placed deepest-reachable-level first,
+    // then by address, so a block that can only sit near the root never takes a slot a
+    // deeper one could have used. Never in hash order, so which blocks stay behind (and
+    // the stash size) is reproducible for a given seed.
+    //
+    // If the write fails, the evicted blocks go back into the stash and the paths are
+    // written again before the next read (see `unwritten`), so the handler stays usable.
+    fn write_back_paths(&mut self, leaves: &[i64]) -> Result<(), OramError> {
+        self.evict_to_paths(leaves)?;
+        self.record_stash_size()
+    }
+
+    // The eviction and write of `write_back_paths`, without its stash bookkeeping.
+    fn evict_to_paths(&mut self, leaves: &[i64]) -> Result<(), OramError> {
+        let mut write_block_request = WriteBlockRequest::default();
+        // What every bucket of the paths holds once this write-back lands, for the cache
+        let mut written = HashMap::new();
+        let mut visited = Vec::new();
+        let mut evicted = Vec::new(); // Every real block taken out of the stash
+        let mut candidates: Vec<(i32, i64, i64)> = self
+            .stash
+            .keys()
+            .map(|&a| {
+                let leaf = self.leaf_of(a);
+                let depth = leaves.iter().map(|&x| self.common_depth(x, leaf)).max();
+                (depth.unwrap_or(0), a, leaf)
+            })
+            .collect();
+        candidates.sort_unstable_by_key(|&(depth, a, _)| (Reverse(depth), a));
+        let limit = self.eviction_limit.min(self.z as usize);
+
+        // Addresses placed in each bucket, by heap index. Down to level `depth` a block's
+        // path runs along a written one, so its buckets at those levels are written too.
+        let mut placed: HashMap<i64, Vec<i64>> = HashMap::new();
+        for (depth, a, leaf) in candidates {
+            for l in (0..=depth).rev() {
+                let bucket = placed.entry(self.get_index(leaf, l)).or_default();
+                if bucket.len() < limit {
+                    bucket.push(a);
+                    break;
+                }
+            }
+        }
+
+        let paths: Vec<Vec<i64>> = leaves
+            .iter()
+            .map(|&x| self.path_bucket_indices(x).collect())
+            .collect();
+        for l in (0..=self.l).rev() {
+            for path in &paths {
+                let target_index = path[l as usize];
+                if visited.contains(&target_index) {
+                    continue;
+                }
+                visited.push(target_index);
+                let write_back = placed.remove(&target_index).unwrap_or_default();
+                trace!(level = l, bucket = target_index, ?write_back, "evict");
+
+                // Collect blocks for this index, filling with dummy blocks if needed
+                let mut real = Vec::new();
+                for a in &write_back {
+                    if let Some(data) = self.stash.remove(a) {
+                        let block = Block { index: *a, data };
+                        evicted.push(block.clone());
+                        real.push(block);
+                    }
+                }
+                let blocks_for_index = self.fill_bucket(target_index, real);
+                if self.path_cache.is_some() {
+                    written.insert(target_index, blocks_for_index.clone());
+                }
+
+                if self.skip_unchanged
+                    && self
+                        .last_read
+                        .get(&target_index)
+                        .is_some_and(|read| self.same_contents(read, &blocks_for_index))
+                {
+                    self.skipped_buckets += 1;
+                    continue;
+                }
+
+                self.real_blocks_written += write_back.len() as u64;
+                self.total_blocks_written += blocks_for_index.len() as u64;
+
+                // Add the target index and its blocks to the request
+                write_block_request.indices.push(target_index);
+                write_block_request.blocks.extend(blocks_for_index);
+            }
+        }
+        self.last_read.clear();
+
+        trace!(request = ?write_block_request, "write request");
+
+        if !write_block_request.indices.is_empty() {
+            // Send the batched write request
+            let WriteBlockRequest {
+                indices, blocks, ..
+            } = write_block_request;
+            if let Err(e) = self.write_buckets(indices, blocks) {
+                // The write may or may not have landed
+                self.clear_path_cache();
+                self.requeue(leaves, evicted);
+                return Err(e);
+            }
+            if self.overlap_write_back {
+                self.unconfirmed = Some((leaves.to_vec(), evicted));
+            }
+        }
+        if let Some(cache) = self.path_cache.as_mut() {
+            *cache = written;
+        }
+        // The position-map levels' write-backs for this access may still be in flight
+        if let Some(posmap) = self.posmap.as_mut() {
+            posmap.finish_writes()?;
+        }
+        Ok(())
+    }
+
+    // Records the stash size a write-back left, failing if it is over `max_stash`.
+    fn record_stash_size(&mut self) -> Result<(), OramError> {
+        self.peak_stash = self.peak_stash.max(self.stash.len());
+        if self.stash_histogram.len() <= self.stash.len() {
+            self.stash_histogram.resize(self.stash.len() + 1, 0);
+        }
+        self.stash_histogram[self.stash.len()] += 1;
+        debug!(
+            stash_size = self.stash.len(),
+            peak = self.peak_stash,
+            "stash"
+        );
+
+        // The tree is consistent either way; the blocks that didn't fit stay stashed
+        match self.max_stash {
+            Some(max) if self.stash.len() > max => {
+                let mut addresses: Vec<i64> = self.stash.keys().copied().collect();
+                addresses.sort_unstable();
+                Err(OramError::StashOverflow { max, addresses })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Blocks currently in the stash.
+    pub fn stash_size(&self) -> usize {
+        self.stash.len()
+    }
+
+    pub fn stash_info(&self) -> StashInfo {
+        StashInfo {
+            current: self.stash.len(),
+            peak: self.peak_stash,
+        }
+    }
+
+    // Largest stash left by any write-back since setup (or `reset_stash_stats`).
+    pub fn peak_stash(&self) -> usize {
+        self.peak_stash
+    }
+
+    // How many write-backs left the stash at each size, indexed by size, over the same
+    // span as `peak_stash`. Trailing sizes never seen are omitted.
+    pub fn stash_histogram(&self) -> &[u64] {
+        &self.stash_histogram
+    }
+
+    // Restarts `peak_stash` (from the current size) and the histogram, e.g. after warmup.
+    pub fn reset_stash_stats(&mut self) {
+        self.peak_stash = self.stash.len();
+        self.stash_histogram.clear();
+    }
+
+    fn seal_overhead(&self) -> usize {
+        if self.cipher.is_some() {
+            SEAL_OVERHEAD
+        } else {
+            0
+        }
+    }
+
+    // Whether bucket contents `read` and `written` hold the same blocks, for
+    // `skip_unchanged`. Plaintext dummies match whatever their random payloads; sealed
+    // blocks all carry index -1, so they must match byte for byte, which a fresh nonce
+    // never does. With a key nothing is skipped, so the CLI refuses the combination.
+    fn same_contents(&self, read: &[Block], written: &[Block]) -> bool {
+        if self.cipher.is_some() {
+            return read == written;
+        }
+        read.len() == written.len()
+            && read.iter().zip(written).all(|(old, new)| {
+                old.index == new.index && (new.index == -1 || old.data == new.data)
+            })
+    }
+
+    // Encrypts `block` (index and payload) for bucket `bucket` under a fresh random nonce,
+    // so a rewritten bucket never repeats a ciphertext and dummies look like real blocks.
+    // The bucket index is bound in as associated data, so the server can't move a sealed
+    // block to another bucket unnoticed.
+    fn seal(&self, bucket: i64, block: Block) -> Block {
+        let Some(cipher) = &self.cipher else {
+            return block;
+        };
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let mut plaintext = block.index.to_le_bytes().to_vec();
+        plaintext.extend(block.data);
+        let ciphertext = cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: &plaintext,
+                    aad: &bucket.to_le_bytes(),
+                },
+            )
+            .expect("AES-GCM encryption failed");
+        let mut data = nonce.to_vec();
+        data.extend(ciphertext);
+        Block { index: -1, data }
+    }
+
+    // The Z blocks stored in bucket `index`: `real`, then dummies, all sealed. This is the
+    // one place write-backs make dummies, so every path treats them alike. With a key,
+    // a dummy is sealed exactly like a real block (random nonce, index -1 outside, the
+    // same length), so every bucket looks full to the server and which slots are empty
+    // can't be told apart. Without one, the clear -1 index marks dummies (a random fake
+    // index would read back as that address), but their random payloads (see
+    // `dummy_block`) still keep them from standing out as runs of zeros.
+    fn fill_bucket(&mut self, index: i64, mut real: Vec<Block>) -> Vec<Block> {
+        let width = self.block_width();
+        real.resize_with(self.z as usize, || dummy_block(width, &mut self.dummy_rng));
+        real.into_iter()
+            .map(|block| self.seal(index, block))
+            .collect()
+    }
+
+    // Inverse of `seal`. Setup seals every bucket of an encrypted tree, so a block that
+    // doesn't decrypt, the server's all-zero fill included, was tampered with and fails
+    // with `Corruption`, as a bad checksum does.
+    fn open(&self, bucket: i64, block: Block) -> Result<Block, OramError> {
+        let Some(cipher) = &self.cipher else {
+            return Ok(block);
+        };
+        let failed = || OramError::Corruption { index: bucket };
+        if block.data.len() < NONCE_BYTES {
+            return Err(failed());
+        }
+        let (nonce, ciphertext) = block.data.split_at(NONCE_BYTES);
+        let plaintext = cipher
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: &bucket.to_le_bytes(),
+                },
+            )
+            .map_err(|_| failed())?;
+        if plaintext.len() < 8 {
+            return Err(failed());
+        }
+        Ok(Block {
+            index: decode_i64(&plaintext),
+            data: plaintext[8..].to_vec(),
+        })
+    }
+
+    fn reset_metrics(&mut self) {
+        self.metrics = Metrics::default();
+        self.wire_baseline = self.backend.wire_bytes();
+        if let Some(posmap) = self.posmap.as_mut() {
+            posmap.reset_metrics();
+        }
+    }
+
+    pub fn metrics(&self) -> Metrics {
+        let posmap = self
+            .posmap
+            .as_ref()
+            .map_or(Metrics::default(), |posmap| posmap.metrics());
+        let (sent, received) = self.backend.wire_bytes();
+        let own = Metrics {
+            bytes_sent: sent - self.wire_baseline.0,
+            bytes_received: received - self.wire_baseline.1,
+            ..self.metrics
+        };
+        own.plus(posmap)
+    }
+
+    // Backend round trips since setup, including those of every recursive position-map
+    // level.
+    pub fn round_trips(&self) -> u64 {
+        let metrics = self.metrics();
+        metrics.rpc_reads + metrics.rpc_writes
+    }
+
+    // Bytes of ORAM state the client holds: the position map (or, in recursive mode, every
+    // level's stash down to the base table) plus stashed (address, payload) pairs and a
+    // byte per address recording whether it was written. Container overhead is not counted.
+    pub fn client_memory_bytes(&self) -> usize {
+        let entry = std::mem::size_of::<i64>();
+        let stash = self.stash.len() * (entry + self.block_width());
+        let written = self.written.as_ref().map_or(0, Vec::len);
+        match &self.posmap {
+            Some(posmap) => stash + posmap.client_memory_bytes(),
+            None => self.pmap.len() * entry + written + stash,
+        }
+    }
+
+    // Payload bytes per block on the backend: B, plus the leaf tag in recursive mode.
+    fn block_width(&self) -> usize {
+        let tag = if self.posmap.is_some() { LEAF_BYTES } else { 0 };
+        self.b as usize + tag
+    }
+
+    // The leaf a stashed block is assigned to.
+    fn leaf_of(&self, a: i64) -> i64 {
+        match self.posmap {
+            Some(_) => decode_i64(&self.stash[&a]),
+            None => self.pmap[slot(a)],
+        }
+    }
+
+    // Takes `a`'s payload (without the leaf tag) out of the stash.
+    fn take_payload(&mut self, a: i64) -> Option<Vec<u8>> {
+        let tag = self.block_width() - self.b as usize;
+        self.stash.remove(&a).map(|mut payload| {
+            payload.drain(..tag);
+            payload
+        })
+    }
+
+    // Stashes `data`, fitted to B bytes, as `a`'s payload bound for `leaf`.
+    fn put_payload(&mut self, a: i64, leaf: i64, data: &[u8]) {
+        let mut payload = match self.posmap {
+            Some(_) => leaf.to_le_bytes().to_vec(),
+            None => Vec::with_capacity(self.b as usize),
+        };
+        payload.extend(fit_payload(data, self.b as usize));
+        self.stash.insert(a, payload);
+    }
+
+    fn next_leaf(&mut self) -> i64 {
+        match self.leaf_chooser.as_mut() {
+            Some(chooser) => chooser.next_leaf(self.num_leaves),
+            None => self.rng.next_leaf(self.num_leaves),
+        }
+    }
+
+    // Gives `a` a fresh random leaf (or keeps its current one when `fresh` is false) and
+    // returns the old and new leaves. In recursive mode the lookup and the update are a
+    // single access to the position-map ORAM.
+    fn remap(&mut self, a: i64, fresh: bool) -> Result<(i64, i64), OramError> {
+        let new_leaf = fresh.then(|| self.next_leaf());
+        let old_leaf = self.swap_leaf(a, new_leaf)?;
+        Ok((old_leaf, new_leaf.unwrap_or(old_leaf)))
+    }
+
+    // Puts `a` back on leaf `x` after an access remapped it and then failed before moving
+    // any block, so its block is still found on the path to `x`. In recursive mode this is
+    // one more position-map access.
+    fn restore_leaf(&mut self, a: i64, x: i64) -> Result<(), OramError> {
+        self.swap_leaf(a, Some(x)).map(drop)
+    }
+
+    // Returns `a`'s leaf, replacing it with `new_leaf` if that is given.
+    fn swap_leaf(&mut self, a: i64, new_leaf: Option<i64>) -> Result<i64, OramError> {
+        match self.posmap.as_mut() {
+            None => {
+                let old_leaf = self.pmap[slot(a)];
+                if let Some(leaf) = new_leaf {
+                    self.pmap[slot(a)] = leaf;
+                }
+                Ok(old_leaf)
+            }
+            Some(posmap) => {
+                let fanout = i64::from(self.posmap_fanout);
+                let offset = slot(a % fanout) * LEAF_BYTES;
+                posmap.access_with(Op::Write, a / fanout, true, |entry| {
+                    let entry = entry.get_or_insert_with(Vec::new);
+                    entry.resize(fanout as usize * LEAF_BYTES, 0);
+                    let leaf = &mut entry[offset..offset + LEAF_BYTES];
+                    let old_leaf = decode_i64(leaf);
+                    if let Some(new_leaf) = new_leaf {
+                        leaf.copy_from_slice(&new_leaf.to_le_bytes());
+                    }
+                    old_leaf
+                })
+            }
+        }
+    }
+
+    // Fraction of written-back blocks that carried real data, over every write-back so far.
+    // Each access writes Z*(L+1) blocks, so this is the inverse of the write amplification.
+    pub fn real_write_ratio(&self) -> f64 {
+        if self.total_blocks_written == 0 {
+            return 0.0;
+        }
+        self.real_blocks_written as f64 / self.total_blocks_written as f64
+    }
+
+    // The value at `a`, or `None` if it was never written or has been deleted. Out-of-range
+    // addresses fail with `AddressOutOfRange`, and a written address whose block can't be
+    // found fails with `MissingBlock` where that is tracked (see `written`).
+    pub fn read(&mut self, a: i64) -> Result<Option<i64>, OramError> {
+        self.access(Op::Read, a, None)
+    }
+
+    pub fn write(&mut self, a: i64, data: i64) -> Result<Option<i64>, OramError> {
+        self.access(Op::Write, a, Some(data))
+    }
+
+    // Reads `a`, or writes `data` to it, and returns the value it held before. `data` is
+    // ignored for reads and required for writes.
+    pub fn access(&mut self, op: Op, a: i64, data: Option<i64>) -> Result<Option<i64>, OramError> {
+        let data = data.map(i64::to_le_bytes);
+        let out = self.access_bytes(op, a, data.as_ref().map(|data| &data[..]))?;
+        Ok(out.as_deref().map(decode_i64))
+    }
+
+    // `access` on raw B-byte payloads; written data is zero-padded or truncated to B bytes.
+    #[instrument(level = "debug", skip(self, data))]
+    pub fn access_bytes(
+        &mut self,
+        op: Op,
+        a: i64,
+        data: Option<&[u8]>,
+    ) -> Result<Option<Vec<u8>>, OramError> {
+        match op {
+            Op::Read => self.access_with(op, a, self.remap_on_read, |entry| entry.clone()),
+            Op::Write => {
+                let data = data.expect("a write needs data");
+                self.access_with(op, a, true, |entry| entry.replace(data.to_vec()))
+            }
+        }
+    }
+
+    fn check_address(&self, a: i64) -> Result<(), OramError> {
+        // `n` stays -1 until setup or a checkpoint provides the addresses
+        if self.n < 1 {
+            return Err(OramError::NotSetup);
+        }
+        if a < 0 || a >= self.n {
+            return Err(OramError::AddressOutOfRange { a, n: self.n });
+        }
+        Ok(())
+    }
+
+    // Returns the B-byte payload stored at `a`.
+    pub fn read_bytes(&mut self, a: i64) -> Result<Option<Vec<u8>>, OramError> {
+        self.access_bytes(Op::Read, a, None)
+    }
+
+    // Stores `data` at `a`, zero-padded or truncated to B bytes, and returns the previous
+    // payload.
+    pub fn write_bytes(&mut self, a: i64, data: &[u8]) -> Result<Option<Vec<u8>>, OramError> {
+        self.access_bytes(Op::Write, a, Some(data))
+    }
+
+    // Removes `a` from the ORAM and returns the value it held. This is an ordinary access
+    // (path read, remap, write-back) that drops the block instead of writing it back, so
+    // the server can't tell it from a write. No server-side bookkeeping is needed: `a`
+    // keeps a fresh random leaf like any accessed address, so a later read still touches
+    // a uniformly random path and simply finds nothing there.
+    #[instrument(level = "debug", skip(self))]
+    pub fn delete(&mut self, a: i64) -> Result<Option<i64>, OramError> {
+        let out = self.access_with(Op::Write, a, true, |entry| entry.take())?;
+        Ok(out.as_deref().map(decode_i64))
+    }
+
+    // Every stored value by address, for checking a whole run end to end. This is an
+    // ordinary read of each address in 0..N in order, so it costs N path accesses, looks
+    // like any other N reads to the server and remaps each address as usual (the stash
+    // stays at its normal size). Deleted addresses are left out.
+    pub fn dump_all(&mut self) -> Result<BTreeMap<i64, i64>, OramError> {
+        let mut values = BTreeMap::new();
+        for a in 0..self.n {
+            if let Some(value) = self.read(a)? {
+                values.insert(a, value);
+            }
+        }
+        Ok(values)
+    }
+
+    // Re-randomizes the whole tree without losing data, e.g. between experiment phases:
+    // every bucket is read into the stash, every address gets a fresh random leaf, and
+    // the tree is rewritten bottom-up like a bulk setup (`evict_all`). Write-backs still
+    // in flight or marked `unwritten` are settled first, so no path is read stale. The
+    // server then sees each bucket read once and written once, whatever the position map
+    // held. Every bucket is read and opened before any block enters the stash, so a failed
+    // read leaves the handler as it was. Only the root's overflow stays stashed, and the
+    // stash statistics and metrics restart.
+    pub fn reshuffle(&mut self) -> Result<(), OramError> {
+        if self.n < 1 {
+            return Err(OramError::NotSetup);
+        }
+        if self.posmap.is_some() {
+            return Err(recursive_unsupported().into());
+        }
+        self.finish_write_back()?;
+        self.rewrite_unwritten()?;
+
+        let num_buckets = convert::bucket_count(self.l + 1).expect("tree was set up");
+        let per_request = self.buckets_per_request();
+        let indices: Vec<i64> = (0..num_buckets as i64).collect();
+        let mut found = HashMap::new();
+        for chunk in indices.chunks(per_request) {
+            let (blocks, crcs) = self.backend.read_path_checked(chunk.to_vec())?;
+            self.metrics.rpc_reads += 1;
+            self.metrics.blocks_received += blocks.len() as u64;
+            let buckets = PathBuckets::split(chunk, blocks, self.z)?;
+            verify_checksums(chunk, &buckets, crcs)?;
+            for (&index, bucket) in chunk.iter().zip(buckets) {
+                for block in bucket.blocks {
+                    let block = self.open(index, block)?;
+                    if block.index != -1 {
+                        found.insert(block.index, block.data);
+                    }
+                }
+            }
+        }
+        // A stashed block is newer than any copy the tree still holds
+        for (a, data) in found {
+            self.stash.entry(a).or_insert(data);
+        }
+
+        for a in 0..self.n {
+            self.pmap[slot(a)] = self.next_leaf();
+        }
+        self.clear_path_cache();
+        self.evict_all()?;
+        // Like setup, this isn't part of the workload being measured
+        self.reset_stash_stats();
+        self.reset_metrics();
+        Ok(())
+    }
+
+    // Has the server print its tree after each access when tracing at TRACE level. That
+    // costs a round trip, so it is off otherwise.
+    fn trace_tree(&mut self) {
+        if tracing::enabled!(Level::TRACE) {
+            if let Err(e) = self.backend.print() {
+                warn!("print RPC failed: {:?}", e);
+            }
+        }
+    }
+
+    // One access to `a`: remap it, read its old path into the stash, let `f` inspect or
+    // replace its payload (`None` when absent; leaving `None` deletes it), and write the
+    // path back. Every operation is built on this, so they all look alike to the server.
+    // If the path read fails, `a` goes back to its old leaf and the access changes nothing.
+    // If only the write-back fails, the access has taken effect and its path is written
+    // again before the next read (see `unwritten`).
+    fn access_with<R>(
+        &mut self,
+        op: Op,
+        a: i64,
+        fresh_leaf: bool,
+        f: impl FnOnce(&mut Option<Vec<u8>>) -> R,
+    ) -> Result<R, OramError> {
+        if self.read_only && op == Op::Write {
+            return Err(OramError::ReadOnly);
+        }
+        self.check_address(a)?;
+        self.record_access(op.code(), a);
+        // `x` is the leaf `a` had before the remap, so this read covers wherever the block
+        // currently is (on that path or already in the stash), and `f` sees its true value
+        let (x, new_leaf) = self.remap(a, fresh_leaf)?;
+        debug!(a, old_leaf = x, new_leaf, "remapped");
+        if let Err(e) = self.update_stash(a, x) {
+            // Nothing left the tree, so undoing the remap leaves the access with no effect
+            if new_leaf != x {
+                self.restore_leaf(a, x)?;
+            }
+            return Err(e);
+        }
+        trace!(stash = ?self.stash);
+
+        let mut entry = self.take_payload(a);
+        let found = entry.is_some();
+        let out = f(&mut entry);
+        let missing = self.track_written(op, a, found, entry.is_some());
+        if let Some(data) = entry {
+            self.put_payload(a, new_leaf, &data);
+        }
+
+        self.write_back_stash(x)?;
+        self.trace_tree();
+
+        // Reported only once the path is back, so a lost block doesn't also leave the
+        // server with a read that was never written back
+        if missing {
+            return Err(OramError::MissingBlock { a });
+        }
+        Ok(out)
+    }
+
+    // Checks whether an access to `a` found its block (`found`) against `written`, then
+    // records whether `a` holds a value afterwards (`kept`). Returns true when a written
+    // block was missing. A read that misses leaves the mark set, so the loss keeps being
+    // reported until a write or delete settles it.
+    fn track_written(&mut self, op: Op, a: i64, found: bool, kept: bool) -> bool {
+        let Some(written) = self.written.as_mut() else {
+            return false;
+        };
+        let missing = !found && written[slot(a)];
+        if !missing || op == Op::Write {
+            written[slot(a)] = kept;
+        }
+        missing
+    }
+
+    // An access to no address: reads the path to a random leaf into the stash and writes
+    // it back, after the same kind of dummy access to every position-map level. This is a
+    // security feature, not just padding. The server sees the same requests, sizes and
+    // uniformly random paths as for a real read or write, so issuing these whenever there
+    // is nothing real to do hides when and how often the client accesses the ORAM. A
+    // dummy that looked any different would mark the real accesses instead. Blocks on the
+    // path may move between tree and stash, but no payload or leaf changes.
+    #[instrument(level = "debug", skip(self))]
+    pub fn dummy_access(&mut self) -> Result<(), OramError> {
+        if let Some(posmap) = self.posmap.as_mut() {
+            posmap.dummy_access()?;
+        }
+        let leaf = self.next_leaf();
+        debug!(leaf, "dummy access");
+        self.read_paths(&[leaf])?;
+        self.write_back_paths(&[leaf])?;
+        self.trace_tree();
+        Ok(())
+    }
+
+    // Debug check of the Path ORAM invariant: every real block is either in the stash or
+    // in the bucket at its level on the path to its assigned leaf, exactly once, and every
+    // assigned leaf exists. Reads the whole tree a batch of buckets at a time, leaving the
+    // stash and metrics alone, so it costs O(tree size) and is only for tests and
+    // debugging. Position-map levels are checked first. Returns the first violation found.
+    pub fn validate_invariant(&mut self) -> Result<(), String> {
+        if let Some(posmap) = self.posmap.as_mut() {
+            posmap
+                .validate_invariant()
+                .map_err(|e| format!("position map: {}", e))?;
+        }
+
+        let mut seen = HashSet::new();
+        for &a in self.stash.keys() {
+            if a < 0 || a >= self.n {
+                return Err(format!("stash holds address {}, outside 0..{}", a, self.n));
+            }
+            let leaf = self.leaf_of(a);
+            if leaf < 0 || leaf >= self.num_leaves {
+                return Err(format!(
+                    "stashed address {} is assigned missing leaf {}",
+                    a, leaf
+                ));
+            }
+            seen.insert(a);
+        }
+
+        let num_buckets = convert::bucket_count(self.l + 1).map_err(|e| e.to_string())?;
+        let per_request = self.buckets_per_request();
+        let indices: Vec<i64> = (0..num_buckets as i64).collect();
+        for chunk in indices.chunks(per_request) {
+            let blocks = self
+                .backend
+                .read_path(chunk.to_vec())
+                .map_err(|e| OramError::from(e).to_string())?;
+            let buckets = PathBuckets::split(chunk, blocks, self.z)
+                .map_err(|e| OramError::from(e).to_string())?;
+            for (&index, bucket) in chunk.iter().zip(buckets) {
+                let level = (index + 1).ilog2() as i32;
+                for block in bucket.blocks {
+                    let block = self.open(index, block).map_err(|e| e.to_string())?;
+                    let a = block.index;
+                    if a == -1 {
+                        continue;
+                    }
+                    if a < 0 || a >= self.n {
+                        return Err(format!(
+                            "bucket {} holds address {}, outside 0..{}",
+                            index, a, self.n
+                        ));
+                    }
+                    let leaf = match self.posmap {
+                        Some(_) => decode_i64(&block.data),
+                        None => self.pmap[slot(a)],
+                    };
+                    if leaf < 0 || leaf >= self.num_leaves {
+                        return Err(format!(
+                            "address {} in bucket {} is assigned missing leaf {}",
+                            a, index, leaf
+                        ));
+                    }
+                    // Worked out from the leaf's node number rather than with `get_index`,
+                    // so a bug there doesn't hide itself
+                    if ((1_i64 << self.l) + leaf) >> (self.l - level) != index + 1 {
+                        return Err(format!(
+                            "address {} is in bucket {} (level {}), off the path to its leaf {}",
+                            a, index, level, leaf
+                        ));
+                    }
+                    if !seen.insert(a) {
+                        return Err(format!(
+                            "address {} is stored twice (again in bucket {})",
+                            a, index
+                        ));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Reads `a` and panics unless it holds `expected`, reporting enough state (stash size,
+    // the leaf `a` was read from and the one it moved to) to start debugging from.
+    #[cfg(any(test, feature = "testing"))]
+    pub fn read_expecting(&mut self, a: i64, expected: Option<i64>) {
+        // Leaves are only visible without a recursive position map
+        let old_leaf = self.pmap.get(slot(a)).copied();
+        let actual = self.read(a).expect("read failed");
+        if actual != expected {
+            panic!(
+                "read({}) returned {:?}, expected {:?} (stash size: {}, pmap[{}]: {:?} -> {:?})",
+                a,
+                actual,
+                expected,
+                self.stash.len(),
+                a,
+                old_leaf,
+                self.pmap.get(slot(a))
+            );
+        }
+    }
+
+    // Applies all `writes` as one unit: every affected path is read in one request, the
+    // stash is updated for every address, and the union of the paths is written back in
+    // one request, so the server applies the whole group under a single write. If an
+    // address appears more than once, the last write wins. Returns the previous value of
+    // each entry of `writes`, in order.
+    #[instrument(level = "debug", skip_all, fields(len = writes.len()))]
+    pub fn transaction(&mut self, writes: Vec<(i64, i64)>) -> Result<Vec<Option<i64>>, OramError> {
+        let ops: Vec<_> = writes
+            .into_iter()
+            .map(|(a, data)| (Op::Write, a, Some(data)))
+            .collect();
+        self.apply_batch(&ops)
+    }
+
+    // Runs `ops` with two round trips in total instead of two each: the paths of every
+    // distinct address are read in one request, the ops are applied to the stash in order,
+    // and the union of those paths is written back in one request. Returns what each op
+    // found at its address, as `access` would.
+    //
+    // Ops on the same address behave exactly as if run one at a time, in order: a read
+    // sees the latest write before it in the batch, and a write returns what the op before
+    // it left there. Each address is remapped once per batch, not once per op, so the
+    // server learns how many distinct addresses a batch touched, but not which ones or
+    // how often each.
+    #[instrument(level = "debug", skip_all, fields(len = ops.len()))]
+    pub fn batch_access(
+        &mut self,
+        ops: &[(Op, i64, Option<i64>)],
+    ) -> Result<Vec<Option<i64>>, OramError> {
+        self.apply_batch(ops)
+    }
+
+    // Reads every address in `start..end` (empty when `end <= start`) with two round trips,
+    // like `batch_access`. Each address is distinct, so each gets one fresh leaf; the
+    // results are in address order, however the paths overlapped.
+    #[instrument(level = "debug", skip(self))]
+    pub fn read_range(&mut self, start: i64, end: i64) -> Result<Vec<Option<i64>>, OramError> {
+        let ops: Vec<_> = (start..end).map(|a| (Op::Read, a, None)).collect();
+        self.apply_batch(&ops)
+    }
+
+    fn apply_batch(
+        &mut self,
+        ops: &[(Op, i64, Option<i64>)],
+    ) -> Result<Vec<Option<i64>>, OramError> {
+        if self.read_only && ops.iter().any(|&(op, _, _)| op == Op::Write) {
+            return Err(OramError::ReadOnly);
+        }
+        // Check everything before remapping anything, so a bad op leaves no block orphaned
+        for &(op, a, data) in ops {
+            self.check_address(a)?;
+            assert!(op == Op::Read || data.is_some(), "a write needs data");
+        }
+
+        let accesses: Vec<_> = ops.iter().map(|&(op, a, _)| (op, a)).collect();
+        let paths = self.remap_and_read_paths(&accesses)?;
+
+        // First address found missing, reported once the paths are written back
+        let mut missing = None;
+        let out = ops
+            .iter()
+            .map(|&(op, a, data)| {
+                let new_leaf = paths.new_leaf(a);
+                let old = self.take_payload(a);
+                let kept = match (op, data) {
+                    (Op::Write, Some(data)) => Some(data.to_le_bytes().to_vec()),
+                    _ => old.clone(),
+                };
+                if self.track_written(op, a, old.is_some(), kept.is_some()) {
+                    missing.get_or_insert(a);
+                }
+                if let Some(payload) = kept {
+                    self.put_payload(a, new_leaf, &payload);
+                }
+                old.as_deref().map(decode_i64)
+            })
+            .collect();
+
+        self.write_back_paths(&paths.leaves)?;
+        self.trace_tree();
+
+        if let Some(a) = missing {
+            return Err(OramError::MissingBlock { a });
+        }
+        Ok(out)
+    }
+
+    // Remaps every distinct address of `accesses` (to a fresh leaf if any access to it
+    // writes, or with remap-on-read) and reads all of their old paths in one request.
+    // If the read fails, every remap is undone and nothing has changed. Addresses must
+    // already be checked.
+    fn remap_and_read_paths(&mut self, accesses: &[(Op, i64)]) -> Result<BatchPaths, OramError> {
+        let mut remapped: Vec<(i64, i64, i64)> = Vec::new();
+        for &(op, a) in accesses {
+            self.record_access(op.code(), a);
+            if remapped.iter().any(|&(b, _, _)| b == a) {
+                continue;
+            }
+            let fresh_leaf =
+                self.remap_on_read || accesses.iter().any(|&(op, b)| b == a && op == Op::Write);
+            let (x, new_leaf) = self.remap(a, fresh_leaf)?;
+            debug!(a, old_leaf = x, new_leaf, "remapped");
+            remapped.push((a, x, new_leaf));
+        }
+        // In leaf order, so paths that share buckets are neighbours in the request. Which
+        // blocks go where doesn't depend on this: buckets on one level never compete.
+        let mut leaves: Vec<i64> = remapped.iter().map(|&(_, x, _)| x).collect();
+        leaves.sort_unstable();
+        leaves.dedup();
+
+        if let Err(e) = self.read_paths(&leaves) {
+            // As in `access_with`: no block has moved yet, so put every address back
+            for &(a, x, new_leaf) in remapped.iter().rev() {
+                if new_leaf != x {
+                    self.restore_leaf(a, x)?;
+                }
+            }
+            return Err(e);
+        }
+        trace!(stash = ?self.stash);
+        Ok(BatchPaths { remapped, leaves })
+    }
+
+    // Relocates the value at `a` to `b`, leaving `a` empty, and returns whatever `b` held
+    // before it was overwritten. Both paths are read in one request and written back in
+    // one, like a two-address `batch_access`, and both addresses get fresh leaves, so the
+    // server can't tell a move from any other pair of writes. It is all one access: if the
+    // path read fails nothing changes, and once it succeeds the value can't be lost in
+    // between. Moving an address onto itself leaves its value in place and returns it.
+    #[instrument(level = "debug", skip(self))]
+    pub fn move_block(&mut self, a: i64, b: i64) -> Result<Option<i64>, OramError> {
+        if self.read_only {
+            return Err(OramError::ReadOnly);
+        }
+        self.check_address(a)?;
+        self.check_address(b)?;
+        let paths = self.remap_and_read_paths(&[(Op::Write, a), (Op::Write, b)])?;
+
+        let moved = self.take_payload(a);
+        let (old, missing) = if a == b {
+            let missing = self.track_written(Op::Write, a, moved.is_some(), moved.is_some());
+            (moved.clone(), missing.then_some(a))
+        } else {
+            let old = self.take_payload(b);
+            let missing_a = self.track_written(Op::Write, a, moved.is_some(), false);
+            let missing_b = self.track_written(Op::Write, b, old.is_some(), moved.is_some());
+            (old, missing_a.then_some(a).or(missing_b.then_some(b)))
+        };
+        if let Some(payload) = &moved {
+            self.put_payload(b, paths.new_leaf(b), payload);
+        }
+
+        self.write_back_paths(&paths.leaves)?;
+        self.trace_tree();
+
+        if let Some(lost) = missing {
+            return Err(OramError::MissingBlock { a: lost });
+        }
+        Ok(old.as_deref().map(decode_i64))
+    }
+
+    // Heap index (root = 0) of the level-`l` bucket on the path to leaf `x`. Defined for
+    // 0 <= l <= self.l and 0 <= x < 2^self.l; a single-bucket tree (self.l == 0) maps
+    // its one leaf to the root.
+    fn get_index(&self, x: i64, l: i32) -> i64 {
+        debug_assert!(
+            (0..=self.l).contains(&l),
+            "level {l} outside 0..={}",
+            self.l
+        );
+        debug_assert!((0..1 << self.l).contains(&x), "leaf {x} outside tree");
+        (((1 << self.l) + x) >> (self.l - l)) - 1
+    }
+
+    // Heap indices of the L+1 buckets on the path to leaf `x`, root first, so the level-l
+    // bucket is item l. Same domain as `get_index`.
+    fn path_bucket_indices(&self, x: i64) -> impl Iterator<Item = i64> + '_ {
+        (0..=self.l).map(move |l| self.get_index(x, l))
+    }
+
+    // Deepest level whose bucket is on both the path to `x` and the path to `y`: 0 when
+    // they only share the root, self.l when x == y. Same domain as `get_index`.
+    fn common_depth(&self, x: i64, y: i64) -> i32 {
+        self.l - (i64::BITS - (x ^ y).leading_zeros()) as i32
+    }
+}
+
+// An access waiting for the `AsyncPathORAM` worker, with where to send its result.
+struct QueuedAccess {
+    op: Op,
+    a: i64,
+    data: Option<i64>,
+    done: oneshot::Sender<Result<Option<i64>, OramError>>,
+}
+
+// Front end for launching accesses as futures, e.g. several `read_future`s gathered with
+// `join_all`, that batches the ones in flight together.
+//
+// Concurrency contract: the handler lives on a worker thread of its own, and accesses
+// queue up for it. A batch starts with the first queued access and takes whatever else
+// arrives within `window`, up to `max_batch` accesses, and runs them as one
+// `batch_access`: every path is read in a single request, the stash and position map are
+// updated access by access in queue order, and the union of the paths is written back in
+// a single request. So concurrent accesses share their round trips instead of paying two
+// each, while stash and position-map updates stay serialized and no access sees another
+// half applied. Accesses to the same address apply in the order they were queued, which
+// is the order `read_future`/`write_future` were called. How accesses group into batches
+// depends on timing, and the server sees each batch's size, as with `batch_access`.
+// Batches run one at a time on the worker, each through the same blocking handler as a
+// direct call: nothing here overlaps one batch's requests with another's, or a recursive
+// handler's position-map lookups with its data-tree reads.
+//
+// An access with a bad address, or a write to a read-only handler, fails alone. If a
+// batch fails as a whole, the access queued first gets the error and the rest
+// `OramError::Pipeline`. A failure after the paths were read (a lost block, a failed
+// write-back) is reported the same way, although the batch took effect.
+pub struct AsyncPathORAM {
+    queue: std_mpsc::Sender<QueuedAccess>,
+}
+
+impl Clone for AsyncPathORAM {
+    fn clone(&self) -> Self {
+        AsyncPathORAM {
+            queue: self.queue.clone(),
+        }
+    }
+}
+
+impl AsyncPathORAM {
+    // Moves `handler` onto the worker thread, which exits once every clone is dropped.
+    pub fn new<B: OramBackend + Send + 'static>(
+        handler: PathORAMHandler<B>,
+        max_batch: usize,
+        window: Duration,
+    ) -> Self {
+        let (queue, pending) = std_mpsc::channel();
+        std::thread::Builder::new()
+            .name("oram-pipeline".into())
+            .spawn(move || run_pipeline(handler, pending, max_batch.max(1), window))
+            .expect("failed to start the ORAM worker");
+        AsyncPathORAM { queue }
+    }
+
+    // Queued as soon as this is called, not when the future is first polled.
+    pub fn read_future(&self, a: i64) -> impl Future<Output = Result<Option<i64>, OramError>> {
+        self.enqueue(Op::Read, a, None)
+    }
+
+    pub fn write_future(
+        &self,
+        a: i64,
+        data: i64,
+    ) -> impl Future<Output = Result<Option<i64>, OramError>> {
+        self.enqueue(Op::Write, a, Some(data))
+    }
+
+    fn enqueue(
+        &self,
+        op: Op,
+        a: i64,
+        data: Option<i64>,
+    ) -> impl Future<Output = Result<Option<i64>, OramError>> {
+        let (done, result) = oneshot::channel();
+        let sent = self.queue.send(QueuedAccess { op, a, data, done });
+        async move {
+            sent.map_err(|_| pipeline_stopped())?;
+            result.await.map_err(|_| pipeline_stopped())?
+        }
+    }
+}
+
+fn pipeline_stopped() -> OramError {
+    OramError::Pipeline("the ORAM worker stopped".into())
+}
+
+// The `AsyncPathORAM` worker: batches queued accesses as described there, running each
+// batch to completion before collecting the next, until every sender is gone.
+fn run_pipeline<B: OramBackend>(
+    mut handler: PathORAMHandler<B>,
+    pending: std_mpsc::Receiver<QueuedAccess>,
+    max_batch: usize,
+    window: Duration,
+) {
+    let mut batch = Vec::with_capacity(max_batch);
+    while let Ok(first) = pending.recv() {
+        batch.push(first);
+        let deadline = std::time::Instant::now() + window;
+        while batch.len() < max_batch {
+            let left = deadline.saturating_duration_since(std::time::Instant::now());
+            match pending.recv_timeout(left) {
+                Ok(access) => batch.push(access),
+                Err(_) => break,
+            }
+        }
+
+        // Accesses that would fail the whole batch are answered on their own
+        let (batch_ok, rejected): (Vec<_>, Vec<_>) = batch.drain(..).partition(|access| {
+            !(handler.read_only && access.op == Op::Write)
+                && handler.check_address(access.a).is_ok()
+        });
+        for access in rejected {
+            let error = match handler.check_address(access.a) {
+                Err(e) => e,
+                Ok(()) => OramError::ReadOnly,
+            };
+            // The caller may have gone away; nothing to do then
+            let _ = access.done.send(Err(error));
+        }
+        if batch_ok.is_empty() {
+            continue;
+        }
+
+        let ops: Vec<_> = batch_ok
+            .iter()
+            .map(|access| (access.op, access.a, access.data))
+            .collect();
+        match handler.batch_access(&ops) {
+            Ok(values) => {
+                for (access, value) in batch_ok.into_iter().zip(values) {
+                    let _ = access.done.send(Ok(value));
+                }
+            }
+            Err(e) => {
+                let shared = format!("failed with its batch: {}", e);
+                let mut error = Some(e);
+                for access in batch_ok {
+                    let result = error
+                        .take()
+                        .unwrap_or_else(|| OramError::Pipeline(shared.clone()));
+                    let _ = access.done.send(Err(result));
+                }
+            }
+        }
+    }
+}
+
+// Converts a value type to and from the B-byte payloads blocks carry, for `TypedOram`.
+// `to_bytes` may return fewer than B bytes (the payload is zero-padded), never more;
+// `from_bytes` gets all B bytes back, padding included.
+//
+// A 16-byte record, say an (id, balance) pair, stored in a tree with B >= 16:
+//
+//     struct Account { id: u64, balance: i64 }
+//
+//     impl BlockCodec for Account {
+//         fn to_bytes(&self) -> Vec<u8> {
+//             [self.id.to_le_bytes(), self.balance.to_le_bytes()].to_bytes()
+//         }
+//
+//         fn from_bytes(bytes: &[u8]) -> Result<Self, OramError> {
+//             let [id, balance] = <[[u8; 8]; 2]>::from_bytes(bytes)?;
+//             Ok(Account { id: u64::from_le_bytes(id), balance: i64::from_le_bytes(balance) })
+//         }
+//     }
+//
+//     let mut accounts: TypedOram<_, Account> = TypedOram::new(handler);
+//     accounts.write(7, &Account { id: 7, balance: -40 })?;
+pub trait BlockCodec: Sized {
+    fn to_bytes(&self) -> Vec<u8>;
+    fn from_bytes(bytes: &[u8]) -> Result<Self, OramError>;
+}
+
+// Same encoding as `PathORAMHandler::read`/`write`, so the two views agree.
+impl BlockCodec for i64 {
+    fn to_bytes(&self) -> Vec<u8> {
+        self.to_le_bytes().to_vec()
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, OramError> {
+        Ok(decode_i64(bytes))
+    }
+}
+
+impl BlockCodec for i32 {
+    fn to_bytes(&self) -> Vec<u8> {
+        self.to_le_bytes().to_vec()
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, OramError> {
+        <[u8; 4]>::from_bytes(bytes).map(i32::from_le_bytes)
+    }
+}
+
+// The first N bytes of the payload.
+impl<const N: usize> BlockCodec for [u8; N] {
+    fn to_bytes(&self) -> Vec<u8> {
+        self.to_vec()
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, OramError> {
+        bytes
+            .get(..N)
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or_else(|| {
+                OramError::Codec(format!(
+                    "a {}-byte payload can't hold a {}-byte array",
+                    bytes.len(),
+                    N
+                ))
+            })
+    }
+}
+
+// N byte arrays back to back, e.g. `[[u8; 8]; 2]` for two 8-byte fields.
+impl<const M: usize, const N: usize> BlockCodec for [[u8; M]; N] {
+    fn to_bytes(&self) -> Vec<u8> {
+        self.concat()
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, OramError> {
+        let mut fields = [[0; M]; N];
+        for (k, field) in fields.iter_mut().enumerate() {
+            *field = <[u8; M]>::from_bytes(bytes.get(k * M..).unwrap_or_default())?;
+        }
+        Ok(fields)
+    }
+}
+
+// UTF-8 behind a 4-byte little-endian length, so strings may contain NUL bytes and the
+// padding isn't mistaken for text.
+impl BlockCodec for String {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = (self.len() as u32).to_le_bytes().to_vec();
+        bytes.extend(self.as_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, OramError> {
+        let len = u32::from_le_bytes(<[u8; 4]>::from_bytes(bytes)?) as usize;
+        let text = bytes.get(4..4 + len).ok_or_else(|| {
+            OramError::Codec(format!(
+                "string of {} bytes runs past its {}-byte payload",
+                len,
+                bytes.len()
+            ))
+        })?;
+        String::from_utf8(text.to_vec())
+            .map_err(|e| OramError::Codec(format!("stored string isn't UTF-8: {}", e)))
+    }
+}
+
+// Typed front end over a handler's byte payloads: values of type V go in and come out,
+// encoded with `BlockCodec`. The handler itself is unchanged (`TypedOram<_, i64>` reads
+// and writes exactly what `read`/`write` do) and can be taken back with `into_inner`.
+// A value wider than the tree's B bytes is refused rather than truncated.
+pub struct TypedOram<B: OramBackend, V: BlockCodec = i64> {
+    handler: PathORAMHandler<B>,
+    values: PhantomData<V>,
+}
+
+impl<B: OramBackend, V: BlockCodec> TypedOram<B, V> {
+    pub fn new(handler: PathORAMHandler<B>) -> Self {
+        TypedOram {
+            handler,
+            values: PhantomData,
+        }
+    }
+
+    pub fn handler_mut(&mut self) -> &mut PathORAMHandler<B> {
+        &mut self.handler
+    }
+
+    pub fn into_inner(self) -> PathORAMHandler<B> {
+        self.handler
+    }
+
+    // The value at `a`, or `None` if it was never written or has been deleted.
+    pub fn read(&mut self, a: i64) -> Result<Option<V>, OramError> {
+        self.handler
+            .read_bytes(a)?
+            .map(|bytes| V::from_bytes(&bytes))
+            .transpose()
+    }
+
+    // Stores `value` at `a` and returns the value it replaces.
+    pub fn write(&mut self, a: i64, value: &V) -> Result<Option<V>, OramError> {
+        let bytes = value.to_bytes();
+        let width = self.handler.block_size();
+        if bytes.len() > width as usize {
+            return Err(OramError::Codec(format!(
+                "value needs {} bytes but blocks hold {}",
+                bytes.len(),
+                width
+            )));
+        }
+        self.handler
+            .write_bytes(a, &bytes)?
+            .map(|bytes| V::from_bytes(&bytes))
+            .transpose()
+    }
+}
+
+fn run_client(args: &Args) {
+    let z = args.z.expect("--z is required");
+    let exp = args.n.expect("--n is required");
+    let n = 1 << exp;
+    // Checked up front so a bad line fails before setup, not after
+    let trace = args.trace.as_ref().map(|path| match read_trace(path, n) {
+        Ok(ops) => ops,
+        Err(e) => {
+            eprintln!("Failed to read trace: {}", e);
+            std::process::exit(1);
+        }
+    });
+    let rt = match Runtime::new() {
+        Ok(rt) => rt,
+        Err(e) => {
+            eprintln!("Failed to start the async runtime: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let client = match connect(&rt, args) {
+        Ok(client) => client,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let retry_backoff = Duration::from_millis(args.retry_backoff_ms);
+    let mut backend = GrpcBackend::new(client.clone(), rt.handle().clone());
+    backend.set_namespace(args.namespace.clone());
+    backend.set_retry(args.max_retries, retry_backoff);
+    backend.set_force_setup(args.force_setup);
+    backend.set_packed(!args.per_block_wire);
+    backend.set_checksums(args.checksums);
+    let mut handler = PathORAMHandler::new(backend, z, args.seed);
+    handler.set_block_size(args.b.expect("--b is required"));
+    if let Some(limit) = args.max_message_bytes {
+        handler.set_max_message_bytes(limit);
+    }
+    if let Some(path) = &args.key_file {
+        match read_key(path) {
+            Ok(key) => handler.set_key(&key),
+            Err(e) => {
+                eprintln!("Failed to read key from {}: {}", path.display(), e);
+                std::process::exit(1);
+            }
+        }
+    }
+    handler.set_skip_unchanged(args.skip_unchanged);
+    if args.path_cache {
+        warn!("--path-cache shows the server which buckets reads skip; results are not oblivious");
+        handler.set_path_cache(true);
+    }
+    handler.set_max_stash(args.max_stash);
+    if let Some(limit) = args.eviction_limit {
+        handler.set_eviction_limit(limit);
+    }
+    handler.set_levels(args.levels);
+    handler.set_pipeline_posmap(!args.no_posmap_pipeline);
+    if args.no_remap_on_read {
+        warn!("--no-remap-on-read makes reads linkable; results are not oblivious");
+        handler.set_remap_on_read(false);
+    }
+
+    if let Some(path) = &args.export_trace {
+        if let Err(e) = handler.export_trace(path) {
+            eprintln!("Failed to open trace file {}: {}", path.display(), e);
+        }
+    }
+
+    // Fail now rather than partway into setup or warmup
+    let status = match handler.ping() {
+        Ok(status) => status,
+        Err(e) => {
+            eprintln!("Server is not responding: {}", e);
+            std::process::exit(1);
+        }
+    };
+    println!(
+        "Server up for {:.1}s; tree {}",
+        status.uptime_ms as f64 / 1000.0,
+        if status.initialized {
+            "already set up"
+        } else {
+            "not set up yet"
+        }
+    );
+    if args.resume_from.is_some() && !status.initialized {
+        eprintln!("Nothing to resume against: restart the server with --restore-from");
+        std::process::exit(1);
+    }
+    if args.resume_from.is_none() && status.initialized && !args.force_setup {
+        eprintln!("Tree is already set up; pass --force-setup to replace it");
+        std::process::exit(1);
+    }
+
+    let start_op = match &args.resume_from {
+        Some(base) => {
+            let (slot, marked_ops) = match read_checkpoint_marker(base) {
+                Ok(Some(marker)) => marker,
+                Ok(None) => {
+                    eprintln!(
+                        "No complete checkpoint at {}: {} is missing",
+                        base.display(),
+                        checkpoint_marker(base).display()
+                    );
+                    std::process::exit(1);
+                }
+                Err(e) => {
+                    eprintln!("Failed to load checkpoint: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            let path = checkpoint_file(base, slot, "client");
+            let ops_done = match handler.load_checkpoint(&path) {
+                Ok(ops_done) if ops_done == marked_ops => ops_done,
+                Ok(ops_done) => {
+                    eprintln!(
+                        "{} is from op {}, but the marker names op {}",
+                        path.display(),
+                        ops_done,
+                        marked_ops
+                    );
+                    std::process::exit(1);
+                }
+                Err(e) => {
+                    eprintln!("Failed to load checkpoint: {}", e);
+                    std::process::exit(1);
+                }
+            };
+
+            // The server must hold the tree this checkpoint was taken against
+            let (l, z, b) = (handler.l, handler.z, handler.b);
+            match handler.fetch_config() {
+                Ok(_) if (handler.l, handler.z, handler.b) == (l, z, b) => {}
+                Ok(config) => {
+                    eprintln!(
+                        "Server has L={}, Z={}, B={} but the checkpoint expects L={}, Z={}, B={}",
+                        config.num_layers - 1,
+                        config.bucket_size,
+                        config.block_size,
+                        l,
+                        z,
+                        b
+                    );
+                    std::process::exit(1);
+                }
+                Err(e) => {
+                    eprintln!("Failed to fetch server config: {}", e);
+                    std::process::exit(1);
+                }
+            }
+            println!(
+                "Resuming after {} operations from {} (the server must be restored from {})",
+                ops_done,
+                path.display(),
+                checkpoint_file(base, slot, "server").display()
+            );
+            ops_done
+        }
+        None => {
+            let data: Vec<i64> = (0..n).collect();
+            let start = Instant::now();
+            let result = match args.posmap_fanout {
+                Some(fanout) => {
+                    let mut level = 0;
+                    let mut posmap_backend = || {
+                        level += 1;
+                        let mut backend = GrpcBackend::new(client.clone(), rt.handle().clone());
+                        backend.set_namespace(format!("{}/posmap{}", args.namespace, level));
+                        backend.set_retry(args.max_retries, retry_backoff);
+                        backend.set_force_setup(args.force_setup);
+                        backend.set_packed(!args.per_block_wire);
+                        backend.set_checksums(args.checksums);
+                        backend
+                    };
+                    handler.setup_recursive(data, fanout, args.posmap_cutoff, &mut posmap_backend)
+                }
+                None if args.bulk_setup => handler.setup_bulk(data),
+                None => handler.setup(data),
+            };
+            if let Err(e) = result {
+                eprintln!("Setup failed: {}", e);
+                std::process::exit(1);
+            }
+            let elapsed = start.elapsed().as_secs_f64();
+            println!("\nsetup time taken: {:.4} seconds", elapsed);
+            match handler.server_stats() {
+                Ok(stats) => println!(
+                    "server tree: {} buckets of {} ({} blocks), about {:.1} MiB",
+                    stats.num_buckets,
+                    stats.bucket_size,
+                    stats.total_blocks,
+                    stats.estimated_bytes as f64 / (1 << 20) as f64
+                ),
+                Err(e) => eprintln!("Failed to fetch server stats: {}", e),
+            }
+
+            if args.validate {
+                if let Err(e) = validate_setup(&mut handler, n) {
+                    eprintln!("Validation failed: {}", e);
+                    std::process::exit(1);
+                }
+                println!("Validation passed");
+            }
+
+            if let Some(path) = &args.preload_stash {
+                match handler.preload_stash(path) {
+                    Ok(count) => {
+                        println!("Preloaded {} stash entries from {}", count, path.display())
+                    }
+                    Err(e) => {
+                        eprintln!("Failed to preload stash: {}", e);
+                        std::process::exit(1);
+                    }
+                }
+            }
+            0
+        }
+    };
+
+    if let Some(ops) = trace {
+        println!("Replaying {} accesses", ops.len());
+        let shadow = args.shadow_check.then(|| ShadowModel::after_setup(n));
+        replay_trace(handler, &ops, shadow);
+        return;
+    }
+
+    let options = ExperimentOptions {
+        checkpoint: (args.checkpoint_every > 0)
+            .then_some((args.checkpoint_every, args.checkpoint_path.as_path())),
+        start_op,
+        max_ops_per_sec: args.max_ops_per_sec,
+        padded_ops_per_sec: args.padded_ops_per_sec,
+        shadow_check: args.shadow_check,
+        seed: args.seed,
+        warmup_ops: args.warmup,
+        test_ops: args.iters,
+        pattern: args.pattern,
+        stash_log: args.stash_log.as_deref(),
+        stash_log_fsync: args.stash_log_fsync,
+    };
+    run_experiment(handler, n, z, &options);
+}
+
+// Addresses `validate_setup` writes and reads back, spread evenly over 0..N.
+const VALIDATE_ADDRESSES: i64 = 8;
+
+// Self-check for a freshly set up tree holding `a` at every address `a`: each sampled
+// address must hold its setup value, take a new one and read it back, and is then
+// restored, so the run starts from the same data. Then the invariant is checked over the
+// whole tree. Catches a wrong Z or a server holding some other tree before the run
+// spends any time. Stash and metrics counters are reset afterwards, like after setup.
+fn validate_setup<B: OramBackend>(handler: &mut PathORAMHandler<B>, n: i64) -> Result<(), String> {
+    let mut addresses: Vec<i64> = (0..VALIDATE_ADDRESSES)
+        .map(|i| i * n / VALIDATE_ADDRESSES)
+        .collect();
+    addresses.dedup();
+    for a in addresses {
+        // Never a setup value, since those are all non-negative
+        let probe = !a;
+        let old = handler.write(a, probe).map_err(|e| e.to_string())?;
+        if old != Some(a) {
+            return Err(format!(
+                "address {} held {:?} after setup, expected {}",
+                a, old, a
+            ));
+        }
+        let read = handler.read(a).map_err(|e| e.to_string())?;
+        if read != Some(probe) {
+            return Err(format!(
+                "address {} read back {:?} after writing {}",
+                a, read, probe
+            ));
+        }
+        handler.write(a, a).map_err(|e| e.to_string())?;
+    }
+    handler.validate_invariant()?;
+    handler.reset_stash_stats();
+    handler.reset_metrics();
+    Ok(())
+}
+
+// Writes `path` through `write` into a file beside it, syncs that and renames it over
+// `path`, so a crash mid-write leaves the previous contents intact, as the server's
+// snapshots do.
+fn write_atomically(
+    path: &Path,
+    write: impl FnOnce(&mut BufWriter<File>) -> io::Result<()>,
+) -> io::Result<()> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+
+    let mut out = BufWriter::new(File::create(&tmp_path)?);
+    write(&mut out)?;
+    out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    std::fs::rename(&tmp_path, path)
+}
+
+// Checkpoints alternate between two slots: slot s is the server snapshot
+// `<base>.<s>.server` and the client state `<base>.<s>.client`. `<base>.latest` holds
+// "<slot> <ops_done>" for the last pair written in full. A checkpoint fills the other slot
+// and rewrites the marker last, so a crash at any point leaves the previous pair complete
+// and still named, and a resume never pairs a tree with another checkpoint's client state.
+fn checkpoint_file(base: &Path, slot: u8, side: &str) -> PathBuf {
+    let mut path = base.as_os_str().to_owned();
+    path.push(format!(".{}.{}", slot, side));
+    PathBuf::from(path)
+}
+
+fn checkpoint_marker(base: &Path) -> PathBuf {
+    let mut path = base.as_os_str().to_owned();
+    path.push(".latest");
+    PathBuf::from(path)
+}
+
+// The slot and `ops_done` of the last complete checkpoint at `base`, if there is one.
+fn read_checkpoint_marker(base: &Path) -> io::Result<Option<(u8, u64)>> {
+    let path = checkpoint_marker(base);
+    let marker = match std::fs::read_to_string(&path) {
+        Ok(marker) => marker,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let invalid = || {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: bad checkpoint marker", path.display()),
+        )
+    };
+    let (slot, ops_done) = marker.trim().split_once(' ').ok_or_else(invalid)?;
+    let slot = slot
+        .parse()
+        .ok()
+        .filter(|&slot| slot < 2)
+        .ok_or_else(invalid)?;
+    let ops_done = ops_done.parse().map_err(|_| invalid())?;
+    Ok(Some((slot, ops_done)))
+}
+
+// Snapshots the server tree, then the client state, into the slot the last checkpoint
+// didn't use, and only then names that slot in the marker (see `checkpoint_file`).
+fn save_checkpoint<B: OramBackend>(handler: &mut PathORAMHandler<B>, base: &Path, ops_done: u64) {
+    let slot = match read_checkpoint_marker(base) {
+        Ok(Some((last, _))) => 1 - last,
+        Ok(None) => 0,
+        Err(e) => {
+            eprintln!("Failed to checkpoint at op {}: {}", ops_done, e);
+            return;
+        }
+    };
+    let server_path = checkpoint_file(base, slot, "server")
+        .to_string_lossy()
+        .into_owned();
+    match handler.backend.flush(server_path) {
+        Ok(true) => {}
+        Ok(false) => {
+            eprintln!("Server refused checkpoint at op {}", ops_done);
+            return;
+        }
+        Err(e) => {
+            eprintln!("Failed to checkpoint server at op {}: {:?}", ops_done, e);
+            return;
+        }
+    }
+    if let Err(e) = handler.save_checkpoint(&checkpoint_file(base, slot, "client"), ops_done) {
+        eprintln!("Failed to checkpoint client at op {}: {}", ops_done, e);
+        return;
+    }
+    let marker = write_atomically(&checkpoint_marker(base), |out| {
+        writeln!(out, "{} {}", slot, ops_done)
+    });
+    if let Err(e) = marker {
+        eprintln!("Failed to checkpoint at op {}: {}", ops_done, e);
+    }
+}
+
+// Token bucket allowing `rate` operations per second with bursts of up to one second's
+// worth. `acquire` sleeps until a token is available.
+struct RateLimiter {
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl RateLimiter {
+    fn new(rate: f64) -> Self {
+        RateLimiter {
+            rate,
+            tokens: rate.min(1.0),
+            last: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let refill = now.duration_since(self.last).as_secs_f64() * self.rate;
+        self.tokens = (self.tokens + refill).min(self.rate.max(1.0));
+        self.last = now;
+    }
+
+    fn acquire(&mut self) {
+        self.refill();
+        if self.tokens < 1.0 {
+            let wait = (1.0 - self.tokens) / self.rate;
+            std::thread::sleep(Duration::from_secs_f64(wait));
+            self.tokens = 1.0;
+            self.last = Instant::now();
+        }
+        self.tokens -= 1.0;
+    }
+
+    // Takes a token if one is available, without waiting.
+    fn try_acquire(&mut self) -> bool {
+        self.refill();
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+// Fixed schedule of `rate` slots per second. Unlike `RateLimiter` it never bursts: a slot
+// missed because the previous access ran long is dropped rather than made up, so the
+// server sees evenly spaced accesses.
+struct Pacer {
+    interval: Duration,
+    next: Instant,
+}
+
+impl Pacer {
+    fn new(rate: f64) -> Self {
+        Pacer {
+            interval: Duration::from_secs_f64(1.0 / rate),
+            next: Instant::now(),
+        }
+    }
+
+    // Sleeps until the next slot.
+    fn tick(&mut self) {
+        let now = Instant::now();
+        if self.next > now {
+            std::thread::sleep(self.next - now);
+        } else {
+            self.next = now;
+        }
+        self.next += self.interval;
+    }
+}
+
+// Waits until the next operation may run. With a pacer, every slot until then is spent on
+// a dummy access; returns how many were made.
+fn wait_for_slot<B: OramBackend>(
+    handler: &mut PathORAMHandler<B>,
+    limiter: &mut Option<RateLimiter>,
+    pacer: &mut Option<Pacer>,
+) -> Result<u64, OramError> {
+    let Some(pacer) = pacer.as_mut() else {
+        if let Some(limiter) = limiter.as_mut() {
+            limiter.acquire();
+        }
+        return Ok(0);
+    };
+    let mut dummies = 0;
+    loop {
+        pacer.tick();
+        if limiter.as_mut().is_none_or(RateLimiter::try_acquire) {
+            return Ok(dummies);
+        }
+        handler.dummy_access()?;
+        dummies += 1;
+    }
+}
+
+struct ExperimentOptions<'a> {
+    checkpoint: Option<(u64, &'a Path)>, // (every N ops, checkpoint base path)
+    start_op: u64,                       // Operations already done when resuming
+    max_ops_per_sec: Option<f64>,
+    padded_ops_per_sec: Option<f64>,
+    shadow_check: bool,
+    seed: u64,
+    warmup_ops: u64,
+    test_ops: u64,
+    pattern: Pattern,
+    stash_log: Option<&'a Path>, // Overrides the default stash log file name
+    stash_log_fsync: bool,       // Whether every stash log flush is also an fsync
+}
+
+// The address each operation of an experiment reads, by operation number. Random
+// addresses come from their own generator, offset from the seed so they don't mirror the
+// handler's leaf draws; `skip` replays draws so a resumed run continues the same sequence.
+struct AddressStream {
+    pattern: Pattern,
+    n: i64,
+    rng: StdRng,
+}
+
+impl AddressStream {
+    fn new(pattern: Pattern, n: i64, seed: u64) -> Self {
+        AddressStream {
+            pattern,
+            n,
+            rng: StdRng::seed_from_u64(seed.wrapping_add(1)),
+        }
+    }
+
+    // Advances past the first `ops` operations.
+    fn skip(&mut self, ops: u64) {
+        if self.pattern == Pattern::Random {
+            for _ in 0..ops {
+                self.rng.gen_range(0..self.n);
+            }
+        }
+    }
+
+    // The address for operation `op`; must be called once per operation, in order.
+    fn next(&mut self, op: u64) -> i64 {
+        match self.pattern {
+            Pattern::Sequential => (op % self.n as u64) as i64,
+            Pattern::Random => self.rng.gen_range(0..self.n),
+        }
+    }
+}
+
+// Reference model of what the ORAM should hold, consulted on every access. Any
+// disagreement is a correctness bug (a lost, stale or phantom block). It starts at the
+// values setup wrote; only trace replays write.
+struct ShadowModel {
+    values: HashMap<i64, i64>,
+}
+
+impl ShadowModel {
+    // What setup wrote: each address in 0..n holding itself.
+    fn after_setup(n: i64) -> Self {
+        ShadowModel {
+            values: (0..n).map(|a| (a, a)).collect(),
+        }
+    }
+
+    fn check_read(&self, op: u64, a: i64, actual: Option<i64>) {
+        let expected = self.values.get(&a).copied();
+        if actual != expected {
+            panic!(
+                "shadow check failed at op {}: read({}) returned {:?}, expected {:?}",
+                op, a, actual, expected
+            );
+        }
+    }
+
+    fn record_write(&mut self, a: i64, value: i64) {
+        self.values.insert(a, value);
+    }
+}
+
+// One line of a replay trace.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct TraceOp {
+    op: Op,
+    a: i64,
+    value: Option<i64>, // Set for writes
+}
+
+// Reads a replay trace: one `R <addr>` or `W <addr> <value>` per line, blank lines
+// ignored. The whole file is checked before anything runs, so a malformed line or an
+// address outside 0..n fails with its line number instead of partway into a replay.
+// `--export-trace` writes the same format but without write values, so a trace of
+// writes recorded that way needs them filled in first.
+fn read_trace(path: &Path, n: i64) -> Result<Vec<TraceOp>, OramError> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut ops = Vec::new();
+    for (line_no, line) in reader.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let invalid = |msg: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}:{}: {}", path.display(), line_no + 1, msg),
+            )
+        };
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let (op, a, value) = match fields[..] {
+            ["R", a] => (Op::Read, a, None),
+            ["W", a, value] => {
+                let value = value.parse().map_err(|_| invalid("bad value"))?;
+                (Op::Write, a, Some(value))
+            }
+            ["W", _] => return Err(invalid("a write needs a value: `W <addr> <value>`").into()),
+            _ => return Err(invalid("expected `R <addr>` or `W <addr> <value>`").into()),
+        };
+        let a: i64 = a.parse().map_err(|_| invalid("bad address"))?;
+        if a < 0 || a >= n {
+            return Err(invalid("address out of range").into());
+        }
+        ops.push(TraceOp { op, a, value });
+    }
+    Ok(ops)
+}
+
+// Feeds `ops` through `access` in order, timing each, and reports latency percentiles,
+// the peak stash and the traffic per access. Stash and traffic figures cover the replay
+// only. With `shadow` every access's result is checked against the model.
+fn replay_trace<B: OramBackend>(
+    mut handler: PathORAMHandler<B>,
+    ops: &[TraceOp],
+    mut shadow: Option<ShadowModel>,
+) {
+    handler.reset_stash_stats();
+    handler.reset_metrics();
+    let mut latencies = LatencyHistogram::new();
+    let mut start = Instant::now();
+    for (i, &TraceOp { op, a, value }) in ops.iter().enumerate() {
+        let i = i as u64;
+        let access_start = Instant::now();
+        let result = handler.access(op, a, value);
+        latencies.record(access_start.elapsed());
+        let previous = result.unwrap_or_else(|e| abort_run(i, e));
+        // Writes return what they replaced, so they are checked the same way
+        if let Some(shadow) = shadow.as_mut() {
+            shadow.check_read(i, a, previous);
+            if let Some(value) = value {
+                shadow.record_write(a, value);
+            }
+        }
+
+        if (i + 1).is_multiple_of(PROGRESS_EVERY) {
+            println!(
+                "replay: {} accesses completed, time for last {}: {:.4} seconds",
+                i + 1,
+                PROGRESS_EVERY,
+                start.elapsed().as_secs_f64()
+            );
+            start = Instant::now();
+        }
+    }
+
+    latencies.print_summary();
+    println!("peak stash: {} blocks", handler.peak_stash());
+    let metrics = handler.metrics();
+    let accesses = ops.len().max(1) as f64;
+    println!(
+        "RPCs per access: {:.2} reads, {:.2} writes; blocks per access: {:.1} received, {:.1} sent",
+        metrics.rpc_reads as f64 / accesses,
+        metrics.rpc_writes as f64 / accesses,
+        metrics.blocks_received as f64 / accesses,
+        metrics.blocks_sent as f64 / accesses
+    );
+    if let Err(e) = handler.flush_trace() {
+        eprintln!("Failed to flush trace: {}", e);
+    }
+}
+
+// Per-operation stash sizes for the test phase. Writes are buffered; if the file can't be
+// opened or a write fails, logging is switched off with a warning and the run carries on.
+// Summary statistics are kept in memory either way.
+struct StashLog {
+    file: Option<BufWriter<File>>,
+    fsync: bool, // Whether `flush` also syncs the file to disk
+    count: u64,
+    total: u64,
+    max: usize,
+}
+
+impl StashLog {
+    // Truncates `path` unless `append` (a resumed run adds to the sizes logged before).
+    fn open(path: &Path, append: bool, fsync: bool) -> Self {
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(append)
+            .truncate(!append)
+            .open(path);
+        let file = match file {
+            Ok(file) => Some(BufWriter::new(file)),
+            Err(e) => {
+                warn!(
+                    "can't open {} ({}); stash sizes won't be logged",
+                    path.display(),
+                    e
+                );
+                None
+            }
+        };
+        StashLog {
+            file,
+            fsync,
+            count: 0,
+            total: 0,
+            max: 0,
+        }
+    }
+
+    fn record(&mut self, stash_size: usize) {
+        self.count += 1;
+        self.total += stash_size as u64;
+        self.max = self.max.max(stash_size);
+
+        if let Some(file) = self.file.as_mut() {
+            if let Err(e) = writeln!(file, "{}", stash_size) {
+                self.disable(e);
+            }
+        }
+    }
+
+    fn flush(&mut self) {
+        self.flush_inner(self.fsync);
+    }
+
+    // Flushes and syncs the log whatever `fsync` says, at the end of the run.
+    fn finish(&mut self) {
+        self.flush_inner(true);
+    }
+
+    fn flush_inner(&mut self, sync: bool) {
+        if let Some(file) = self.file.as_mut() {
+            let result = file.flush().and_then(|()| {
+                if sync {
+                    file.get_ref().sync_data()
+                } else {
+                    Ok(())
+                }
+            });
+            if let Err(e) = result {
+                self.disable(e);
+            }
+        }
+    }
+
+    fn disable(&mut self, e: io::Error) {
+        warn!("stash log write failed ({}); continuing without it", e);
+        self.file = None;
+    }
+
+    fn print_summary(&self) {
+        if self.count == 0 {
+            return;
+        }
+        println!(
+            "\nstash size over {} ops: mean {:.3}, max {}",
+            self.count,
+            self.total as f64 / self.count as f64,
+            self.max
+        );
+    }
+}
+
+// Writes `histogram` (counts by stash size) as the `size count` lines `plot-stash` reads,
+// skipping sizes never seen. When appending, e.g. after resuming, sizes that appear twice
+// are summed by the reader.
+fn write_stash_histogram(path: &Path, histogram: &[u64], append: bool) -> io::Result<()> {
+    let file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .append(append)
+        .truncate(!append)
+        .open(path)?;
+    let mut out = BufWriter::new(file);
+    for (size, &count) in histogram.iter().enumerate() {
+        if count > 0 {
+            writeln!(out, "{} {}", size, count)?;
+        }
+    }
+    out.flush()
+}
+
+// Reads between progress lines in both phases
+const PROGRESS_EVERY: u64 = 10_000;
+
+// Access latencies in nanoseconds, HdrHistogram style: exact below 128ns, and above that
+// 64 buckets per power of two, so any reported value is within 1/64 (about 1.6%) of the
+// true one. Memory stays at a few KiB however long the run.
+struct LatencyHistogram {
+    counts: Vec<u64>,
+    total: u64,
+    max: u64,
+}
+
+impl LatencyHistogram {
+    const EXACT: u64 = 128;
+    const PER_OCTAVE: u64 = 64;
+
+    fn new() -> Self {
+        LatencyHistogram {
+            counts: Vec::new(),
+            total: 0,
+            max: 0,
+        }
+    }
+
+    fn bucket(nanos: u64) -> usize {
+        if nanos < Self::EXACT {
+            return nanos as usize;
+        }
+        // Keep the top 7 bits: nanos >> shift lands in 64..128
+        let shift = 63 - nanos.leading_zeros() as u64 - 6;
+        (Self::EXACT + (shift - 1) * Self::PER_OCTAVE + (nanos >> shift) - Self::PER_OCTAVE)
+            as usize
+    }
+
+    // Largest value that falls in `bucket`.
+    fn highest_in(bucket: usize) -> u64 {
+        let bucket = bucket as u64;
+        if bucket < Self::EXACT {
+            return bucket;
+        }
+        let shift = (bucket - Self::EXACT) / Self::PER_OCTAVE + 1;
+        let top = (bucket - Self::EXACT) % Self::PER_OCTAVE + Self::PER_OCTAVE;
+        // Wraps to u64::MAX for the very last bucket
+        ((top + 1) << shift).wrapping_sub(1)
+    }
+
+    fn record(&mut self, latency: Duration) {
+        let nanos = u64::try_from(latency.as_nanos()).unwrap_or(u64::MAX);
+        let bucket = Self::bucket(nanos);
+        if self.counts.len() <= bucket {
+            self.counts.resize(bucket + 1, 0);
+        }
+        self.counts[bucket] += 1;
+        self.total += 1;
+        self.max = self.max.max(nanos);
+    }
+
+    // The latency at or below which `percentile` percent of accesses fell.
+    fn percentile(&self, percentile: f64) -> Duration {
+        let rank = ((percentile / 100.0 * self.total as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bucket, &count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Duration::from_nanos(Self::highest_in(bucket).min(self.max));
+            }
+        }
+        Duration::from_nanos(self.max)
+    }
+
+    fn print_summary(&self) {
+        if self.total == 0 {
+            return;
+        }
+        let us = |d: Duration| d.as_secs_f64() * 1e6;
+        println!(
+            "access latency over {} accesses: p50 {:.1}us, p90 {:.1}us, p99 {:.1}us, max {:.1}us",
+            self.total,
+            us(self.percentile(50.0)),
+            us(self.percentile(90.0)),
+            us(self.percentile(99.0)),
+            us(Duration::from_nanos(self.max))
+        );
+    }
+}
+
+// Stops the experiment on a failed access; continuing would report numbers for a run
+// whose tree no longer matches the client state.
+fn abort_run(op: u64, e: OramError) -> ! {
+    eprintln!("Operation {} failed: {}", op, e);
+    std::process::exit(1);
+}
+
+// Runs the warmup and test phases as one sequence of operations numbered from 0, so a
+// checkpoint taken after `ops_done` operations resumes at the same point in either phase.
+fn run_experiment<B: OramBackend>(
+    mut handler: PathORAMHandler<B>,
+    n: i64,
+    z: i32,
+    options: &ExperimentOptions,
+) {
+    let (checkpoint, start_op) = (options.checkpoint, options.start_op);
+    let (warmup_ops, test_ops) = (options.warmup_ops, options.test_ops);
+    let mut addresses = AddressStream::new(options.pattern, n, options.seed);
+    addresses.skip(start_op);
+    let mut limiter = options.max_ops_per_sec.map(RateLimiter::new);
+    let mut pacer = options.padded_ops_per_sec.map(Pacer::new);
+    let mut dummies = 0;
+    let run_start = Instant::now();
+    // The experiment only reads, so the model stays as setup left the tree
+    let shadow = options.shadow_check.then(|| ShadowModel::after_setup(n));
+
+    let maybe_checkpoint = |handler: &mut PathORAMHandler<B>, ops_done: u64| {
+        if let Some((every, base)) = checkpoint {
+            if ops_done.is_multiple_of(every) {
+                save_checkpoint(handler, base, ops_done);
+            }
+        }
+    };
+
+    let mut start = Instant::now();
+    for i in start_op.min(warmup_ops)..warmup_ops {
+        dummies += wait_for_slot(&mut handler, &mut limiter, &mut pacer)
+            .unwrap_or_else(|e| abort_run(i, e));
+        let a = addresses.next(i);
+        let value = handler.read(a).unwrap_or_else(|e| abort_run(i, e));
+        if let Some(shadow) = &shadow {
+            shadow.check_read(i, a, value);
+        }
+        maybe_checkpoint(&mut handler, i + 1);
+
+        if (i + 1).is_multiple_of(PROGRESS_EVERY) {
+            let elapsed = start.elapsed().as_secs_f64();
+            println!(
+                "Warmup: {} reads completed, time for last {}: {:.4} seconds",
+                i + 1,
+                PROGRESS_EVERY,
+                elapsed
+            );
+            start = Instant::now(); // Reset timer
+        }
+    }
+
+    let resuming_test = start_op > warmup_ops;
+    let mut run_name = format!(
+        "n={}_z={}_b={}_seed={}",
+        n,
+        z,
+        handler.block_size(),
+        options.seed
+    );
+    if handler.levels.is_some() {
+        run_name.push_str(&format!("_levels={}", handler.l));
+    }
+    if options.pattern == Pattern::Random {
+        run_name.push_str("_pattern=random");
+    }
+    let stash_log_path = match options.stash_log {
+        Some(path) => path.to_path_buf(),
+        None => PathBuf::from(format!("stash_sizes_{}.txt", run_name)),
+    };
+    let mut stash_log = StashLog::open(&stash_log_path, resuming_test, options.stash_log_fsync);
+    // The histogram and peak cover the measured reads only
+    handler.reset_stash_stats();
+
+    // The measured reads
+    let mut latencies = LatencyHistogram::new();
+    let mut start = Instant::now();
+    for i in start_op.saturating_sub(warmup_ops)..test_ops {
+        dummies += wait_for_slot(&mut handler, &mut limiter, &mut pacer)
+            .unwrap_or_else(|e| abort_run(warmup_ops + i, e));
+        let a = addresses.next(warmup_ops + i);
+        let access_start = Instant::now();
+        let result = handler.read(a);
+        latencies.record(access_start.elapsed());
+        let value = match result {
+            Ok(value) => value,
+            Err(e) => {
+                // Keep the sizes leading up to the failure, an overflowing one included
+                stash_log.record(handler.stash_size());
+                stash_log.finish();
+                abort_run(warmup_ops + i, e)
+            }
+        };
+        if let Some(shadow) = &shadow {
+            shadow.check_read(warmup_ops + i, a, value);
+        }
+
+        // Write stash size to the file
+        stash_log.record(handler.stash_size());
+
+        // Display time taken for every PROGRESS_EVERY operations
+        if (i + 1).is_multiple_of(PROGRESS_EVERY) {
+            let elapsed = start.elapsed().as_secs_f64();
+            println!(
+                "test: {} reads completed, time for last {}: {:.4} seconds",
+                i + 1,
+                PROGRESS_EVERY,
+                elapsed
+            );
+            stash_log.flush(); // Flush to ensure data is saved
+            start = Instant::now(); // Reset timer
+        }
+
+        if checkpoint.is_some() {
+            // Stash sizes logged so far must survive alongside the checkpoint
+            stash_log.flush();
+        }
+        maybe_checkpoint(&mut handler, warmup_ops + i + 1);
+    }
+
+    stash_log.finish();
+    stash_log.print_summary();
+    latencies.print_summary();
+    let histogram_path = PathBuf::from(format!("stash_histogram_{}.txt", run_name));
+    match write_stash_histogram(&histogram_path, handler.stash_histogram(), resuming_test) {
+        Ok(()) => println!(
+            "peak stash: {} blocks; histogram in {}",
+            handler.peak_stash(),
+            histogram_path.display()
+        ),
+        Err(e) => eprintln!("Failed to write {}: {}", histogram_path.display(), e),
+    }
+
+    if let Some(target) = options.max_ops_per_sec {
+        let ops = (warmup_ops + test_ops).saturating_sub(start_op) as f64;
+        println!(
+            "\nachieved rate: {:.1} ops/sec (target {:.1})",
+            ops / run_start.elapsed().as_secs_f64(),
+            target
+        );
+    }
+    if let Some(target) = options.padded_ops_per_sec {
+        let ops = ((warmup_ops + test_ops).saturating_sub(start_op) + dummies) as f64;
+        println!(
+            "padded rate: {:.1} accesses/sec (target {:.1}), {} of them dummies",
+            ops / run_start.elapsed().as_secs_f64(),
+            target,
+            dummies
+        );
+    }
+    let ratio = handler.real_write_ratio();
+    println!(
+        "\nreal blocks written: {} / {} ({:.4}); write amplification: {:.2}x",
+        handler.real_blocks_written,
+        handler.total_blocks_written,
+        ratio,
+        if ratio > 0.0 { 1.0 / ratio } else { 0.0 }
+    );
+
+    let metrics = handler.metrics();
+    let ops = (warmup_ops + test_ops).saturating_sub(start_op).max(1) as f64;
+    println!(
+        "RPCs per access: {:.2} reads, {:.2} writes; blocks per access: {:.1} received, {:.1} sent",
+        metrics.rpc_reads as f64 / ops,
+        metrics.rpc_writes as f64 / ops,
+        metrics.blocks_received as f64 / ops,
+        metrics.blocks_sent as f64 / ops
+    );
+
+    if metrics.bytes_sent + metrics.bytes_received > 0 {
+        println!(
+            "message bytes per access: {:.0} received, {:.0} sent",
+            metrics.bytes_received as f64 / ops,
+            metrics.bytes_sent as f64 / ops
+        );
+    }
+
+    if handler.skip_unchanged {
+        println!("unchanged buckets skipped: {}", handler.skipped_buckets());
+    }
+    if handler.path_cache.is_some() {
+        println!(
+            "path buckets served from cache: {:.2} per access",
+            metrics.cached_buckets as f64 / ops
+        );
+    }
+
+    if let Err(e) = handler.flush_trace() {
+        eprintln!("Failed to flush trace: {}", e);
+    }
+}
+
+// One line of `repl` input.
+enum ReplCommand {
+    Setup { n: i64, z: i32, b: i32 },
+    Read(i64),
+    Write(i64, i64),
+    Delete(i64),
+    Print,
+    Stash,
+    Save(PathBuf),
+    Help,
+    Quit,
+}
+
+const REPL_HELP: &str = "\
+setup <n> <z> <b>  set up a fresh tree of n addresses (address a holds a), Z and B
+read <a>           print the value at a
+write <a> <v>      store v at a and print the previous value
+delete <a>         remove a and print the value it held
+print              have the server print its tree
+stash              print the stash size and peak
+save <path>        save the client state, for `repl --state <path>` to resume from
+quit               leave (so does end of input)";
+
+fn parse_repl_line(line: &str) -> Result<ReplCommand, String> {
+    fn number<T: std::str::FromStr>(field: &str) -> Result<T, String> {
+        field
+            .parse()
+            .map_err(|_| format!("`{}` is not a number", field))
+    }
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    Ok(match fields[..] {
+        ["setup", n, z, b] => ReplCommand::Setup {
+            n: number(n)?,
+            z: number(z)?,
+            b: number(b)?,
+        },
+        ["read", a] => ReplCommand::Read(number(a)?),
+        ["write", a, value] => ReplCommand::Write(number(a)?, number(value)?),
+        ["delete", a] => ReplCommand::Delete(number(a)?),
+        ["print"] => ReplCommand::Print,
+        ["stash"] => ReplCommand::Stash,
+        ["save", path] => ReplCommand::Save(PathBuf::from(path)),
+        ["help"] => ReplCommand::Help,
+        ["quit"] | ["exit"] => ReplCommand::Quit,
+        _ => return Err(format!("can't parse `{}`; try `help`", line)),
+    })
+}
+
+// Interactive front end: one connection and one handler for the whole session, so the
+// position map and stash live as long as the REPL does. Transport options (namespace,
+// retries, wire format, checksums, key) come from the top-level flags. A failed command
+// is reported and the session goes on. With `state`, the session starts from that file
+// if it exists and saves back to it on the way out, so separate invocations (e.g.
+// `echo "read 5" | client repl --state s`) work on the same tree.
+fn run_repl(args: &Args, state: Option<&Path>) {
+    let rt = match Runtime::new() {
+        Ok(rt) => rt,
+        Err(e) => {
+            eprintln!("Failed to start the async runtime: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let client = match connect(&rt, args) {
+        Ok(client) => client,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let key = args.key_file.as_ref().map(|path| match read_key(path) {
+        Ok(key) => key,
+        Err(e) => {
+            eprintln!("Failed to read key from {}: {}", path.display(), e);
+            std::process::exit(1);
+        }
+    });
+
+    let new_handler = |z: i32, force_setup: bool| {
+        let mut backend = GrpcBackend::new(client.clone(), rt.handle().clone());
+        backend.set_namespace(args.namespace.clone());
+        backend.set_retry(
+            args.max_retries,
+            Duration::from_millis(args.retry_backoff_ms),
+        );
+        backend.set_force_setup(force_setup);
+        backend.set_packed(!args.per_block_wire);
+        backend.set_checksums(args.checksums);
+        let mut handler = PathORAMHandler::new(backend, z, args.seed);
+        if let Some(limit) = args.max_message_bytes {
+            handler.set_max_message_bytes(limit);
+        }
+        if let Some(key) = &key {
+            handler.set_key(key);
+        }
+        handler
+    };
+
+    let mut handler: Option<PathORAMHandler<GrpcBackend>> = None;
+    let mut replace = args.force_setup;
+    // Pick up the session a previous `repl --state` left behind
+    if let Some(path) = state.filter(|path| path.exists()) {
+        let mut loaded = new_handler(0, false);
+        if let Err(e) = loaded.load_state(path) {
+            eprintln!("Failed to load state from {}: {}", path.display(), e);
+            std::process::exit(1);
+        }
+        println!("Loaded state from {}", path.display());
+        handler = Some(loaded);
+        replace = true;
+    }
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    loop {
+        print!("> ");
+        let _ = io::stdout().flush();
+        let line = match lines.next() {
+            Some(Ok(line)) => line,
+            Some(Err(e)) => {
+                eprintln!("Failed to read input: {}", e);
+                break;
+            }
+            None => break,
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        let command = match parse_repl_line(line.trim()) {
+            Ok(command) => command,
+            Err(e) => {
+                println!("{}", e);
+                continue;
+            }
+        };
+
+        let result = match (command, handler.as_mut()) {
+            (ReplCommand::Help, _) => Ok(REPL_HELP.to_string()),
+            (ReplCommand::Quit, _) => break,
+            (ReplCommand::Setup { n, z, b }, _) => {
+                let mut fresh = new_handler(z, replace);
+                fresh.set_block_size(b);
+                match fresh.setup((0..n).collect()) {
+                    Ok(()) => {
+                        // Later setups in this session replace this tree
+                        replace = true;
+                        handler = Some(fresh);
+                        Ok(format!("set up {} addresses", n))
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+            (_, None) => Err(OramError::NotSetup),
+            (ReplCommand::Read(a), Some(handler)) => {
+                handler.read(a).map(|value| format!("{:?}", value))
+            }
+            (ReplCommand::Write(a, value), Some(handler)) => handler
+                .write(a, value)
+                .map(|old| format!("previous value: {:?}", old)),
+            (ReplCommand::Delete(a), Some(handler)) => handler
+                .delete(a)
+                .map(|old| format!("deleted value: {:?}", old)),
+            (ReplCommand::Print, Some(handler)) => handler
+                .backend_mut()
+                .print()
+                .map(|_| "printed on the server".to_string())
+                .map_err(OramError::from),
+            (ReplCommand::Stash, Some(handler)) => {
+                let info = handler.stash_info();
+                Ok(format!("{} blocks (peak {})", info.current, info.peak))
+            }
+            (ReplCommand::Save(path), Some(handler)) => handler
+                .save_state(&path)
+                .map(|()| format!("saved to {}", path.display())),
+        };
+        match result {
+            Ok(output) => println!("{}", output),
+            Err(e) => println!("error: {}", e),
+        }
+    }
+
+    if let (Some(path), Some(handler)) = (state, handler.as_mut()) {
+        match handler.save_state(path) {
+            Ok(()) => println!("Saved state to {}", path.display()),
+            Err(e) => {
+                eprintln!("Failed to save state to {}: {}", path.display(), e);
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+// Runs `ops` sequential reads over 2^exp addresses against an in-process tree and prints
+// the client footprint and round trips for each position-map layout. With --server, the
+// recursive layout is then timed against the server with and without the position-map
+// pipeline, which only real round trips can show the effect of.
+fn compare_recursion(args: &Args, compare: &CompareArgs) {
+    let n: i64 = 1 << compare.n;
+    let (z, ops) = (compare.z, compare.ops);
+
+    // Peak client bytes and access round trips for one run of `ops` reads
+    let measure = |handler: &mut PathORAMHandler<LocalBackend>| {
+        let setup_round_trips = handler.round_trips();
+        let mut peak_bytes = handler.client_memory_bytes();
+        for i in 0..ops {
+            handler
+                .read(i64::from(i) % n)
+                .expect("in-process read failed");
+            peak_bytes = peak_bytes.max(handler.client_memory_bytes());
+        }
+        (peak_bytes, handler.round_trips() - setup_round_trips)
+    };
+
+    let mut flat = PathORAMHandler::new(LocalBackend::new(), z, compare.seed);
+    flat.setup((0..n).collect())
+        .expect("in-process setup failed");
+    let mut recursive = PathORAMHandler::new(LocalBackend::new(), z, compare.seed);
+    recursive
+        .setup_recursive(
+            (0..n).collect(),
+            compare.fanout,
+            compare.cutoff,
+            &mut LocalBackend::new,
+        )
+        .expect("in-process setup failed");
+
+    println!(
+        "{:<10} {:>16} {:>16} {:>14}",
+        "pmap", "peak client B", "round trips", "trips / op"
+    );
+    for (name, handler) in [("flat", &mut flat), ("recursive", &mut recursive)] {
+        let (peak_bytes, round_trips) = measure(handler);
+        println!(
+            "{:<10} {:>16} {:>16} {:>14.2}",
+            name,
+            peak_bytes,
+            round_trips,
+            round_trips as f64 / ops.max(1) as f64
+        );
+    }
+    if !compare.server {
+        return;
+    }
+
+    let rt = match Runtime::new() {
+        Ok(rt) => rt,
+        Err(e) => {
+            eprintln!("Failed to start the async runtime: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let client = connect(&rt, args).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+    let mut make_backend = |namespace: String| {
+        let mut backend = GrpcBackend::new(client.clone(), rt.handle().clone());
+        backend.set_namespace(namespace);
+        backend.set_retry(
+            args.max_retries,
+            Duration::from_millis(args.retry_backoff_ms),
+        );
+        backend.set_force_setup(args.force_setup);
+        backend.set_packed(!args.per_block_wire);
+        backend.set_checksums(args.checksums);
+        backend
+    };
+    let timings = match compare_pipelining(compare, &args.namespace, &mut make_backend) {
+        Ok(timings) => timings,
+        Err(e) => {
+            eprintln!("Server comparison failed: {}", e);
+            std::process::exit(1);
+        }
+    };
+    println!();
+    println!("{:<10} {:>16} {:>14}", "pipeline", "us / op", "trips / op");
+    for (name, (micros, round_trips)) in ["on", "off"].into_iter().zip(timings) {
+        println!("{:<10} {:>16.1} {:>14.2}", name, micros, round_trips);
+    }
+}
+
+// Sets up a recursive handler of 2^n addresses in `<base>/pipelined` and another in
+// `<base>/sequential`, each position-map level in a namespace of its own from
+// `make_backend`, the first overlapping position-map write-backs with the next level's
+// read and the second waiting for them. Returns each one's mean microseconds and round
+// trips per read over `ops` sequential reads.
+fn compare_pipelining(
+    compare: &CompareArgs,
+    base: &str,
+    make_backend: &mut dyn FnMut(String) -> GrpcBackend,
+) -> Result<[(f64, f64); 2], OramError> {
+    let n: i64 = 1 << compare.n;
+    let mut timings = [(0.0, 0.0); 2];
+    for (timing, (name, pipelined)) in timings
+        .iter_mut()
+        .zip([("pipelined", true), ("sequential", false)])
+    {
+        let namespace = format!("{}/{}", base, name);
+        let backend = make_backend(namespace.clone());
+        let mut handler = PathORAMHandler::new(backend, compare.z, compare.seed);
+        handler.set_pipeline_posmap(pipelined);
+        let mut level = 0;
+        handler.setup_recursive(
+            (0..n).collect(),
+            compare.fanout,
+            compare.cutoff,
+            &mut || {
+                level += 1;
+                make_backend(format!("{}/posmap{}", namespace, level))
+            },
+        )?;
+
+        let round_trips = handler.round_trips();
+        let start = Instant::now();
+        for i in 0..compare.ops {
+            handler.read(i64::from(i) % n)?;
+        }
+        let ops = f64::from(compare.ops.max(1));
+        *timing = (
+            start.elapsed().as_secs_f64() * 1e6 / ops,
+            (handler.round_trips() - round_trips) as f64 / ops,
+        );
+    }
+    Ok(timings)
+}
+
+// One `bench` run: the CSV row for a tree of N addresses, Z and B.
+struct BenchRow {
+    n: i64,
+    z: i32,
+    b: i32,
+    setup_secs: f64,
+    avg_access_us: f64,
+    peak_stash: usize,
+    rpcs_per_access: f64,
+    blocks_per_access: f64,
+}
+
+// Sets up `handler` with N = `n` addresses of `b` bytes, then times `ops` sequential reads
+// after `warmup` untimed ones. The stash peak and the RPC and block counts only cover the
+// timed reads.
+fn bench_run<B: OramBackend>(
+    mut handler: PathORAMHandler<B>,
+    n: i64,
+    b: i32,
+    bench: &BenchArgs,
+) -> Result<BenchRow, OramError> {
+    handler.set_block_size(b);
+    let start = Instant::now();
+    if bench.bulk_setup {
+        handler.setup_bulk((0..n).collect())?;
+    } else {
+        handler.setup((0..n).collect())?;
+    }
+    let setup_secs = start.elapsed().as_secs_f64();
+
+    let addresses = (0..).map(|i: i64| i % n);
+    for a in addresses.clone().take(bench.warmup as usize) {
+        handler.read(a)?;
+    }
+    handler.reset_metrics();
+    handler.reset_stash_stats();
+    let start = Instant::now();
+    for a in addresses
+        .skip(bench.warmup as usize)
+        .take(bench.ops as usize)
+    {
+        handler.read(a)?;
+    }
+    let elapsed = start.elapsed();
+
+    let ops = bench.ops.max(1) as f64;
+    let metrics = handler.metrics();
+    Ok(BenchRow {
+        n,
+        z: handler.z,
+        b,
+        setup_secs,
+        avg_access_us: elapsed.as_secs_f64() * 1e6 / ops,
+        peak_stash: handler.peak_stash(),
+        rpcs_per_access: handler.round_trips() as f64 / ops,
+        blocks_per_access: (metrics.blocks_sent + metrics.blocks_received) as f64 / ops,
+    })
+}
+
+// Runs `bench_run` for every combination of the swept N, Z and B, each on a fresh tree
+// (in `--namespace` on the server, or in process with `--local`). Rows are flushed as
+// they finish, so a sweep cut short keeps the runs it completed.
+fn run_bench(args: &Args, bench: &BenchArgs) {
+    let mut out = match File::create(&bench.out) {
+        Ok(file) => BufWriter::new(file),
+        Err(e) => {
+            eprintln!("Failed to create {}: {}", bench.out.display(), e);
+            std::process::exit(1);
+        }
+    };
+    let rt = match Runtime::new() {
+        Ok(rt) => rt,
+        Err(e) => {
+            eprintln!("Failed to start the async runtime: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let client = (!bench.local).then(|| {
+        connect(&rt, args).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        })
+    });
+
+    let mut write_row = |line: String| {
+        if let Err(e) = writeln!(out, "{}", line).and_then(|()| out.flush()) {
+            eprintln!("Failed to write {}: {}", bench.out.display(), e);
+            std::process::exit(1);
+        }
+    };
+    write_row("n,z,b,setup_secs,avg_access_us,peak_stash,rpcs_per_access,blocks_per_access".into());
+    let mut replace = false;
+    for &exp in &bench.n.0 {
+        for &z in &bench.z.0 {
+            for &b in &bench.b.0 {
+                if exp >= 63 {
+                    println!("Skipping n=2^{}: too many addresses", exp);
+                    continue;
+                }
+                let n = 1_i64 << exp;
+                println!("Running n={} z={} b={}", n, z, b);
+                let row = match &client {
+                    None => bench_run(
+                        PathORAMHandler::new(LocalBackend::new(), z, bench.seed),
+                        n,
+                        b,
+                        bench,
+                    ),
+                    Some(client) => {
+                        let mut backend = GrpcBackend::new(client.clone(), rt.handle().clone());
+                        backend.set_namespace(args.namespace.clone());
+                        backend.set_retry(
+                            args.max_retries,
+                            Duration::from_millis(args.retry_backoff_ms),
+                        );
+                        // Each configuration after the first replaces the previous one's tree
+                        backend.set_force_setup(args.force_setup || replace);
+                        replace = true;
+                        backend.set_packed(!args.per_block_wire);
+                        backend.set_checksums(args.checksums);
+                        let mut handler = PathORAMHandler::new(backend, z, bench.seed);
+                        if let Some(limit) = args.max_message_bytes {
+                            handler.set_max_message_bytes(limit);
+                        }
+                        bench_run(handler, n, b, bench)
+                    }
+                };
+                match row {
+                    Ok(row) => write_row(format!(
+                        "{},{},{},{:.6},{:.3},{},{:.3},{:.1}",
+                        row.n,
+                        row.z,
+                        row.b,
+                        row.setup_secs,
+                        row.avg_access_us,
+                        row.peak_stash,
+                        row.rpcs_per_access,
+                        row.blocks_per_access
+                    )),
+                    Err(e) => println!("Run n={} z={} b={} failed: {}", n, z, b, e),
+                }
+            }
+        }
+    }
+    println!("Results written to {}", bench.out.display());
+}
+
+// Builds a gnuplot script for the outputs in `run_dir`: one stash-over-time plot with a
+// line per `stash_sizes_*.txt` log, and one occupancy plot per `*occupancy*.csv` (first
+// column x, second y, one header row). A `metadata.json` beside them is referenced in
+// the header so the plots can be traced back to their parameters. Images are written
+// next to the data as `stash.png` and `<csv stem>.png`.
+fn plot_script(run_dir: &Path) -> io::Result<String> {
+    let mut stash_logs = Vec::new();
+    let mut occupancy = Vec::new();
+    for entry in std::fs::read_dir(run_dir)? {
+        let path = entry?.path();
+        let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+            continue;
+        };
+        if name.starts_with("stash_sizes_") && name.ends_with(".txt") {
+            stash_logs.push(path);
+        } else if name.contains("occupancy") && name.ends_with(".csv") {
+            occupancy.push(path);
+        }
+    }
+    stash_logs.sort();
+    occupancy.sort();
+    if stash_logs.is_empty() && occupancy.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "no stash_sizes_*.txt or *occupancy*.csv files",
+        ));
+    }
+
+    // gnuplot strings are single-quoted; a quote inside is written twice
+    let quote = |s: &str| format!("'{}'", s.replace('\'', "''"));
+    let quote_path = |path: &Path| quote(&path.display().to_string());
+    let mut script = String::new();
+    script.push_str(&format!("# Generated for {}\n", run_dir.display()));
+    let metadata = run_dir.join("metadata.json");
+    if metadata.exists() {
+        script.push_str(&format!("# Run parameters: {}\n", metadata.display()));
+    }
+    script.push_str("set terminal pngcairo size 1200,600\nset key outside right\n");
+
+    if !stash_logs.is_empty() {
+        script.push_str(&format!(
+            "\nset output {}\nset title 'Stash size over time'\n",
+            quote_path(&run_dir.join("stash.png"))
+        ));
+        script.push_str("set xlabel 'access'\nset ylabel 'blocks in stash'\nplot \\\n");
+        let lines: Vec<String> = stash_logs
+            .iter()
+            .map(|path| {
+                let title = path.file_stem().unwrap_or_default().to_string_lossy();
+                format!(
+                    "    {} using 0:1 with lines title {}",
+                    quote_path(path),
+                    quote(&title)
+                )
+            })
+            .collect();
+        script.push_str(&lines.join(", \\\n"));
+        script.push('\n');
+    }
+
+    for path in &occupancy {
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        script.push_str(&format!(
+            "\nset output {}\nset title {}\nset datafile separator ','\n",
+            quote_path(&run_dir.join(format!("{}.png", stem))),
+            quote(&stem)
+        ));
+        script.push_str(&format!(
+            "plot {} every ::1 using 1:2 with linespoints notitle\nset datafile separator whitespace\n",
+            quote_path(path)
+        ));
+    }
+    Ok(script)
+}
+
+// The golden workload: N = 2^8, Z = 2 (small enough that the stash actually grows), and
+// alternating reads and writes to seeded random addresses.
+const GOLDEN_EXP: i32 = 8;
+const GOLDEN_Z: i32 = 2;
+const GOLDEN_OPS: i32 = 5000;
+const GOLDEN_SEED: u64 = 11;
+
+fn golden_stash_sizes() -> Vec<usize> {
+    // Addresses are drawn as i32, as when the trace was recorded
+    let n: i32 = 1 << GOLDEN_EXP;
+    let mut handler = PathORAMHandler::new(LocalBackend::new(), GOLDEN_Z, GOLDEN_SEED);
+    handler
+        .setup((0..i64::from(n)).collect())
+        .expect("in-process setup failed");
+
+    let mut rng = StdRng::seed_from_u64(GOLDEN_SEED);
+    let mut sizes = Vec::with_capacity(GOLDEN_OPS as usize);
+    for i in 0..GOLDEN_OPS {
+        let a = i64::from(rng.gen_range(0..n));
+        if i % 2 == 0 {
+            handler.read(a).expect("in-process read failed");
+        } else {
+            handler.write(a, i.into()).expect("in-process write failed");
+        }
+        sizes.push(handler.stash_size());
+    }
+    sizes
+}
+
+// The golden stash sizes in `path`, one per line.
+fn read_golden(path: &Path) -> Result<Vec<usize>, String> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    text.lines()
+        .map(str::parse)
+        .collect::<Result<_, _>>()
+        .map_err(|e| format!("Malformed golden trace {}: {}", path.display(), e))
+}
+
+// The first op whose stash size differs between `actual` and `expected`, if any.
+fn golden_divergence(actual: &[usize], expected: &[usize]) -> Option<usize> {
+    (0..actual.len().max(expected.len())).find(|&i| actual.get(i) != expected.get(i))
+}
+
+// Eviction regression check: the stash size after every access of the golden workload
+// must match `path` exactly. Exits non-zero at the first divergence. The same comparison
+// runs under `cargo test`; this is mainly here to regenerate the trace with `update`.
+fn check_golden(path: &Path, update: bool) {
+    let actual = golden_stash_sizes();
+    if update {
+        let write = || -> io::Result<()> {
+            if let Some(dir) = path.parent() {
+                std::fs::create_dir_all(dir)?;
+            }
+            let mut file = BufWriter::new(File::create(path)?);
+            for size in &actual {
+                writeln!(file, "{}", size)?;
+            }
+            file.flush()
+        };
+        if let Err(e) = write() {
+            eprintln!("Failed to write {}: {}", path.display(), e);
+            std::process::exit(1);
+        }
+        println!("Updated {} ({} ops)", path.display(), actual.len());
+        return;
+    }
+
+    let expected = match read_golden(path) {
+        Ok(sizes) => sizes,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    if let Some(op) = golden_divergence(&actual, &expected) {
+        eprintln!(
+            "Eviction regression at op {}: stash size {:?}, golden {:?}",
+            op,
+            actual.get(op),
+            expected.get(op)
+        );
+        eprintln!("If the change is intentional, rerun with --update-golden");
+        std::process::exit(1);
+    }
+    println!(
+        "Stash sizes match {} ({} ops)",
+        path.display(),
+        actual.len()
+    );
+}
+
+const SPARK_LEVELS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+// Reads a stash log and prints a sparkline of the size over time (each column is the max
+// of its share of the run), a histogram of sizes, and summary stats. Histogram files
+// (`size count` per line) have no time axis, so only the histogram and stats are shown.
+fn plot_stash(path: &Path, width: usize) -> io::Result<()> {
+    let mut series = Vec::new();
+    let mut histogram: BTreeMap<u64, u64> = Default::default();
+    let mut is_histogram = false;
+    for (line_no, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let line = line?;
+        let fields: Vec<u64> = line
+            .split_whitespace()
+            .map(|f| f.parse())
+            .collect::<Result<_, _>>()
+            .map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("line {}: expected numbers", line_no + 1),
+                )
+            })?;
+        match fields[..] {
+            [] => {}
+            [size] => {
+                series.push(size);
+                *histogram.entry(size).or_default() += 1;
+            }
+            [size, count] => {
+                is_histogram = true;
+                *histogram.entry(size).or_default() += count;
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("line {}: too many fields", line_no + 1),
+                ))
+            }
+        }
+    }
+
+    let count: u64 = histogram.values().sum();
+    if count == 0 {
+        println!("{} is empty", path.display());
+        return Ok(());
+    }
+    let max = *histogram.keys().next_back().unwrap_or(&0);
+
+    if !is_histogram && width > 0 {
+        let chunk = series.len().div_ceil(width);
+        let line: String = series
+            .chunks(chunk)
+            .map(|c| {
+                let peak = c.iter().copied().max().unwrap_or(0);
+                let level = (peak * (SPARK_LEVELS.len() as u64 - 1))
+                    .checked_div(max)
+                    .unwrap_or(0) as usize;
+                SPARK_LEVELS[level]
+            })
+            .collect();
+        println!(
+            "stash size over time (max per column, {} ops/column):",
+            chunk
+        );
+        println!("{}\n", line);
+    }
+
+    let peak_count = histogram.values().copied().max().unwrap_or(1);
+    println!("stash size histogram:");
+    for (size, n) in &histogram {
+        let bar = "#".repeat(((n * 50).div_ceil(peak_count)) as usize);
+        println!("{:>6} | {:<50} {}", size, bar, n);
+    }
+
+    let total: u64 = histogram.iter().map(|(size, n)| size * n).sum();
+    let min = *histogram.keys().next().unwrap_or(&0);
+    println!(
+        "\nops: {}, min: {}, max: {}, mean: {:.3}",
+        count,
+        min,
+        max,
+        total as f64 / count as f64
+    );
+    Ok(())
+}
+
+fn main() {
+    let args = Args::parse();
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::new(&args.log_level))
+        .with_writer(io::stderr)
+        .init();
+
+    match args.command {
+        Some(Command::CompareRecursion(ref compare)) => compare_recursion(&args, compare),
+        Some(Command::Bench(ref bench)) => run_bench(&args, bench),
+        Some(Command::Repl { ref state }) => run_repl(&args, state.as_deref()),
+        Some(Command::CheckGolden {
+            path,
+            update_golden,
+        }) => check_golden(&path, update_golden),
+        Some(Command::GenPlotScript { run_dir, out }) => {
+            let script = match plot_script(&run_dir) {
+                Ok(script) => script,
+                Err(e) => {
+                    eprintln!("Failed to scan {}: {}", run_dir.display(), e);
+                    std::process::exit(1);
+                }
+            };
+            match out {
+                Some(path) => {
+                    if let Err(e) = std::fs::write(&path, script) {
+                        eprintln!("Failed to write {}: {}", path.display(), e);
+                        std::process::exit(1);
+                    }
+                }
+                None => print!("{}", script),
+            }
+        }
+        Some(Command::PlotStash { path, width }) => {
+            if let Err(e) = plot_stash(&path, width) {
+                eprintln!("Failed to plot {}: {}", path.display(), e);
+                std::process::exit(1);
+            }
+        }
+        None => run_client(&args),
+    }
+}
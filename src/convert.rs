@@ -0,0 +1,65 @@
+// Checked replacements for the `as` casts used in tree and address math. Each helper
+// returns a `ConvertError` where the bare cast would wrap, truncate or go negative.
+use std::error::Error;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConvertError {
+    what: &'static str,
+    value: i128, // The value that failed, wide enough for any i32, i64 or usize
+}
+
+impl ConvertError {
+    fn new(what: &'static str, value: impl Into<i128>) -> Self {
+        ConvertError {
+            what,
+            value: value.into(),
+        }
+    }
+}
+
+impl fmt::Display for ConvertError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} is out of range", self.what, self.value)
+    }
+}
+
+impl Error for ConvertError {}
+
+// An address, leaf or bucket index as a slice index.
+pub fn idx(value: i64) -> Result<usize, ConvertError> {
+    usize::try_from(value).map_err(|_| ConvertError::new("index", value))
+}
+
+// A length or count back to the i64 used for addresses.
+pub fn to_i64(value: usize) -> Result<i64, ConvertError> {
+    // usize is at most 64 bits wide, so the u64 holds it exactly
+    i64::try_from(value).map_err(|_| ConvertError::new("count", value as u64))
+}
+
+// Number of leaves in a tree of height `l`, i.e. 2^l.
+pub fn leaf_count(l: i32) -> Result<i64, ConvertError> {
+    u32::try_from(l)
+        .ok()
+        .and_then(|l| 1_i64.checked_shl(l))
+        .filter(|&count| count > 0)
+        .ok_or_else(|| ConvertError::new("tree height", l))
+}
+
+// Height of the smallest tree with at least `n` leaves, i.e. ceil(log2(n)).
+pub fn tree_height(n: i64) -> Result<i32, ConvertError> {
+    if n < 1 {
+        return Err(ConvertError::new("address count", n));
+    }
+    Ok((n as u64).next_power_of_two().trailing_zeros() as i32)
+}
+
+// Number of buckets in a tree with `num_layers` levels, i.e. 2^num_layers - 1.
+pub fn bucket_count(num_layers: i32) -> Result<usize, ConvertError> {
+    u32::try_from(num_layers)
+        .ok()
+        .and_then(|layers| 1_usize.checked_shl(layers))
+        .filter(|&count| count > 0)
+        .map(|count| count - 1)
+        .ok_or_else(|| ConvertError::new("layer count", num_layers))
+}
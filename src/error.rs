@@ -0,0 +1,49 @@
+// Errors returned by the client's ORAM operations.
+use std::io;
+use thiserror::Error;
+use tonic::Status;
+
+#[derive(Debug, Error)]
+pub enum OramError {
+    // The server (or backend) answered with an error status
+    #[error("RPC failed with {:?}: {}", .0.code(), .0.message())]
+    Rpc(#[from] Status),
+    // The connection itself failed
+    #[error("transport error: {0}")]
+    Transport(#[from] tonic::transport::Error),
+    // An address outside 0..n
+    #[error("address {a} is out of range for {n} addresses")]
+    AddressOutOfRange { a: i64, n: i64 },
+    // A write-back left more than `max` blocks in the stash; `addresses` is what it holds
+    #[error("stash holds {} blocks, over the limit of {max}: {addresses:?}", .addresses.len())]
+    StashOverflow { max: usize, addresses: Vec<i64> },
+    // A write, delete or setup through a handler made read-only with `set_read_only`
+    #[error("handler is read-only; values can't be changed")]
+    ReadOnly,
+    // `set_levels` asked for a tree with fewer than N leaves
+    #[error("tree height {levels} is too short for {n} addresses; it needs at least {needed}")]
+    TreeTooShort { levels: i32, needed: i32, n: i64 },
+    // An access before `setup` or `load_checkpoint`, or attaching to a server whose tree
+    // was never set up
+    #[error("no ORAM has been set up yet")]
+    NotSetup,
+    // An access found no block for an address that was written and never deleted, so the
+    // position map or the tree lost it. The access itself completed as usual.
+    #[error("address {a} was written but its block is on neither its path nor the stash")]
+    MissingBlock { a: i64 },
+    // A bucket read from the server didn't match the checksum it was stored with, or holds
+    // a sealed block that doesn't decrypt
+    #[error("bucket {index} failed its integrity check")]
+    Corruption { index: i64 },
+    // A value too wide for the tree's blocks, or a payload that doesn't decode as the type
+    // it was read as (see `BlockCodec`)
+    #[error("{0}")]
+    Codec(String),
+    // An `AsyncPathORAM` access failed without an error of its own: its worker stopped, or
+    // another access in its batch failed
+    #[error("{0}")]
+    Pipeline(String),
+    // Reading or writing a checkpoint, trace or stash file failed, or its contents were bad
+    #[error("{0}")]
+    Io(#[from] io::Error),
+}
@@ -1,45 +1,836 @@
-use tonic::{transport::Server, Request, Response, Status};
+// tonic::Status is the error type for every RPC helper; boxing it buys nothing here.
+#![allow(clippy::result_large_err)]
 
-use clap::Parser;
-use path_oram::path_oram_server::{PathOram, PathOramServer};
+use tonic::{Request, Response, Status, Streaming};
+
+use path_oram::path_oram_server::PathOram;
 use path_oram::Block;
 use path_oram::{
-    PrintRequest, PrintResponse, ReadBlockRequest, ReadBlockResponse, SetupRequest, SetupResponse,
-    WriteBlockRequest, WriteBlockResponse,
+    BucketChunk, ClearRequest, ClearResponse, FlushRequest, FlushResponse, GetBucketRequest,
+    GetBucketResponse, GetConfigRequest, GetConfigResponse, PingRequest, PingResponse,
+    PrintRequest, PrintResponse, ReadBlockRequest, ReadBlockResponse, SetupBulkResponse,
+    SetupRequest, SetupResponse, StatsRequest, StatsResponse, WriteBlockRequest,
+    WriteBlockResponse,
 };
 use std::cmp;
-use std::sync::RwLock;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, oneshot};
+use tracing::info;
 
 pub mod path_oram {
     tonic::include_proto!("path_oram"); // The string specified here must match the proto package name
 }
 
+// Segments each tree is split into unless `--shards` says otherwise.
+const DEFAULT_SHARDS: usize = 16;
+
+// Fixed allocation backing the tree, split into shards that are locked independently so
+// writes to different buckets don't wait for each other. Bucket i is the (i / K)th bucket
+// of shard i % K, for K shards; a shard keeps its buckets in one contiguous Vec, and
+// `reset` refills them in place when the geometry is unchanged, so back-to-back setups
+// don't go back to the allocator. Each bucket's checksum (`bucket_crc32`) is kept beside
+// it and replaced with it, under the same lock.
+//
+// Consistency: `read_buckets` and `write_buckets` lock one bucket's shard at a time, so
+// each bucket is read or replaced atomically, but a write-back spanning several shards is
+// not atomic as a whole. A read of an overlapping path running at the same time may see
+// some of its buckets from before the write and some from after. A client never does that
+// to itself (it waits for each write-back before reading the next path), and everything
+// that needs the whole tree at once (setup, clear, print, snapshots) holds the tree's
+// lock exclusively, so it only sees whole write-backs. A write is still applied in one
+// synchronous pass, so a dropped connection can't cut it short: it lands in full or not
+// at all.
+#[derive(Debug)]
+pub struct Arena {
+    shards: Vec<RwLock<Shard>>,
+    num_buckets: usize,
+    bucket_size: usize,
+    block_size: usize, // Payload bytes per block
+}
+
+// One shard's buckets, back to back, and their checksums in the same order.
 #[derive(Debug, Default)]
+struct Shard {
+    blocks: Vec<Block>,
+    crcs: Vec<u32>,
+}
+
+impl Default for Arena {
+    fn default() -> Self {
+        Arena::new(DEFAULT_SHARDS)
+    }
+}
+
+// A dummy block: index -1 and an all-zero payload of the tree's width. Only the index
+// marks it empty; the server never looks at payloads.
+fn empty_block(block_size: usize) -> Block {
+    Block {
+        index: -1,
+        data: vec![0; block_size],
+    }
+}
+
+// CRC-32 of `bucket` laid out as in a `packed` field (see the proto).
+fn bucket_crc32(bucket: &[Block]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    for block in bucket {
+        hasher.update(&block.index.to_le_bytes());
+        hasher.update(&block.data);
+    }
+    hasher.finalize()
+}
+
+// Nothing panics while a shard is locked, and every slot is assigned whole, so a poisoned
+// shard still holds whole buckets and is used as is.
+fn read_shard(shard: &RwLock<Shard>) -> RwLockReadGuard<'_, Shard> {
+    shard.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn write_shard(shard: &RwLock<Shard>) -> RwLockWriteGuard<'_, Shard> {
+    shard.write().unwrap_or_else(PoisonError::into_inner)
+}
+
+impl Arena {
+    // An empty tree split into `shards` segments (at least one).
+    pub fn new(shards: usize) -> Self {
+        Arena {
+            shards: (0..shards.max(1)).map(|_| RwLock::default()).collect(),
+            num_buckets: 0,
+            bucket_size: 0,
+            block_size: 0,
+        }
+    }
+
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    // The shard holding bucket `index`, and which of its buckets it is.
+    fn locate(&self, index: usize) -> (usize, usize) {
+        let shards = self.shards.len();
+        (index % shards, index / shards)
+    }
+
+    // Empties every bucket and resizes to the given geometry, keeping the allocation
+    // whenever it's already large enough. Callers bound `num_buckets * bucket_size` first
+    // (setup by --max-blocks).
+    pub fn reset(&mut self, num_buckets: usize, bucket_size: usize, block_size: usize) {
+        let shards = self.shards.len();
+        let empty_crc = bucket_crc32(&vec![empty_block(block_size); bucket_size]);
+        for (s, shard) in self.shards.iter_mut().enumerate() {
+            let shard = shard.get_mut().unwrap_or_else(PoisonError::into_inner);
+            let buckets = num_buckets.saturating_sub(s).div_ceil(shards);
+            let total = buckets
+                .checked_mul(bucket_size)
+                .expect("tree size overflows usize");
+            if shard.blocks.len() == total && self.block_size == block_size {
+                for block in shard.blocks.iter_mut() {
+                    block.index = -1;
+                    block.data.fill(0);
+                }
+            } else {
+                shard.blocks.clear();
+                shard.blocks.resize(total, empty_block(block_size));
+            }
+            shard.crcs.clear();
+            shard.crcs.resize(buckets, empty_crc);
+        }
+        self.num_buckets = num_buckets;
+        self.bucket_size = bucket_size;
+        self.block_size = block_size;
+    }
+
+    // Appends a bucket; used when rebuilding from a snapshot.
+    fn push_bucket(&mut self, bucket: &[Block]) {
+        debug_assert_eq!(bucket.len(), self.bucket_size);
+        let (shard, _) = self.locate(self.num_buckets);
+        let shard = self.shards[shard]
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner);
+        shard.blocks.extend_from_slice(bucket);
+        shard.crcs.push(bucket_crc32(bucket));
+        self.num_buckets += 1;
+    }
+
+    pub fn len(&self) -> usize {
+        self.num_buckets
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.num_buckets == 0
+    }
+
+    pub fn bucket_size(&self) -> usize {
+        self.bucket_size
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    // Rough bytes the tree holds: each block's struct and payload, plus each bucket's
+    // checksum. Worked out from the geometry, so no shard needs locking.
+    pub fn estimated_bytes(&self) -> usize {
+        let block = std::mem::size_of::<Block>() + self.block_size;
+        self.num_buckets * (self.bucket_size * block + std::mem::size_of::<u32>())
+    }
+
+    // A copy of bucket `index`.
+    pub fn get(&self, index: usize) -> Option<Vec<Block>> {
+        if index >= self.num_buckets {
+            return None;
+        }
+        let (shard, nth) = self.locate(index);
+        let start = nth * self.bucket_size;
+        Some(read_shard(&self.shards[shard]).blocks[start..start + self.bucket_size].to_vec())
+    }
+
+    // The buckets at `positions`, in order. Every position must be in the tree.
+    pub fn read_buckets(&self, positions: &[usize]) -> Vec<Block> {
+        self.read_buckets_checked(positions).0
+    }
+
+    // `read_buckets`, along with each bucket's checksum as of the same moment.
+    pub fn read_buckets_checked(&self, positions: &[usize]) -> (Vec<Block>, Vec<u32>) {
+        let mut blocks = Vec::with_capacity(positions.len() * self.bucket_size);
+        let mut crcs = Vec::with_capacity(positions.len());
+        for &position in positions {
+            let (shard, nth) = self.locate(position);
+            let start = nth * self.bucket_size;
+            let shard = read_shard(&self.shards[shard]);
+            blocks.extend_from_slice(&shard.blocks[start..start + self.bucket_size]);
+            crcs.push(shard.crcs[nth]);
+        }
+        (blocks, crcs)
+    }
+
+    // Overwrites the buckets at `positions` with consecutive bucket-sized runs of
+    // `blocks`. Every position must be in the tree.
+    pub fn write_buckets(&self, positions: &[usize], blocks: Vec<Block>) {
+        let mut blocks = blocks.into_iter();
+        for &position in positions {
+            let bucket: Vec<Block> = blocks.by_ref().take(self.bucket_size).collect();
+            let crc = bucket_crc32(&bucket);
+            let (shard, nth) = self.locate(position);
+            let start = nth * self.bucket_size;
+            let mut shard = write_shard(&self.shards[shard]);
+            for (slot, block) in shard.blocks[start..start + self.bucket_size]
+                .iter_mut()
+                .zip(bucket)
+            {
+                *slot = block;
+            }
+            shard.crcs[nth] = crc;
+        }
+    }
+
+    // Every block, bucket by bucket.
+    pub fn blocks(&self) -> Vec<Block> {
+        let positions: Vec<usize> = (0..self.num_buckets).collect();
+        self.read_buckets(&positions)
+    }
+}
+
+// A write_block request waiting in the coalescing queue, with where to send its result.
+struct PendingWrite {
+    indices: Vec<i64>,
+    blocks: Vec<Block>,
+    done: oneshot::Sender<Result<(), Status>>,
+}
+
+// Funnels write_block requests to a background task that applies up to `max_batch` of
+// them under a single acquisition of the tree lock. A batch starts with the first queued write
+// and takes whatever else arrives within `window`. Each caller is answered only after its
+// batch has been applied, so a client never reads back a tree missing its own write.
+// Meant for the trusted single-client benchmark setup, where lock churn dominates.
+#[derive(Debug)]
+pub struct WriteCoalescer {
+    queue: mpsc::Sender<PendingWrite>,
+}
+
+impl WriteCoalescer {
+    // Spawns the batching task; must be called from inside a tokio runtime.
+    pub fn spawn(data_store: Arc<RwLock<Arena>>, max_batch: usize, window: Duration) -> Self {
+        let max_batch = max_batch.max(1);
+        let (queue, mut pending) = mpsc::channel::<PendingWrite>(max_batch * 4);
+        tokio::spawn(async move {
+            let mut batch = Vec::with_capacity(max_batch);
+            while let Some(first) = pending.recv().await {
+                batch.push(first);
+                let deadline = tokio::time::Instant::now() + window;
+                while batch.len() < max_batch {
+                    match tokio::time::timeout_at(deadline, pending.recv()).await {
+                        Ok(Some(write)) => batch.push(write),
+                        _ => break,
+                    }
+                }
+
+                let results: Vec<Result<(), Status>> = match data_store.read() {
+                    Ok(data_store) => batch
+                        .iter_mut()
+                        .map(|write| {
+                            apply_write(
+                                &data_store,
+                                &write.indices,
+                                std::mem::take(&mut write.blocks),
+                            )
+                        })
+                        .collect(),
+                    Err(_) => batch
+                        .iter()
+                        .map(|_| Err(Status::internal("Lock failed")))
+                        .collect(),
+                };
+                for (write, result) in batch.drain(..).zip(results) {
+                    // The caller may have gone away; nothing to do then
+                    let _ = write.done.send(result);
+                }
+            }
+        });
+        WriteCoalescer { queue }
+    }
+
+    async fn write(&self, indices: Vec<i64>, blocks: Vec<Block>) -> Result<(), Status> {
+        let (done, result) = oneshot::channel();
+        let write = PendingWrite {
+            indices,
+            blocks,
+            done,
+        };
+        self.queue
+            .send(write)
+            .await
+            .map_err(|_| Status::unavailable("write coalescer stopped"))?;
+        result
+            .await
+            .map_err(|_| Status::unavailable("write coalescer stopped"))?
+    }
+}
+
+// One namespace's ORAM tree. Path reads and writes share its lock and synchronize on the
+// arena's shards; anything that replaces or reads the whole tree takes it exclusively.
+#[derive(Debug)]
+struct Tree {
+    data_store: Arc<RwLock<Arena>>, // Buckets of the implicit tree, Z blocks each
+    // When set, write_block goes through the batching queue instead of taking the lock
+    coalescer: Option<WriteCoalescer>,
+    // Whether the arena has a geometry yet, kept outside its lock so Ping never waits on
+    // a long-running setup or print
+    initialized: AtomicBool,
+}
+
+impl Tree {
+    // `coalescing` is (max_batch, window) for a `WriteCoalescer`, if enabled.
+    fn new(data_store: Arc<RwLock<Arena>>, coalescing: Option<(usize, Duration)>) -> Self {
+        let coalescer = coalescing.map(|(max_batch, window)| {
+            WriteCoalescer::spawn(Arc::clone(&data_store), max_batch, window)
+        });
+        let initialized = !data_store.read().expect("Lock failed").is_empty();
+        Tree {
+            data_store,
+            coalescer,
+            initialized: AtomicBool::new(initialized),
+        }
+    }
+
+    fn shared(&self) -> Result<RwLockReadGuard<'_, Arena>, Status> {
+        self.data_store
+            .read()
+            .map_err(|_| Status::internal("Lock failed"))
+    }
+
+    fn exclusive(&self) -> Result<RwLockWriteGuard<'_, Arena>, Status> {
+        self.data_store
+            .write()
+            .map_err(|_| Status::internal("Lock failed"))
+    }
+}
+
+// Default cap on one tree's blocks: 2^26, a few GiB at the default block size.
+pub const DEFAULT_MAX_BLOCKS: usize = 1 << 26;
+
+#[derive(Debug)]
 pub struct MyPathOram {
-    // Add fields here as needed to manage server state
-    data_store: RwLock<Vec<Vec<Block>>>, // 2D vector to simulate data storage with buckets and blocks
-    bucket_size: RwLock<i32>,
+    // Independent trees by namespace, each created by the first setup naming it. The
+    // default namespace "" always exists, so clients that don't name one share it.
+    trees: RwLock<HashMap<String, Arc<Tree>>>,
+    // (max_batch, window) given to every tree's write coalescer, when enabled
+    coalescing: Option<(usize, Duration)>,
+    shards: usize, // Shards per tree created from now on
+    // Whether write_block moves each bucket's real blocks ahead of its dummies
+    compact_buckets: bool,
+    // Directory Print may render trees into; printing to a file is refused without one
+    print_dir: Option<PathBuf>,
+    // Directory Flush may write snapshots into; Flush is refused without one
+    snapshot_dir: Option<PathBuf>,
+    // Most blocks (buckets times bucket size) a setup may allocate for one tree
+    max_blocks: usize,
+    started: Instant,
+}
+
+impl Default for MyPathOram {
+    fn default() -> Self {
+        MyPathOram::new(Arena::default())
+    }
 }
 
 impl MyPathOram {
-    pub fn new(num_buckets: Option<usize>, bucket_size: Option<i32>) -> Self {
-        // Initialize data_store with empty blocks (value = -1, index = -1) for each bucket
-        let num_buckets = num_buckets.unwrap_or(0);
-        let bucket_size = bucket_size.unwrap_or(0);
-
-        let empty_block = Block {
-            value: -1,
-            index: -1,
+    // A server whose default namespace holds `data_store`. Namespaces created later are
+    // split into as many shards as it is.
+    pub fn new(data_store: Arena) -> Self {
+        let shards = data_store.shard_count();
+        let default = Tree::new(Arc::new(RwLock::new(data_store)), None);
+        MyPathOram {
+            trees: RwLock::new(HashMap::from([(String::new(), Arc::new(default))])),
+            coalescing: None,
+            shards,
+            compact_buckets: false,
+            print_dir: None,
+            snapshot_dir: None,
+            max_blocks: DEFAULT_MAX_BLOCKS,
+            started: Instant::now(),
+        }
+    }
+
+    // Routes write_block through a `WriteCoalescer` (see there), one per namespace.
+    pub fn enable_write_coalescing(&mut self, max_batch: usize, window: Duration) {
+        self.coalescing = Some((max_batch, window));
+        let trees = self.trees.get_mut().expect("Lock failed");
+        for tree in trees.values_mut() {
+            *tree = Arc::new(Tree::new(Arc::clone(&tree.data_store), self.coalescing));
+        }
+    }
+
+    // Stores every written bucket with its real blocks (index != -1) first, in the order
+    // they were sent, and its dummies after them, so a reader can stop at the first dummy.
+    // Encrypted blocks all carry index -1 on the wire, so their buckets are stored exactly
+    // as sent and real blocks stay indistinguishable from dummies.
+    pub fn set_compact_buckets(&mut self, compact: bool) {
+        self.compact_buckets = compact;
+    }
+
+    // Lets Print write into `dir`, under names the client gives relative to it.
+    pub fn set_print_dir(&mut self, dir: Option<PathBuf>) {
+        self.print_dir = dir;
+    }
+
+    // Lets Flush write snapshots into `dir`, under names the client gives relative to it.
+    pub fn set_snapshot_dir(&mut self, dir: Option<PathBuf>) {
+        self.snapshot_dir = dir;
+    }
+
+    // Rejects setups of trees holding more than `max_blocks` blocks.
+    pub fn set_max_blocks(&mut self, max_blocks: usize) {
+        self.max_blocks = max_blocks;
+    }
+
+    // The tree for `namespace`, which must have been set up.
+    fn tree(&self, namespace: &str) -> Result<Arc<Tree>, Status> {
+        let trees = self
+            .trees
+            .read()
+            .map_err(|_| Status::internal("Lock failed"))?;
+        trees
+            .get(namespace)
+            .cloned()
+            .ok_or_else(|| Status::not_found(format!("Namespace `{}` not found", namespace)))
+    }
+
+    // The tree for `namespace`, creating an empty one if it doesn't exist yet.
+    fn tree_or_insert(&self, namespace: &str) -> Result<Arc<Tree>, Status> {
+        let mut trees = self
+            .trees
+            .write()
+            .map_err(|_| Status::internal("Lock failed"))?;
+        let tree = trees.entry(namespace.to_string()).or_insert_with(|| {
+            let data_store = Arc::new(RwLock::new(Arena::new(self.shards)));
+            Arc::new(Tree::new(data_store, self.coalescing))
+        });
+        Ok(Arc::clone(tree))
+    }
+
+    // Snapshots every namespace; see `save_namespaces`.
+    pub fn save_snapshot(&self, path: &Path) -> io::Result<()> {
+        let mut namespaces: Vec<String> = self
+            .trees
+            .read()
+            .map_err(|_| lock_failed())?
+            .keys()
+            .cloned()
+            .collect();
+        namespaces.sort_unstable();
+        self.save_namespaces(&namespaces, path)
+    }
+
+    // Snapshots `namespace` alone, as the Flush RPC does; restoring the file brings back
+    // just that namespace.
+    pub fn save_namespace(&self, namespace: &str, path: &Path) -> io::Result<()> {
+        self.save_namespaces(&[namespace.to_string()], path)
+    }
+
+    // Writes the trees of `namespaces` to `path` as a little-endian `count: u32`, then for
+    // each tree its name (`name_len: u32` and that many UTF-8 bytes), `num_buckets: u64,
+    // bucket_size: i32, block_size: i32`, and every block's `index: i64` and `block_size`
+    // payload bytes. The trees are cloned with all of their locks held exclusively at once,
+    // so each holds only whole write-backs and all of them date from the same moment, and
+    // written out after releasing them, so requests aren't held up by disk I/O. (A
+    // recursive client's levels only agree with each other when none of its accesses is
+    // half done, as after shutdown drains the server.) The file is written beside `path`
+    // and renamed over it, so a crash mid-write leaves the previous snapshot intact.
+    fn save_namespaces(&self, namespaces: &[String], path: &Path) -> io::Result<()> {
+        let trees = namespaces
+            .iter()
+            .map(|namespace| {
+                self.tree(namespace)
+                    .map_err(|e| io::Error::new(io::ErrorKind::NotFound, e.message()))
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        let snapshots: Vec<_> = {
+            // Always taken in namespace order, so two snapshots can't deadlock
+            let data_stores = trees
+                .iter()
+                .map(|tree| tree.data_store.write().map_err(|_| lock_failed()))
+                .collect::<io::Result<Vec<_>>>()?;
+            data_stores
+                .iter()
+                .map(|data_store| {
+                    (
+                        data_store.blocks(),
+                        data_store.len(),
+                        data_store.bucket_size(),
+                        data_store.block_size(),
+                    )
+                })
+                .collect()
         };
-        let data_store = vec![vec![empty_block; bucket_size as usize]; num_buckets];
 
-        MyPathOram {
-            data_store: RwLock::new(data_store),
-            bucket_size: RwLock::new(bucket_size),
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+
+        let mut out = BufWriter::new(File::create(&tmp_path)?);
+        out.write_all(&(namespaces.len() as u32).to_le_bytes())?;
+        for (namespace, (blocks, num_buckets, bucket_size, block_size)) in
+            namespaces.iter().zip(&snapshots)
+        {
+            out.write_all(&(namespace.len() as u32).to_le_bytes())?;
+            out.write_all(namespace.as_bytes())?;
+            out.write_all(&(*num_buckets as u64).to_le_bytes())?;
+            out.write_all(&(*bucket_size as i32).to_le_bytes())?;
+            out.write_all(&(*block_size as i32).to_le_bytes())?;
+            for block in blocks {
+                out.write_all(&block.index.to_le_bytes())?;
+                out.write_all(&block.data)?;
+            }
+        }
+        out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        std::fs::rename(&tmp_path, path)
+    }
+
+    // Restores every namespace written by `save_snapshot` (or `save_namespace`), each tree
+    // split into `shards` segments. The file doesn't depend on how the trees were sharded.
+    // It holds no checksums; each bucket's is recomputed from the blocks loaded. A default
+    // namespace the file leaves out starts empty. Each tree's header is checked before
+    // anything is allocated for it (see `read_tree`), and a name that repeats, isn't UTF-8,
+    // or runs past the end of the file fails with `InvalidData`, as do bytes left over
+    // after the last tree.
+    pub fn load_snapshot(path: &Path, shards: usize, max_blocks: usize) -> io::Result<Self> {
+        let mut input = SnapshotReader::open(path)?;
+        let count = input.u32()?;
+        let mut arenas = HashMap::new();
+        for _ in 0..count {
+            let name_len = input.u32()?;
+            let name = input.bytes(name_len.into())?;
+            let namespace = String::from_utf8(name)
+                .map_err(|_| invalid_snapshot("namespace name in snapshot isn't UTF-8".into()))?;
+            let data_store = read_tree(&mut input, shards, max_blocks)?;
+            if arenas.insert(namespace.clone(), data_store).is_some() {
+                return Err(invalid_snapshot(format!(
+                    "snapshot holds the {}tree twice",
+                    namespace_label(&namespace)
+                )));
+            }
+        }
+        if input.remaining > 0 {
+            return Err(invalid_snapshot(format!(
+                "snapshot has {} bytes past its last tree",
+                input.remaining
+            )));
+        }
+
+        let default = arenas.remove("").unwrap_or_else(|| Arena::new(shards));
+        let mut path_oram = MyPathOram::new(default);
+        let trees = path_oram.trees.get_mut().expect("Lock failed");
+        for (namespace, data_store) in arenas {
+            let tree = Tree::new(Arc::new(RwLock::new(data_store)), None);
+            trees.insert(namespace, Arc::new(tree));
+        }
+        Ok(path_oram)
+    }
+}
+
+#[cfg(test)]
+impl MyPathOram {
+    // Lets `damage` change bucket `index` of `namespace` in place, leaving its stored
+    // checksum as it was, the way a bad disk or a faulty server would.
+    pub fn tamper(&self, namespace: &str, index: usize, damage: impl FnOnce(&mut [Block])) {
+        let tree = self.tree(namespace).expect("namespace is set up");
+        let data_store = tree.shared().expect("Lock failed");
+        let (shard, nth) = data_store.locate(index);
+        let start = nth * data_store.bucket_size;
+        let mut shard = write_shard(&data_store.shards[shard]);
+        damage(&mut shard.blocks[start..start + data_store.bucket_size]);
+    }
+}
+
+// A snapshot file being read, with how many of its bytes are still unread, so a header
+// can be checked against what the file actually holds before it's trusted.
+struct SnapshotReader {
+    input: BufReader<File>,
+    remaining: u64,
+}
+
+impl SnapshotReader {
+    fn open(path: &Path) -> io::Result<Self> {
+        let file = File::open(path)?;
+        let remaining = file.metadata()?.len();
+        Ok(SnapshotReader {
+            input: BufReader::new(file),
+            remaining,
+        })
+    }
+
+    // The next `len` bytes, refusing to allocate more than the file has left.
+    fn bytes(&mut self, len: u64) -> io::Result<Vec<u8>> {
+        if len > self.remaining {
+            return Err(invalid_snapshot(format!(
+                "snapshot ends {} bytes short",
+                len - self.remaining
+            )));
         }
+        let mut bytes = vec![0; len as usize];
+        self.input.read_exact(&mut bytes)?;
+        self.remaining -= len;
+        Ok(bytes)
+    }
+
+    fn word<const N: usize>(&mut self) -> io::Result<[u8; N]> {
+        let bytes = self.bytes(N as u64)?;
+        Ok(bytes.try_into().expect("read N bytes"))
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        self.word().map(u32::from_le_bytes)
+    }
+
+    fn i32(&mut self) -> io::Result<i32> {
+        self.word().map(i32::from_le_bytes)
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        self.word().map(u64::from_le_bytes)
+    }
+
+    fn i64(&mut self) -> io::Result<i64> {
+        self.word().map(i64::from_le_bytes)
     }
 }
 
+fn invalid_snapshot(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+// Reads one tree of a snapshot (see `save_namespaces`) into an arena of `shards`
+// segments. The header is checked before anything is allocated: the tree must have
+// 2^k - 1 buckets of at least one block (none at all for a namespace that was never set
+// up), no more than `max_blocks` blocks, and no more blocks than the rest of the file
+// holds. Anything else fails with `InvalidData`.
+fn read_tree(input: &mut SnapshotReader, shards: usize, max_blocks: usize) -> io::Result<Arena> {
+    let num_buckets = input.u64()?;
+    let bucket_size = input.i32()?;
+    let block_size = input.i32()?;
+    if bucket_size < 0 || block_size < 0 {
+        return Err(invalid_snapshot(
+            "negative bucket or block size in snapshot".into(),
+        ));
+    }
+    if num_buckets > 0 {
+        if bucket_size == 0 {
+            return Err(invalid_snapshot("zero bucket size in snapshot".into()));
+        }
+        if !num_buckets.checked_add(1).is_some_and(u64::is_power_of_two) {
+            return Err(invalid_snapshot(format!(
+                "snapshot holds {} buckets, not 2^k - 1 for any tree height",
+                num_buckets
+            )));
+        }
+    }
+    let blocks = num_buckets
+        .checked_mul(bucket_size as u64)
+        .filter(|&blocks| blocks <= max_blocks as u64)
+        .ok_or_else(|| {
+            invalid_snapshot(format!(
+                "snapshot's {} buckets of {} blocks exceed the limit of {} blocks",
+                num_buckets, bucket_size, max_blocks
+            ))
+        })?;
+    let stride = packed_stride(block_size as usize) as u64;
+    if blocks
+        .checked_mul(stride)
+        .is_none_or(|len| len > input.remaining)
+    {
+        return Err(invalid_snapshot(format!(
+            "snapshot has {} bytes left, too few for {} blocks of {} bytes",
+            input.remaining, blocks, block_size
+        )));
+    }
+
+    let mut data_store = Arena::new(shards);
+    data_store.reset(0, bucket_size as usize, block_size as usize);
+    let mut bucket = Vec::with_capacity(bucket_size as usize);
+    for _ in 0..num_buckets {
+        bucket.clear();
+        for _ in 0..bucket_size {
+            let index = input.i64()?;
+            let data = input.bytes(block_size as u64)?;
+            bucket.push(Block { index, data });
+        }
+        data_store.push_bucket(&bucket);
+    }
+    Ok(data_store)
+}
+
+// The file `name` names inside `dir`, the directory the server was given with `flag`.
+// Only plain relative names are accepted (no root, drive prefix or `..`), so a client
+// can't reach outside `dir`.
+fn resolve_in(dir: Option<&Path>, name: &str, flag: &str) -> Result<PathBuf, Status> {
+    let dir = dir.ok_or_else(|| {
+        Status::failed_precondition(format!(
+            "Writing files needs the server started with {}",
+            flag
+        ))
+    })?;
+    let name = Path::new(name);
+    let plain = name
+        .components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+    if !plain || name.file_name().is_none() {
+        return Err(Status::invalid_argument(format!(
+            "`{}` must be a file name relative to {}",
+            name.display(),
+            flag
+        )));
+    }
+    Ok(dir.join(name))
+}
+
+fn lock_failed() -> io::Error {
+    io::Error::other("Lock failed")
+}
+
+// Bytes of one block in a `packed` field: its index, then its payload.
+fn packed_stride(block_size: usize) -> usize {
+    8 + block_size
+}
+
+// Lays `blocks` out back to back as in a `packed` field.
+fn pack_blocks(blocks: &[Block], block_size: usize) -> Vec<u8> {
+    let mut packed = Vec::with_capacity(blocks.len() * packed_stride(block_size));
+    for block in blocks {
+        packed.extend_from_slice(&block.index.to_le_bytes());
+        packed.extend_from_slice(&block.data);
+    }
+    packed
+}
+
+// Splits a `packed` field back into blocks of `block_size` payload bytes.
+fn unpack_blocks(packed: &[u8], block_size: usize) -> Result<Vec<Block>, Status> {
+    let stride = packed_stride(block_size);
+    if !packed.len().is_multiple_of(stride) {
+        return Err(Status::invalid_argument(format!(
+            "Packed blocks take {} bytes, not a multiple of {} for {}-byte blocks",
+            packed.len(),
+            stride,
+            block_size
+        )));
+    }
+    Ok(packed
+        .chunks_exact(stride)
+        .map(|block| {
+            let (index, data) = block.split_at(8);
+            Block {
+                index: i64::from_le_bytes(index.try_into().expect("8-byte index")),
+                data: data.to_vec(),
+            }
+        })
+        .collect())
+}
+
+// Converts `indices` to bucket positions, failing on the first one outside the tree.
+fn check_indices(data_store: &Arena, indices: &[i64]) -> Result<Vec<usize>, Status> {
+    indices
+        .iter()
+        .map(|&index| {
+            usize::try_from(index)
+                .ok()
+                .filter(|&i| i < data_store.len())
+                .ok_or_else(|| {
+                    Status::invalid_argument(format!(
+                        "Index {} is outside the tree of {} buckets",
+                        index,
+                        data_store.len()
+                    ))
+                })
+        })
+        .collect()
+}
+
+// Stably moves the real blocks of each `bucket_size` run of `blocks` ahead of its dummies.
+// A trailing partial run is compacted too; apply_write rejects it either way.
+fn compact_buckets(blocks: &mut [Block], bucket_size: usize) {
+    if bucket_size == 0 {
+        return; // Not set up; apply_write says so
+    }
+    for bucket in blocks.chunks_mut(bucket_size) {
+        bucket.sort_by_key(|block| block.index == -1);
+    }
+}
+
+// Overwrites the buckets at `indices` with consecutive bucket-sized runs of `blocks`.
+// The whole request is checked first (every index in range, exactly one bucket's worth
+// of blocks per index, every payload the tree's block size), so a malformed request is
+// rejected with the tree untouched.
+fn apply_write(data_store: &Arena, indices: &[i64], blocks: Vec<Block>) -> Result<(), Status> {
+    let positions = check_indices(data_store, indices)?;
+    let bucket_size = data_store.bucket_size();
+    if blocks.len() != indices.len() * bucket_size {
+        return Err(Status::invalid_argument(format!(
+            "Got {} blocks for {} buckets of {}, expected {}",
+            blocks.len(),
+            indices.len(),
+            bucket_size,
+            indices.len() * bucket_size
+        )));
+    }
+    let block_size = data_store.block_size();
+    if let Some(block) = blocks.iter().find(|block| block.data.len() != block_size) {
+        return Err(Status::invalid_argument(format!(
+            "Block for address {} has {} bytes, expected {}",
+            block.index,
+            block.data.len(),
+            block_size
+        )));
+    }
+    data_store.write_buckets(&positions, blocks);
+    Ok(())
+}
+
 #[tonic::async_trait]
 impl PathOram for MyPathOram {
     // Setup method with write lock
@@ -48,31 +839,61 @@ impl PathOram for MyPathOram {
         request: Request<SetupRequest>,
     ) -> Result<Response<SetupResponse>, Status> {
         let setup_request = request.get_ref();
-        let num_buckets = (2_usize.pow(setup_request.num_layers as u32)) - 1;
-
-        let empty_block = Block {
-            value: -1,
-            index: -1,
-        };
-        let new_data_store =
-            vec![vec![empty_block; setup_request.bucket_size as usize]; num_buckets];
+        // At least the root, and no more levels than a bucket count can hold
+        let num_buckets = u32::try_from(setup_request.num_layers)
+            .ok()
+            .filter(|&layers| layers >= 1)
+            .and_then(|layers| 1_usize.checked_shl(layers))
+            .filter(|&count| count > 0)
+            .map(|count| count - 1)
+            .ok_or_else(|| {
+                Status::invalid_argument(format!(
+                    "Layer count must be between 1 and {}, got {}",
+                    usize::BITS - 1,
+                    setup_request.num_layers
+                ))
+            })?;
+        let block_size = usize::try_from(setup_request.block_size)
+            .map_err(|_| Status::invalid_argument("Block size must not be negative"))?;
+        // Every write is checked against this width, so it must be a real count
+        let bucket_size = usize::try_from(setup_request.bucket_size)
+            .ok()
+            .filter(|&bucket_size| bucket_size > 0)
+            .ok_or_else(|| Status::invalid_argument("Bucket size must be positive"))?;
+        // Checked before taking the lock, so an oversized tree never gets to allocate
+        num_buckets
+            .checked_mul(bucket_size)
+            .filter(|&blocks| blocks <= self.max_blocks)
+            .ok_or_else(|| {
+                Status::invalid_argument(format!(
+                    "{} buckets of {} blocks exceed the server's limit of {} blocks",
+                    num_buckets, bucket_size, self.max_blocks
+                ))
+            })?;
 
-        // Acquire a write lock to modify data_store and bucket_size
-        let mut data_store = self
-            .data_store
-            .write()
-            .map_err(|_| Status::internal("Lock failed"))?;
-        *data_store = new_data_store; // Replace the existing data_store with the new one
-
-        let mut bucket_size = self
-            .bucket_size
-            .write()
-            .map_err(|_| Status::internal("Lock failed"))?;
-        *bucket_size = setup_request.bucket_size;
+        // Acquire the tree exclusively to replace data_store
+        let tree = self.tree_or_insert(&setup_request.namespace)?;
+        let mut data_store = tree.exclusive()?;
+        // Another client's blocks may live in it, possibly with a different Z or B
+        let replacing = !data_store.is_empty();
+        if replacing && !setup_request.force {
+            return Err(Status::failed_precondition(format!(
+                "The {}tree is already set up; set force to replace it",
+                namespace_label(&setup_request.namespace)
+            )));
+        }
+        // Reuses the existing allocation when the geometry hasn't changed
+        data_store.reset(num_buckets, bucket_size, block_size);
+        tree.initialized
+            .store(!data_store.is_empty(), Ordering::Release);
 
-        println!(
-            "Initialized with L={}; Z={}",
-            setup_request.num_layers, setup_request.bucket_size
+        info!(
+            namespace = %setup_request.namespace,
+            layers = setup_request.num_layers,
+            bucket_size,
+            block_size,
+            replaced = replacing,
+            "tree set up"
         );
 
         // display_tree(&data_store);
@@ -80,29 +901,45 @@ impl PathOram for MyPathOram {
         Ok(Response::new(response))
     }
 
+    // Reads the requested buckets in order. Fails as a whole with INVALID_ARGUMENT if any
+    // index is outside the tree, answering with none of the valid ones, so a client never
+    // gets part of a path.
     async fn read_block(
         &self,
         request: Request<ReadBlockRequest>,
     ) -> Result<Response<ReadBlockResponse>, Status> {
-        let indices = &request.get_ref().indices;
+        let ReadBlockRequest {
+            indices,
+            namespace,
+            packed,
+            checksums,
+        } = request.get_ref();
 
-        // Acquire a read lock on data_store
-        let data_store = self
-            .data_store
-            .read()
-            .map_err(|_| Status::internal("Lock failed"))?;
+        // Share the tree; each bucket is copied under its shard's lock
+        let tree = self.tree(namespace)?;
+        let data_store = tree.shared()?;
 
-        // Gather blocks for each index in the list
-        let mut blocks = Vec::new();
-        for &index in indices {
-            if let Some(data_blocks) = data_store.get(index as usize) {
-                blocks.extend(data_blocks.clone()); // Collect blocks from each index
-            } else {
-                return Err(Status::not_found(format!("Index {} not found", index)));
-            }
-        }
+        // Gather blocks for each index in the list, once they are all known to be valid
+        let positions = check_indices(&data_store, indices)?;
+        let (blocks, crc32) = if *checksums {
+            data_store.read_buckets_checked(&positions)
+        } else {
+            (data_store.read_buckets(&positions), Vec::new())
+        };
 
-        let response = ReadBlockResponse { blocks };
+        let response = if *packed {
+            ReadBlockResponse {
+                blocks: Vec::new(),
+                packed: pack_blocks(&blocks, data_store.block_size()),
+                crc32,
+            }
+        } else {
+            ReadBlockResponse {
+                blocks,
+                packed: Vec::new(),
+                crc32,
+            }
+        };
 
         Ok(Response::new(response))
     }
@@ -111,74 +948,346 @@ impl PathOram for MyPathOram {
         &self,
         request: Request<WriteBlockRequest>,
     ) -> Result<Response<WriteBlockResponse>, Status> {
-        let WriteBlockRequest { indices, blocks } = request.into_inner();
-        let mut block_iter = blocks.into_iter(); // Consume `blocks` into an iterator
-
-        // Acquire a write lock on data_store
-        let mut data_store = self
-            .data_store
-            .write()
-            .map_err(|_| Status::internal("Lock failed"))?;
-        let bucket_size = *self
-            .bucket_size
-            .read()
-            .map_err(|_| Status::internal("Lock failed"))?;
+        let WriteBlockRequest {
+            indices,
+            mut blocks,
+            namespace,
+            packed,
+        } = request.into_inner();
 
-        for &index in &indices {
-            if index as usize >= data_store.len() {
-                return Err(Status::not_found(format!("Index {} not found", index)));
+        let buckets_written = indices.len() as i64;
+        let tree = self.tree(&namespace)?;
+        if !packed.is_empty() {
+            if !blocks.is_empty() {
+                return Err(Status::invalid_argument(
+                    "A write carries either blocks or packed blocks, not both",
+                ));
             }
-
-            // Write blocks to the specified index, respecting the bucket size
-            for i in 0..bucket_size as usize {
-                let entry = block_iter
-                    .next()
-                    .expect("There should always be enough blocks");
-
-                data_store[index as usize][i] = Block {
-                    value: entry.value,
-                    index: entry.index,
-                };
+            // A setup changing the block size in between is caught by apply_write
+            let block_size = tree.shared()?.block_size();
+            blocks = unpack_blocks(&packed, block_size)?;
+        }
+        if self.compact_buckets {
+            compact_buckets(&mut blocks, tree.shared()?.bucket_size());
+        }
+        match &tree.coalescer {
+            Some(coalescer) => coalescer.write(indices, blocks).await?,
+            None => {
+                // Share the tree; each bucket is replaced under its shard's lock
+                let data_store = tree.shared()?;
+                apply_write(&data_store, &indices, blocks)?;
             }
         }
 
-        let response = WriteBlockResponse { success: true };
+        let response = WriteBlockResponse {
+            success: true,
+            buckets_written,
+        };
 
         Ok(Response::new(response))
     }
 
-    // Print method with read lock
+    // Print method; takes the tree exclusively so it shows whole write-backs
     async fn print(
         &self,
-        _request: Request<PrintRequest>,
+        request: Request<PrintRequest>,
     ) -> Result<Response<PrintResponse>, Status> {
-        // Acquire a read lock on data_store
-        let data_store = self
-            .data_store
-            .read()
-            .map_err(|_| Status::internal("Lock failed"))?;
+        let PrintRequest { namespace, path } = request.into_inner();
+        let tree = self.tree(&namespace)?;
+        if path.is_empty() {
+            let data_store = tree.exclusive()?;
+
+            // Call the display_tree function to print the data structure
+            display_tree(&data_store);
+            return Ok(Response::new(PrintResponse {
+                success: true,
+                path,
+            }));
+        }
+
+        let file = resolve_in(self.print_dir.as_deref(), &path, "--print-dir")?;
+        // Render under the lock, but write the file off the async workers
+        let rendered = tree_to_string(&*tree.exclusive()?);
+        let target = file.clone();
+        tokio::task::spawn_blocking(move || std::fs::write(target, rendered))
+            .await
+            .map_err(|e| Status::internal(format!("Print task failed: {}", e)))?
+            .map_err(|e| Status::internal(format!("Failed to write {}: {}", path, e)))?;
+        info!(namespace = %namespace, file = %file.display(), "tree printed");
+
+        Ok(Response::new(PrintResponse {
+            success: true,
+            path,
+        }))
+    }
+
+    // Returns a single bucket, for poking at one node without building a path request
+    async fn get_bucket(
+        &self,
+        request: Request<GetBucketRequest>,
+    ) -> Result<Response<GetBucketResponse>, Status> {
+        let GetBucketRequest { index, namespace } = request.get_ref();
+        let index = *index;
+
+        let tree = self.tree(namespace)?;
+        let data_store = tree.shared()?;
+
+        let blocks = usize::try_from(index)
+            .ok()
+            .and_then(|i| data_store.get(i))
+            .ok_or_else(|| Status::not_found(format!("Index {} not found", index)))?;
+
+        Ok(Response::new(GetBucketResponse { index, blocks }))
+    }
 
-        // Call the display_tree function to print the data structure
-        display_tree(&data_store);
+    // Snapshots the tree to a server-side file, for checkpointing long experiments
+    async fn flush(
+        &self,
+        request: Request<FlushRequest>,
+    ) -> Result<Response<FlushResponse>, Status> {
+        let FlushRequest { path, namespace } = request.get_ref();
+        if path.is_empty() {
+            return Err(Status::invalid_argument("Flush needs a snapshot path"));
+        }
+        let path = resolve_in(self.snapshot_dir.as_deref(), path, "--snapshot-dir")?;
+        self.tree(namespace)?;
+
+        self.save_namespace(namespace, &path)
+            .map_err(|e| Status::internal(format!("Failed to write {}: {}", path.display(), e)))?;
+        info!(namespace = %namespace, path = %path.display(), "snapshot written");
+
+        Ok(Response::new(FlushResponse { success: true }))
+    }
 
-        Ok(Response::new(PrintResponse { success: true }))
+    // Empties the tree in place, for back-to-back experiments on the same geometry
+    async fn clear(
+        &self,
+        request: Request<ClearRequest>,
+    ) -> Result<Response<ClearResponse>, Status> {
+        let tree = self.tree(&request.get_ref().namespace)?;
+        let mut data_store = tree.exclusive()?;
+        let (num_buckets, bucket_size, block_size) = (
+            data_store.len(),
+            data_store.bucket_size(),
+            data_store.block_size(),
+        );
+        data_store.reset(num_buckets, bucket_size, block_size);
+
+        Ok(Response::new(ClearResponse { success: true }))
     }
+
+    // Loads a whole tree from a stream of bucket chunks (see `BucketChunk` in the proto).
+    // Chunks are checked and written into a fresh arena with the tree's geometry, which
+    // replaces the live one only once the stream has delivered its last chunk. Ending the
+    // stream early can't be told apart from a client giving up, so it changes nothing.
+    async fn setup_bulk(
+        &self,
+        request: Request<Streaming<BucketChunk>>,
+    ) -> Result<Response<SetupBulkResponse>, Status> {
+        let mut chunks = request.into_inner();
+        let first = chunks
+            .message()
+            .await?
+            .ok_or_else(|| Status::invalid_argument("SetupBulk needs at least one chunk"))?;
+        let namespace = first.namespace.clone();
+        let tree = self.tree(&namespace)?;
+
+        let (mut staging, geometry) = {
+            let data_store = tree.shared()?;
+            if data_store.is_empty() {
+                return Err(Status::failed_precondition(format!(
+                    "Set up the {}tree before loading it",
+                    namespace_label(&namespace)
+                )));
+            }
+            let geometry = (
+                data_store.len(),
+                data_store.bucket_size(),
+                data_store.block_size(),
+            );
+            (Arena::new(data_store.shard_count()), geometry)
+        };
+        let (num_buckets, bucket_size, block_size) = geometry;
+        staging.reset(num_buckets, bucket_size, block_size);
+
+        let mut buckets_written = 0;
+        let mut complete = false;
+        let mut next = Some(first);
+        while let Some(BucketChunk {
+            index,
+            blocks,
+            namespace: chunk_namespace,
+            last,
+        }) = next
+        {
+            if complete {
+                return Err(Status::invalid_argument(
+                    "SetupBulk stream continued past its last chunk",
+                ));
+            }
+            complete = last;
+            if chunk_namespace != namespace {
+                return Err(Status::invalid_argument(
+                    "Every chunk of a SetupBulk stream must name the same namespace",
+                ));
+            }
+            // A partial bucket leaves the count one short, which apply_write rejects
+            let count = blocks.len().div_ceil(bucket_size);
+            let indices: Vec<i64> = (0..count as i64).map(|k| index.saturating_add(k)).collect();
+            apply_write(&staging, &indices, blocks)?;
+            buckets_written += count as i64;
+            next = chunks.message().await?;
+        }
+        if !complete {
+            return Err(Status::aborted(
+                "SetupBulk stream ended before its last chunk; the tree is unchanged",
+            ));
+        }
+
+        let mut data_store = tree.exclusive()?;
+        if (
+            data_store.len(),
+            data_store.bucket_size(),
+            data_store.block_size(),
+        ) != geometry
+        {
+            return Err(Status::aborted("Tree was set up again during SetupBulk"));
+        }
+        *data_store = staging;
+        info!(namespace = %namespace, buckets = buckets_written, "bulk load finished");
+
+        Ok(Response::new(SetupBulkResponse {
+            success: true,
+            buckets_written,
+        }))
+    }
+
+    // Answers without touching any tree's lock, so it stays fast while the server is busy
+    async fn ping(&self, request: Request<PingRequest>) -> Result<Response<PingResponse>, Status> {
+        let initialized = match self.tree(&request.get_ref().namespace) {
+            Ok(tree) => tree.initialized.load(Ordering::Acquire),
+            Err(status) if status.code() == tonic::Code::NotFound => false,
+            Err(status) => return Err(status),
+        };
+        Ok(Response::new(PingResponse {
+            uptime_ms: self.started.elapsed().as_millis() as u64,
+            initialized,
+        }))
+    }
+
+    // Reports how much memory the namespace's tree takes up, from its geometry under a
+    // read lock
+    async fn stats(
+        &self,
+        request: Request<StatsRequest>,
+    ) -> Result<Response<StatsResponse>, Status> {
+        let (num_buckets, bucket_size, estimated_bytes) =
+            match self.tree(&request.get_ref().namespace) {
+                Ok(tree) => {
+                    let data_store = tree.shared()?;
+                    (
+                        data_store.len(),
+                        data_store.bucket_size(),
+                        data_store.estimated_bytes(),
+                    )
+                }
+                Err(status) if status.code() == tonic::Code::NotFound => (0, 0, 0),
+                Err(status) => return Err(status),
+            };
+
+        Ok(Response::new(StatsResponse {
+            num_buckets: num_buckets as i64,
+            bucket_size: bucket_size as i32,
+            total_blocks: (num_buckets * bucket_size) as i64,
+            estimated_bytes: estimated_bytes as u64,
+        }))
+    }
+
+    // Reports the current geometry so a client can attach without calling setup
+    async fn get_config(
+        &self,
+        request: Request<GetConfigRequest>,
+    ) -> Result<Response<GetConfigResponse>, Status> {
+        let (num_buckets, bucket_size, block_size) = match self.tree(&request.get_ref().namespace) {
+            Ok(tree) => {
+                let data_store = tree.shared()?;
+                (
+                    data_store.len(),
+                    data_store.bucket_size() as i32,
+                    data_store.block_size() as i32,
+                )
+            }
+            // A namespace nobody has set up yet reports the same as an empty tree
+            Err(status) if status.code() == tonic::Code::NotFound => (0, 0, 0),
+            Err(status) => return Err(status),
+        };
+
+        // The tree always holds 2^num_layers - 1 buckets
+        let num_layers = (num_buckets + 1).trailing_zeros() as i32;
+
+        Ok(Response::new(GetConfigResponse {
+            num_layers,
+            bucket_size,
+            num_buckets: num_buckets as i64,
+            initialized: num_buckets > 0,
+            block_size,
+        }))
+    }
+}
+
+// "`name` " for a named namespace, nothing for the default one.
+fn namespace_label(namespace: &str) -> String {
+    if namespace.is_empty() {
+        String::new()
+    } else {
+        format!("`{}` ", namespace)
+    }
+}
+
+// The first few payload bytes in hex, with `..` when there are more.
+fn hex_prefix(data: &[u8]) -> String {
+    const SHOWN: usize = 4;
+    let mut hex: String = data
+        .iter()
+        .take(SHOWN)
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    if data.len() > SHOWN {
+        hex.push_str("..");
+    }
+    hex
 }
 
 // Utility function to display `data_store` as an implicit binary tree.
-pub fn display_tree(data_store: &Vec<Vec<Block>>) {
+pub fn display_tree(data_store: &Arena) {
+    display_tree_to(data_store, &mut io::stdout().lock()).expect("failed to write to stdout");
+}
+
+// `display_tree`'s rendering as a String.
+pub fn tree_to_string(data_store: &Arena) -> String {
+    let mut out = Vec::new();
+    display_tree_to(data_store, &mut out).expect("writing to a Vec can't fail");
+    String::from_utf8(out).expect("the tree renders as UTF-8")
+}
+
+// Renders `data_store` as an implicit binary tree into `out`, one level per group of
+// lines and one line per block slot.
+pub fn display_tree_to<W: Write>(data_store: &Arena, out: &mut W) -> io::Result<()> {
     if data_store.is_empty() {
-        println!("Tree is empty.");
-        return;
+        return writeln!(out, "Tree is empty.");
     }
 
+    // At least 1 past the check above: a single-bucket tree is one level, the root
     let num_buckets = data_store.len();
-    let height = (num_buckets as f64 + 1.0).log2().ceil() as usize;
-    let max_width = 2_usize.pow((height - 1) as u32);
+    let height = (usize::BITS - num_buckets.leading_zeros()) as usize;
+    let max_width = 1 << (height - 1);
 
     for level in 0..height {
         let level_padding = (max_width / 2_usize.pow(level as u32)) - 1;
         let start_index = 2_usize.pow(level as u32) - 1;
+        if start_index >= num_buckets {
+            break;
+        }
         let end_index = cmp::min(start_index + 2_usize.pow(level as u32), num_buckets);
 
         let stacked_values: Vec<String> = (start_index..end_index)
@@ -190,7 +1299,7 @@ pub fn display_tree(data_store: &Vec<Vec<Block>>) {
                         if block.index == -1 {
                             "(_,_)".to_string()
                         } else {
-                            format!("({},{})", block.value, block.index)
+                            format!("({},{})", hex_prefix(&block.data), block.index)
                         }
                     })
                     .collect::<Vec<String>>()
@@ -206,38 +1315,308 @@ pub fn display_tree(data_store: &Vec<Vec<Block>>) {
             .map(|value| value.lines().collect())
             .collect();
 
-        for line in 0..stacked_lines[0].len() {
+        // Buckets in a level needn't render to the same number of lines, so run to the
+        // tallest and fill the gaps in shorter ones with blanks as wide as their entries
+        let max_lines = stacked_lines.iter().map(Vec::len).max().unwrap_or(0);
+        for line in 0..max_lines {
             let line_content: String = stacked_lines
                 .iter()
-                .map(|stack| stack[line])
-                .collect::<Vec<&str>>()
+                .map(|stack| match stack.get(line) {
+                    Some(entry) => entry.to_string(),
+                    None => " ".repeat(stack.iter().map(|entry| entry.len()).max().unwrap_or(0)),
+                })
+                .collect::<Vec<String>>()
                 .join(&join_padding);
 
-            println!("{}{}", line_padding, line_content);
+            writeln!(out, "{}{}", line_padding, line_content)?;
         }
 
-        println!();
+        writeln!(out)?;
     }
+    Ok(())
 }
 
-// CLI argument parser using `clap`
-#[derive(Parser)]
-struct Args {
-    /// Port for the server to listen on
-    #[arg(short, long, default_value = "50061")]
-    port: u16,
+#[cfg(not(test))]
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    cli::main()
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let args = Args::parse();
-    let address = format!("[::1]:{}", args.port).parse()?;
-    let path_oram = MyPathOram::default();
-    println!("Path ORAM Server listening on {}", address);
+// The binary's command line, startup and shutdown: everything only `main` uses. The tests
+// compile this file in as a module, without it.
+#[cfg(not(test))]
+mod cli {
+    use super::*;
+    use clap::Parser;
+    use path_oram::path_oram_server::PathOramServer;
+    use std::future::Future;
+    use std::net::{IpAddr, SocketAddr};
+    use std::pin::Pin;
+    use std::sync::atomic::AtomicUsize;
+    use std::task::{Context, Poll};
+    use tonic::transport::{Identity, Server, ServerTlsConfig};
+    use tower::Service;
+    use tracing::error;
+    use tracing_subscriber::EnvFilter;
 
-    Server::builder()
-        .add_service(PathOramServer::new(path_oram))
-        .serve(address)
-        .await?;
-    Ok(())
+    // Encoded descriptors of path_oram.proto, served by --reflection
+    const FILE_DESCRIPTOR_SET: &[u8] = tonic::include_file_descriptor_set!("path_oram_descriptor");
+
+    // CLI argument parser using `clap`
+    #[derive(Parser)]
+    struct Args {
+        /// Address to bind, e.g. `0.0.0.0` or `[::]` to accept remote clients
+        #[arg(long, default_value = "[::1]", value_parser = parse_host)]
+        host: IpAddr,
+        /// PEM certificate chain to serve TLS with (needs --tls-key)
+        #[arg(long, requires = "tls_key")]
+        tls_cert: Option<PathBuf>,
+        /// PEM private key for --tls-cert
+        #[arg(long, requires = "tls_cert")]
+        tls_key: Option<PathBuf>,
+        /// Port for the server to listen on
+        #[arg(short, long, default_value = "50061")]
+        port: u16,
+        /// Snapshot (from the Flush RPC) to restore the tree from on startup
+        #[arg(long)]
+        restore_from: Option<PathBuf>,
+        /// Keep the tree in this file: restored on startup if it exists (unless
+        /// --restore-from is given), rewritten every --snapshot-interval-secs and on Ctrl-C
+        #[arg(long)]
+        snapshot_path: Option<PathBuf>,
+        /// Seconds between periodic snapshots to --snapshot-path (0 = only on shutdown)
+        #[arg(long, default_value_t = 0)]
+        snapshot_interval_secs: u64,
+        /// Directory the Flush RPC may write snapshots into; clients name files relative to
+        /// it. Defaults to the directory of --snapshot-path; without either, Flush is refused
+        #[arg(long)]
+        snapshot_dir: Option<PathBuf>,
+        /// Directory the Print RPC may write trees into; clients name files relative to it.
+        /// Without it, only printing to stdout is allowed
+        #[arg(long)]
+        print_dir: Option<PathBuf>,
+        /// Most blocks (buckets times bucket size) one tree may hold; larger setups, and
+        /// snapshots of larger trees, are rejected
+        #[arg(long, default_value_t = DEFAULT_MAX_BLOCKS)]
+        max_blocks: usize,
+        /// Batch concurrent write_block requests under one lock acquisition (trusted
+        /// single-client benchmarks)
+        #[arg(long)]
+        write_coalesce: bool,
+        /// Most writes applied per batch with --write-coalesce
+        #[arg(long, default_value_t = 32)]
+        coalesce_max_batch: usize,
+        /// How long a batch waits for more writes after the first, in microseconds
+        #[arg(long, default_value_t = 100)]
+        coalesce_window_us: u64,
+        /// Store each written bucket's real blocks ahead of its dummies (no effect on
+        /// encrypted blocks, which all look like dummies to the server)
+        #[arg(long)]
+        compact_buckets: bool,
+        /// Independently locked segments per tree; bucket i lives in segment i mod this
+        #[arg(long, default_value_t = DEFAULT_SHARDS, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+        shards: usize,
+        /// Largest gRPC message to accept or send, in bytes (default: tonic's 4 MiB limit on
+        /// received messages). Each message of a SetupBulk stream is checked separately.
+        #[arg(long, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+        max_message_bytes: Option<usize>,
+        /// Serve gRPC reflection (v1 and v1alpha) describing the PathOram service, so tools
+        /// like grpcurl can call it without the .proto. Off by default: it publishes the schema
+        #[arg(long)]
+        reflection: bool,
+        /// Log filter in `RUST_LOG` syntax, e.g. `info` or `server=debug`; logs go to stderr
+        #[arg(long, default_value = "info", value_parser = parse_log_filter)]
+        log_level: String,
+    }
+
+    // Wraps the gRPC service to count requests still being served, so shutdown can say how
+    // many it is waiting for.
+    #[derive(Clone)]
+    struct InFlight<S> {
+        inner: S,
+        count: Arc<AtomicUsize>,
+    }
+
+    // Decrements the count when a request finishes or its future is dropped.
+    struct InFlightGuard(Arc<AtomicUsize>);
+
+    impl Drop for InFlightGuard {
+        fn drop(&mut self) {
+            self.0.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    impl<S, R> Service<R> for InFlight<S>
+    where
+        S: Service<R>,
+        S::Future: Send + 'static,
+    {
+        type Response = S::Response;
+        type Error = S::Error;
+        type Future = Pin<Box<dyn Future<Output = Result<S::Response, S::Error>> + Send>>;
+
+        fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            self.inner.poll_ready(cx)
+        }
+
+        fn call(&mut self, request: R) -> Self::Future {
+            self.count.fetch_add(1, Ordering::SeqCst);
+            let guard = InFlightGuard(Arc::clone(&self.count));
+            let response = self.inner.call(request);
+            Box::pin(async move {
+                let _guard = guard;
+                response.await
+            })
+        }
+    }
+
+    fn parse_log_filter(s: &str) -> Result<String, String> {
+        EnvFilter::try_new(s)
+            .map(|_| s.to_string())
+            .map_err(|e| format!("bad log filter `{}`: {}", s, e))
+    }
+
+    // An IPv4 or IPv6 address, the latter optionally in brackets.
+    fn parse_host(s: &str) -> Result<IpAddr, String> {
+        s.strip_prefix('[')
+            .and_then(|s| s.strip_suffix(']'))
+            .unwrap_or(s)
+            .parse()
+            .map_err(|_| format!("`{}` is not an IPv4 or IPv6 address", s))
+    }
+
+    #[tokio::main]
+    pub async fn main() -> Result<(), Box<dyn std::error::Error>> {
+        let args = Args::parse();
+        tracing_subscriber::fmt()
+            .with_env_filter(EnvFilter::new(&args.log_level))
+            .with_writer(io::stderr)
+            .init();
+        let address = SocketAddr::new(args.host, args.port);
+        let restore_from = args
+            .restore_from
+            .as_ref()
+            .or(args.snapshot_path.as_ref().filter(|path| path.exists()));
+        let mut path_oram = match restore_from {
+            Some(path) => {
+                let path_oram = MyPathOram::load_snapshot(path, args.shards, args.max_blocks)?;
+                info!(path = %path.display(), "restored snapshot");
+                path_oram
+            }
+            None => MyPathOram::new(Arena::new(args.shards)),
+        };
+        if args.write_coalesce {
+            path_oram.enable_write_coalescing(
+                args.coalesce_max_batch,
+                Duration::from_micros(args.coalesce_window_us),
+            );
+            info!(
+                max_batch = args.coalesce_max_batch,
+                window_us = args.coalesce_window_us,
+                "coalescing writes"
+            );
+        }
+        path_oram.set_compact_buckets(args.compact_buckets);
+        path_oram.set_print_dir(args.print_dir.clone());
+        path_oram.set_max_blocks(args.max_blocks);
+        path_oram.set_snapshot_dir(args.snapshot_dir.clone().or_else(|| {
+            let parent = args.snapshot_path.as_deref()?.parent()?;
+            if parent.as_os_str().is_empty() {
+                Some(PathBuf::from("."))
+            } else {
+                Some(parent.to_path_buf())
+            }
+        }));
+        let path_oram = Arc::new(path_oram);
+
+        if let (Some(path), interval) = (&args.snapshot_path, args.snapshot_interval_secs) {
+            if interval > 0 {
+                let (path_oram, path) = (Arc::clone(&path_oram), path.clone());
+                tokio::spawn(async move {
+                    let mut ticks = tokio::time::interval(Duration::from_secs(interval));
+                    ticks.tick().await; // The first tick fires immediately
+                    loop {
+                        ticks.tick().await;
+                        snapshot(&path_oram, &path).await;
+                    }
+                });
+            }
+        }
+        info!(%address, "Path ORAM server listening");
+
+        // On Ctrl-C the server stops accepting connections and waits for every request it
+        // has started, so a write holding the tree lock (or queued for coalescing) finishes
+        // before the final snapshot is taken and the process exits.
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let shutdown = {
+            let in_flight = Arc::clone(&in_flight);
+            async move {
+                if let Err(e) = tokio::signal::ctrl_c().await {
+                    error!("failed to listen for Ctrl-C: {}", e);
+                    std::future::pending::<()>().await;
+                }
+                info!(
+                    in_flight = in_flight.load(Ordering::SeqCst),
+                    "shutting down, draining requests"
+                );
+            }
+        };
+        let mut service = PathOramServer::from_arc(Arc::clone(&path_oram));
+        if let Some(limit) = args.max_message_bytes {
+            service = service
+                .max_decoding_message_size(limit)
+                .max_encoding_message_size(limit);
+            info!(limit, "message size limited");
+        }
+        let reflection = if args.reflection {
+            let builder = || {
+                tonic_reflection::server::Builder::configure()
+                    .register_encoded_file_descriptor_set(FILE_DESCRIPTOR_SET)
+            };
+            info!("serving gRPC reflection");
+            Some((builder().build_v1()?, builder().build_v1alpha()?))
+        } else {
+            None
+        };
+        let (reflection_v1, reflection_v1alpha) = reflection.unzip();
+        let mut server = Server::builder();
+        if let (Some(cert), Some(key)) = (&args.tls_cert, &args.tls_key) {
+            let identity = Identity::from_pem(std::fs::read(cert)?, std::fs::read(key)?);
+            server = server.tls_config(ServerTlsConfig::new().identity(identity))?;
+            info!(cert = %cert.display(), "serving TLS");
+        }
+        server
+            .layer(tower::layer::layer_fn(|inner| InFlight {
+                inner,
+                count: Arc::clone(&in_flight),
+            }))
+            .add_service(service)
+            .add_optional_service(reflection_v1)
+            .add_optional_service(reflection_v1alpha)
+            .serve_with_shutdown(address, shutdown)
+            .await?;
+        info!("all requests drained");
+
+        if let Some(path) = &args.snapshot_path {
+            snapshot(&path_oram, path).await;
+        }
+        Ok(())
+    }
+
+    // Saves a snapshot off the async workers, reporting rather than propagating failures so
+    // a full disk doesn't take the server down.
+    async fn snapshot(path_oram: &Arc<MyPathOram>, path: &Path) {
+        let (path_oram, path) = (Arc::clone(path_oram), path.to_path_buf());
+        let result = tokio::task::spawn_blocking(move || {
+            path_oram
+                .save_snapshot(&path)
+                .map(|()| path)
+                .map_err(|e| format!("Failed to write snapshot: {}", e))
+        })
+        .await;
+        match result {
+            Ok(Ok(path)) => info!(path = %path.display(), "snapshot written"),
+            Ok(Err(e)) => error!("{}", e),
+            Err(e) => error!("snapshot task failed: {}", e),
+        }
+    }
 }
@@ -0,0 +1,1658 @@
+// Tests for the client, run against `LocalBackend` and `MockBackend` in process, and
+// against the real server over the in-memory channel from `testutil`.
+use super::*;
+
+// Asserts that one access reached the backend as a single path read and a single path
+// write of the same set of buckets. Anything else would let the server tell accesses apart
+// by what the stash held, e.g. a bucket that is read but never written back.
+fn assert_path_read_and_written(calls: Vec<BackendCall>) {
+    let [BackendCall::ReadPath(read), BackendCall::WritePath(written)] = &calls[..] else {
+        panic!("expected one path read and one path write, got {:?}", calls);
+    };
+    let (mut read, mut written) = (read.clone(), written.clone());
+    read.sort_unstable();
+    written.sort_unstable();
+    assert_eq!(read, written, "buckets read and written back differ");
+}
+
+// A handler on `backend` holding `a` at every address a in 0..n. The key, if any, goes in
+// before setup so that setup seals the whole tree.
+fn set_up<B: OramBackend>(backend: B, n: i64, key: Option<&[u8; KEY_BYTES]>) -> PathORAMHandler<B> {
+    let mut handler = PathORAMHandler::new(backend, GOLDEN_Z, GOLDEN_SEED);
+    if let Some(key) = key {
+        handler.set_key(key);
+    }
+    handler.setup((0..n).collect()).unwrap();
+    handler
+}
+
+const TEST_KEY: [u8; KEY_BYTES] = [GOLDEN_SEED as u8; KEY_BYTES];
+
+#[test]
+fn stash_sizes_match_golden_trace() {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("golden/stash_sizes.txt");
+    let expected = read_golden(&path).unwrap();
+    let actual = golden_stash_sizes();
+    if let Some(op) = golden_divergence(&actual, &expected) {
+        panic!(
+            "eviction regression at op {}: stash size {:?}, golden {:?} (if intentional, \
+             regenerate with `client check-golden --update-golden`)",
+            op,
+            actual.get(op),
+            expected.get(op)
+        );
+    }
+}
+
+#[test]
+fn every_access_writes_back_the_buckets_it_read() {
+    let n = 1 << GOLDEN_EXP;
+    let mut handler = set_up(MockBackend::new(), n, None);
+    handler.backend_mut().take_calls();
+
+    let mut rng = StdRng::seed_from_u64(GOLDEN_SEED);
+    for i in 0..1000 {
+        let a = rng.gen_range(0..n);
+        if i % 2 == 0 {
+            handler.read(a).unwrap();
+        } else {
+            handler.write(a, i).unwrap();
+        }
+        assert_path_read_and_written(handler.backend_mut().take_calls());
+    }
+}
+
+// The smallest trees, whose paths are one (L = 0) or two buckets long
+#[test]
+fn small_trees_write_back_the_buckets_they_read() {
+    for n in [1, 2, 3] {
+        let mut handler = set_up(MockBackend::new(), n, None);
+        handler.backend_mut().take_calls();
+        for i in 0..64 {
+            let a = i % n;
+            match i % 4 {
+                0 => handler.read(a).map(drop),
+                1 => handler.write(a, i).map(drop),
+                2 => handler.delete(a).map(drop),
+                _ => handler.dummy_access(),
+            }
+            .unwrap();
+            assert_path_read_and_written(handler.backend_mut().take_calls());
+        }
+    }
+}
+
+// The tree shapes `setup` documents, for powers of two and not: N addresses take
+// L = ceil(log2 N), 2^L leaves and 2^(L+1) - 1 buckets, every address holds its value and
+// N itself is out of range
+#[test]
+fn setup_builds_the_documented_tree_for_any_n() {
+    for (n, l, buckets) in [
+        (1, 0, 1),
+        (2, 1, 3),
+        (3, 2, 7),
+        (5, 3, 15),
+        (1024, 10, 2047),
+    ] {
+        let mut handler = set_up(LocalBackend::new(), n, None);
+        assert_eq!(handler.l, l, "N = {}", n);
+        assert_eq!(handler.num_leaves, 1 << l, "N = {}", n);
+        assert_eq!(handler.backend().data_store.len(), buckets, "N = {}", n);
+        for a in 0..n {
+            handler.read_expecting(a, Some(a));
+        }
+        assert!(matches!(
+            handler.read(n),
+            Err(OramError::AddressOutOfRange { .. })
+        ));
+        handler.validate_invariant().unwrap();
+    }
+}
+
+#[test]
+fn setup_of_no_addresses_is_an_error() {
+    let mut handler = PathORAMHandler::new(LocalBackend::new(), GOLDEN_Z, GOLDEN_SEED);
+    match handler.setup(Vec::new()) {
+        Err(OramError::Rpc(status)) => assert_eq!(status.code(), tonic::Code::InvalidArgument),
+        other => panic!("empty setup gave {:?}", other),
+    }
+    assert!(matches!(handler.read(0), Err(OramError::NotSetup)));
+}
+
+// Garbles the leaf bucket of `a`'s path (the last bucket a read of `a` opens) so it no
+// longer decrypts. Returns the bucket and what it held, for putting it back.
+fn garble_leaf_bucket(handler: &mut PathORAMHandler<LocalBackend>, a: i64) -> (usize, Vec<Block>) {
+    let bucket = slot(handler.get_index(handler.leaf_of(a), handler.l));
+    let store = &mut handler.backend_mut().data_store;
+    let saved = store[bucket].clone();
+    for block in store[bucket].iter_mut() {
+        block.data.fill(0xff);
+    }
+    (bucket, saved)
+}
+
+// A read that fails must change neither the stash nor where the block is looked for, so
+// once the bucket is back the value is too.
+#[test]
+fn failed_read_changes_nothing() {
+    let n = 1 << 6;
+    let mut handler = set_up(LocalBackend::new(), n, Some(&TEST_KEY));
+    for a in 0..n {
+        let (bucket, saved) = garble_leaf_bucket(&mut handler, a);
+        let before = handler.stash.clone();
+        let result = handler.read(a);
+        handler.backend_mut().data_store[bucket] = saved;
+        assert!(
+            result.is_err(),
+            "read of {} succeeded from a garbled path",
+            a
+        );
+        assert_eq!(
+            handler.stash, before,
+            "failed read of {} changed the stash",
+            a
+        );
+        assert_eq!(
+            handler.read(a).unwrap(),
+            Some(a),
+            "block {} was orphaned",
+            a
+        );
+    }
+}
+
+// A reshuffle reads the tree in chunks; one that fails on a later chunk must leave the
+// stash and position map as they were, with the tree still holding every block
+#[test]
+fn failed_reshuffle_changes_nothing() {
+    let n = 1 << 6;
+    let mut handler = set_up(LocalBackend::new(), n, Some(&TEST_KEY));
+    // One bucket a request, so the garbled leaf comes long after the first chunk
+    handler.bulk_bytes = 1;
+    let (bucket, saved) = garble_leaf_bucket(&mut handler, n - 1);
+    assert!(bucket > 0);
+    let (stash, pmap) = (handler.stash.clone(), handler.pmap.clone());
+    assert!(handler.reshuffle().is_err());
+    handler.backend_mut().data_store[bucket] = saved;
+    assert_eq!(handler.stash, stash);
+    assert_eq!(handler.pmap, pmap);
+    handler.validate_invariant().unwrap();
+    for a in 0..n {
+        handler.read_expecting(a, Some(a));
+    }
+}
+
+#[test]
+fn failed_batch_read_changes_nothing() {
+    let n = 1 << 6;
+    let mut handler = set_up(LocalBackend::new(), n, Some(&TEST_KEY));
+    for a in (0..n).step_by(2) {
+        let (bucket, saved) = garble_leaf_bucket(&mut handler, a);
+        let before = handler.stash.clone();
+        let result = handler.read_range(a, a + 2);
+        handler.backend_mut().data_store[bucket] = saved;
+        assert!(
+            result.is_err(),
+            "batch read of {} succeeded from a garbled path",
+            a
+        );
+        assert_eq!(
+            handler.stash, before,
+            "failed batch read of {} changed the stash",
+            a
+        );
+        assert_eq!(
+            handler.read_range(a, a + 2).unwrap(),
+            vec![Some(a), Some(a + 1)]
+        );
+    }
+}
+
+#[test]
+fn read_only_rejects_writes() {
+    let mut handler = set_up(LocalBackend::new(), 8, None);
+    handler.set_read_only(true);
+    assert!(matches!(handler.write(1, 99), Err(OramError::ReadOnly)));
+    assert!(matches!(handler.delete(1), Err(OramError::ReadOnly)));
+    handler.read_expecting(1, Some(1));
+}
+
+#[test]
+fn read_only_rejects_transactions() {
+    let mut handler = set_up(LocalBackend::new(), 8, None);
+    handler.set_read_only(true);
+    assert!(matches!(
+        handler.transaction(vec![(1, 99)]),
+        Err(OramError::ReadOnly)
+    ));
+    let mixed = [(Op::Read, 2, None), (Op::Write, 1, Some(99))];
+    assert!(matches!(
+        handler.batch_access(&mixed),
+        Err(OramError::ReadOnly)
+    ));
+    assert_eq!(handler.read_range(1, 3).unwrap(), vec![Some(1), Some(2)]);
+}
+
+#[test]
+fn read_only_rejects_moves() {
+    let mut handler = set_up(LocalBackend::new(), 8, None);
+    handler.set_read_only(true);
+    assert!(matches!(handler.move_block(2, 3), Err(OramError::ReadOnly)));
+    handler.read_expecting(2, Some(2));
+    handler.read_expecting(3, Some(3));
+}
+
+#[test]
+fn read_only_rejects_stash_preloads() {
+    let mut handler = set_up(LocalBackend::new(), 8, None);
+    handler.set_read_only(true);
+    let path = std::env::temp_dir().join(format!("read_only_preload_{}.txt", std::process::id()));
+    std::fs::write(&path, "1,99\n").unwrap();
+    let result = handler.preload_stash(&path);
+    std::fs::remove_file(&path).unwrap();
+    assert!(matches!(result, Err(OramError::ReadOnly)));
+    handler.read_expecting(1, Some(1));
+}
+
+// A `LocalBackend` whose path writes fail, applying nothing, while `fail_writes` is set,
+// and whose setups fail the same way while `fail_setup` is.
+#[derive(Default, Clone)]
+struct FlakyBackend {
+    inner: LocalBackend,
+    fail_writes: bool,
+    fail_setup: bool,
+}
+
+impl OramBackend for FlakyBackend {
+    fn setup(
+        &mut self,
+        num_layers: i32,
+        bucket_size: i32,
+        block_size: i32,
+    ) -> Result<bool, Status> {
+        if self.fail_setup {
+            return Err(Status::unavailable("setup dropped"));
+        }
+        self.inner.setup(num_layers, bucket_size, block_size)
+    }
+
+    fn read_path(&mut self, indices: Vec<i64>) -> Result<Vec<Block>, Status> {
+        self.inner.read_path(indices)
+    }
+
+    fn write_path(&mut self, indices: Vec<i64>, blocks: Vec<Block>) -> Result<bool, Status> {
+        if self.fail_writes {
+            return Err(Status::unavailable("write dropped"));
+        }
+        self.inner.write_path(indices, blocks)
+    }
+
+    fn print(&mut self) -> Result<bool, Status> {
+        self.inner.print()
+    }
+
+    fn get_config(&mut self) -> Result<GetConfigResponse, Status> {
+        self.inner.get_config()
+    }
+}
+
+#[test]
+fn accesses_report_success() {
+    let mut handler = set_up(FlakyBackend::default(), 16, None);
+    assert_eq!(handler.write(3, 30).unwrap(), Some(3));
+    handler.read_expecting(3, Some(30));
+    assert!(matches!(
+        handler.read(16),
+        Err(OramError::AddressOutOfRange { a: 16, n: 16 })
+    ));
+}
+
+// Every write fails for a while. The first access reads its path and only fails writing
+// it back, so its write still counts; the later ones fail rewriting that path before they
+// read anything, so theirs don't. Either way no block is lost, and the handler carries on
+// once writes go through again.
+#[test]
+fn failed_write_backs_lose_no_blocks() {
+    let n = 64;
+    let mut handler = set_up(FlakyBackend::default(), n, None);
+
+    handler.backend_mut().fail_writes = true;
+    for a in 0..8 {
+        let result = handler.write(a, 100 + a);
+        assert!(
+            matches!(result, Err(OramError::Rpc(_))),
+            "write of {} went through",
+            a
+        );
+    }
+    assert!(handler.read(20).is_err());
+    handler.backend_mut().fail_writes = false;
+
+    for a in 0..n {
+        let expected = if a == 0 { 100 } else { a };
+        handler.read_expecting(a, Some(expected));
+    }
+    handler.validate_invariant().unwrap();
+}
+
+// A reshuffle right after a failed write-back settles that path first, so the stale copy
+// the tree still holds of the written value doesn't come back, and no value is lost
+#[test]
+fn reshuffle_after_a_failed_write_back_keeps_every_value() {
+    let n = 64;
+    let mut handler = set_up(FlakyBackend::default(), n, None);
+    for a in (0..n).step_by(4) {
+        handler.write(a, 100 + a).unwrap();
+    }
+    handler.backend_mut().fail_writes = true;
+    assert!(handler.write(6, 106).is_err());
+    handler.backend_mut().fail_writes = false;
+
+    handler.reshuffle().unwrap();
+    assert!(handler.unwritten.is_empty());
+    handler.validate_invariant().unwrap();
+    for a in 0..n {
+        let expected = if a % 4 == 0 || a == 6 { 100 + a } else { a };
+        handler.read_expecting(a, Some(expected));
+    }
+    handler.validate_invariant().unwrap();
+}
+
+// A handler restored by `load_state` over a copy of the tree carries on exactly like the
+// one that saved it: the same leaves and the same values. Saving settles a failed
+// write-back first, so the copy holds that path as the saved stash expects.
+#[test]
+fn saved_state_resumes_the_same_run() {
+    let dir = ScratchDir::new("saved_state");
+    let path = dir.0.join("state.client");
+    let n = 64;
+    let mut saved = set_up(FlakyBackend::default(), n, None);
+    for a in (0..n).step_by(4) {
+        saved.write(a, 100 + a).unwrap();
+    }
+    saved.backend_mut().fail_writes = true;
+    assert!(saved.write(6, 106).is_err());
+    saved.backend_mut().fail_writes = false;
+    saved.save_state(&path).unwrap();
+    assert!(saved.unwritten.is_empty());
+
+    let backend = saved.backend().inner.clone();
+    let mut resumed = PathORAMHandler::new(backend, GOLDEN_Z, GOLDEN_SEED + 1);
+    resumed.load_state(&path).unwrap();
+    for a in 0..n {
+        let expected = if a % 4 == 0 || a == 6 { 100 + a } else { a };
+        saved.read_expecting(a, Some(expected));
+        resumed.read_expecting(a, Some(expected));
+        assert_eq!(saved.pmap, resumed.pmap, "after reading {}", a);
+    }
+    for i in 0..500 {
+        let a = (i * 37) % n;
+        let (ours, theirs) = if i % 3 == 0 {
+            (saved.write(a, 1000 + i), resumed.write(a, 1000 + i))
+        } else {
+            (saved.read(a), resumed.read(a))
+        };
+        assert_eq!(ours.unwrap(), theirs.unwrap(), "op {}", i);
+        assert_eq!(saved.pmap, resumed.pmap, "op {}", i);
+    }
+    resumed.validate_invariant().unwrap();
+}
+
+// With the path cache on, reads take buckets the last write-back wrote from memory, and
+// never see anything older than the tree holds. A failed write-back empties the cache,
+// since the tree may or may not have taken it.
+#[test]
+fn path_cache_never_serves_stale_buckets() {
+    let n = 64;
+    let mut handler = set_up(FlakyBackend::default(), n, None);
+    handler.set_path_cache(true);
+    let mut expected: Vec<i64> = (0..n).collect();
+    let mut rng = StdRng::seed_from_u64(GOLDEN_SEED);
+    let run = |handler: &mut PathORAMHandler<FlakyBackend>,
+               expected: &mut Vec<i64>,
+               rng: &mut StdRng,
+               ops: i64| {
+        for i in 0..ops {
+            // A few addresses, so consecutive accesses share more than the root
+            let a = rng.gen_range(0..8);
+            if i % 2 == 0 {
+                handler.write(a, 1000 + i).unwrap();
+                expected[slot(a)] = 1000 + i;
+            } else {
+                handler.read_expecting(a, Some(expected[slot(a)]));
+            }
+        }
+    };
+    run(&mut handler, &mut expected, &mut rng, 200);
+    assert!(handler.metrics().cached_buckets > 0);
+
+    handler.backend_mut().fail_writes = true;
+    assert!(handler.write(3, 33).is_err());
+    expected[3] = 33;
+    handler.backend_mut().fail_writes = false;
+    assert!(handler.path_cache.as_ref().unwrap().is_empty());
+
+    run(&mut handler, &mut expected, &mut rng, 200);
+    for a in 0..n {
+        handler.read_expecting(a, Some(expected[slot(a)]));
+    }
+    handler.validate_invariant().unwrap();
+}
+
+// With skip_unchanged, a plaintext write-back leaves out buckets that come back as they
+// were read, such as the all-dummy buckets plain setup never filled, and every value still
+// reads back
+#[test]
+fn unchanged_buckets_are_not_written_back() {
+    let n = 64;
+    let mut handler = set_up(MockBackend::new(), n, None);
+    handler.set_skip_unchanged(true);
+    handler.backend_mut().take_calls();
+    let mut skipped_dummies = 0;
+    for i in 0..200 {
+        let a = (i * 37) % n;
+        let skipped = handler.skipped_buckets();
+        handler.write(a, 1000 + i).unwrap();
+        let calls = handler.backend_mut().take_calls();
+        let (read, written) = match &calls[..] {
+            [BackendCall::ReadPath(read), BackendCall::WritePath(written)] => (read, &written[..]),
+            [BackendCall::ReadPath(read)] => (read, &[][..]),
+            _ => panic!(
+                "expected a path read and at most one write, got {:?}",
+                calls
+            ),
+        };
+        let left_out: Vec<i64> = read
+            .iter()
+            .copied()
+            .filter(|index| !written.contains(index))
+            .collect();
+        assert_eq!(
+            handler.skipped_buckets() - skipped,
+            left_out.len() as u64,
+            "op {}",
+            i
+        );
+        let store = &handler.backend().inner.data_store;
+        skipped_dummies += left_out
+            .iter()
+            .filter(|&&index| store[slot(index)].iter().all(|block| block.index == -1))
+            .count();
+    }
+    assert!(skipped_dummies > 0);
+    for a in 0..n {
+        let i = (0..200).rev().find(|i| (i * 37) % n == a);
+        handler.read_expecting(a, Some(i.map_or(a, |i| 1000 + i)));
+    }
+    handler.validate_invariant().unwrap();
+}
+
+// A setup the backend fails returns the error and leaves the handler on its old tree
+#[test]
+fn failed_setup_changes_nothing() {
+    let mut handler = set_up(FlakyBackend::default(), 16, None);
+    handler.write(3, 30).unwrap();
+    handler.backend_mut().fail_setup = true;
+    assert!(matches!(
+        handler.setup((100..132).collect()),
+        Err(OramError::Rpc(_))
+    ));
+    handler.backend_mut().fail_setup = false;
+    assert!(matches!(
+        handler.read(16),
+        Err(OramError::AddressOutOfRange { a: 16, n: 16 })
+    ));
+    for a in 0..16 {
+        handler.read_expecting(a, Some(if a == 3 { 30 } else { a }));
+    }
+    handler.validate_invariant().unwrap();
+}
+
+// dump_all returns what was written, deleted addresses left out, including values that
+// are still in the stash when it runs
+#[test]
+fn dump_all_returns_every_written_value() {
+    let n = 64;
+    let mut handler = PathORAMHandler::new(LocalBackend::new(), 2, GOLDEN_SEED);
+    handler.setup((0..n).collect()).unwrap();
+    let mut expected: BTreeMap<i64, i64> = (0..n).map(|a| (a, a)).collect();
+    let mut rng = StdRng::seed_from_u64(GOLDEN_SEED);
+    // With two blocks a bucket the stash soon holds some of the written values
+    for i in 0.. {
+        if i >= n && !handler.stash.is_empty() {
+            break;
+        }
+        assert!(i < 100 * n, "the stash never held a block");
+        let a = rng.gen_range(0..n);
+        if i % 5 == 4 {
+            handler.delete(a).unwrap();
+            expected.remove(&a);
+        } else {
+            handler.write(a, 1000 + i).unwrap();
+            expected.insert(a, 1000 + i);
+        }
+    }
+    assert!(handler.stash.keys().any(|a| expected.contains_key(a)));
+    assert_eq!(handler.dump_all().unwrap(), expected);
+    handler.validate_invariant().unwrap();
+}
+
+// A second setup without force is refused by the server, and the handler keeps serving
+// the tree it already has
+#[test]
+fn refused_setup_keeps_the_old_tree() {
+    let server = testutil::InProcessServer::start();
+    let mut handler = set_up(server.backend(""), 16, None);
+    handler.write(3, 30).unwrap();
+    match handler.setup((100..132).collect()) {
+        Err(OramError::Rpc(status)) => {
+            assert_eq!(status.code(), tonic::Code::FailedPrecondition)
+        }
+        other => panic!("re-setup without force gave {:?}", other),
+    }
+    assert!(matches!(
+        handler.read(16),
+        Err(OramError::AddressOutOfRange { a: 16, n: 16 })
+    ));
+    for a in 0..16 {
+        handler.read_expecting(a, Some(if a == 3 { 30 } else { a }));
+    }
+    handler.validate_invariant().unwrap();
+}
+
+// The bucket, slot and address of some real block below the root, so on the paths to only
+// some of the leaves.
+fn block_below_root(handler: &PathORAMHandler<LocalBackend>) -> (usize, usize, i64) {
+    let store = &handler.backend().data_store;
+    (1..store.len())
+        .flat_map(|bucket| (0..store[bucket].len()).map(move |i| (bucket, i)))
+        .map(|(bucket, i)| (bucket, i, store[bucket][i].index))
+        .find(|&(_, _, a)| a != -1)
+        .expect("every block is in the root or the stash")
+}
+
+// Each way of breaking the invariant, done to a freshly set-up tree, must be reported
+#[test]
+fn invariant_violations_are_reported() {
+    type Corruption = fn(&mut PathORAMHandler<LocalBackend>);
+    let n = 16;
+    let corruptions: [(&str, Corruption); 4] = [
+        ("leaf changed in the pmap", |handler| {
+            // The other half of the tree, which shares only the root with the old path
+            let (_, _, a) = block_below_root(handler);
+            handler.pmap[slot(a)] ^= 1 << (handler.l - 1);
+        }),
+        ("block moved off its path", |handler| {
+            let (bucket, i, _) = block_below_root(handler);
+            let leaf_level = (1 << handler.l) - 1;
+            let store = &mut handler.backend_mut().data_store;
+            let (target, j) = (leaf_level..store.len())
+                .filter(|&target| target != bucket)
+                .find_map(|target| {
+                    let j = store[target].iter().position(|block| block.index == -1)?;
+                    Some((target, j))
+                })
+                .expect("no free slot in the leaves");
+            let block = std::mem::replace(
+                &mut store[bucket][i],
+                dummy_block(8, &mut rand::thread_rng()),
+            );
+            let a = block.index;
+            store[target][j] = block;
+            // Off the path unless the leaf happens to be its own
+            let leaf = target as i64 - leaf_level as i64;
+            if handler.pmap[slot(a)] == leaf {
+                handler.pmap[slot(a)] = (leaf + 1) % (1 << handler.l);
+            }
+        }),
+        ("block both stashed and in the tree", |handler| {
+            let (bucket, i, a) = block_below_root(handler);
+            let data = handler.backend().data_store[bucket][i].data.clone();
+            handler.stash.insert(a, data);
+        }),
+        ("stashed address out of range", |handler| {
+            let n = handler.n;
+            handler.stash.insert(n, vec![0; 8]);
+        }),
+    ];
+    for (what, corrupt) in corruptions {
+        let mut handler = set_up(LocalBackend::new(), n, None);
+        handler.validate_invariant().unwrap();
+        corrupt(&mut handler);
+        assert!(
+            handler.validate_invariant().is_err(),
+            "{} went unnoticed",
+            what
+        );
+    }
+}
+
+// A directory of its own under the system temp dir for one test, removed when dropped.
+struct ScratchDir(PathBuf);
+
+impl ScratchDir {
+    fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("{}_{}", name, std::process::id()));
+        std::fs::create_dir_all(&path).unwrap();
+        ScratchDir(path)
+    }
+}
+
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+#[test]
+fn print_writes_only_inside_print_dir() {
+    let dir = ScratchDir::new("print_dir");
+    let print_dir = dir.0.join("trees");
+    std::fs::create_dir(&print_dir).unwrap();
+    let server = testutil::InProcessServer::start_with(|| {
+        let mut path_oram = testutil::server::MyPathOram::default();
+        path_oram.set_print_dir(Some(print_dir.clone()));
+        path_oram
+    });
+    let mut handler = set_up(server.backend(""), 8, None);
+    let backend = handler.backend_mut();
+
+    assert_eq!(
+        backend.print_to_file("tree.txt".into()).unwrap(),
+        "tree.txt"
+    );
+    assert!(print_dir.join("tree.txt").is_file());
+
+    let outside = dir.0.join("escaped.txt");
+    for name in [
+        outside.to_string_lossy().into_owned(),
+        "../escaped.txt".to_string(),
+        "sub/../../escaped.txt".to_string(),
+        "..".to_string(),
+    ] {
+        let status = backend.print_to_file(name.clone()).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument, "{}", name);
+    }
+    assert!(!outside.exists());
+}
+
+#[test]
+fn print_to_file_needs_a_print_dir() {
+    let server = testutil::InProcessServer::start_with(testutil::server::MyPathOram::default);
+    let mut handler = set_up(server.backend(""), 8, None);
+    let status = handler
+        .backend_mut()
+        .print_to_file("tree.txt".into())
+        .unwrap_err();
+    assert_eq!(status.code(), tonic::Code::FailedPrecondition);
+    assert!(handler.backend_mut().print().unwrap());
+}
+
+// A server that coalesces writes and compacts buckets still serves every value back,
+// with and without encryption
+#[test]
+fn coalescing_compact_server_serves_every_value() {
+    for key in [None, Some(&TEST_KEY)] {
+        let server = testutil::InProcessServer::start_with(|| {
+            let mut path_oram = testutil::server::MyPathOram::default();
+            path_oram.enable_write_coalescing(4, Duration::from_millis(1));
+            path_oram.set_compact_buckets(true);
+            path_oram
+        });
+        let n = 64;
+        let mut handler = set_up(server.backend(""), n, key);
+        for a in (0..n).step_by(3) {
+            handler.write(a, 1000 + a).unwrap();
+        }
+        for a in 0..n {
+            let expected = if a % 3 == 0 { 1000 + a } else { a };
+            handler.read_expecting(a, Some(expected));
+        }
+        handler.validate_invariant().unwrap();
+    }
+}
+
+// Dummies get random payloads, so two buckets padded alike differ and no dummy the client
+// wrote is a run of zeros, while each still has index -1, or opens to it under a key
+#[test]
+fn padded_buckets_never_repeat() {
+    for key in [None, Some(&TEST_KEY)] {
+        let mut handler = set_up(LocalBackend::new(), 16, key);
+        let first = handler.fill_bucket(0, Vec::new());
+        let second = handler.fill_bucket(0, Vec::new());
+        assert_eq!(first.len(), GOLDEN_Z as usize);
+        assert_ne!(first, second);
+        assert_ne!(first[0], first[1]);
+        for block in first.into_iter().chain(second) {
+            assert_eq!(handler.open(0, block).unwrap().index, -1);
+        }
+    }
+
+    // A bulk setup writes every bucket, so none keeps the backend's all-zero fill
+    let mut handler = PathORAMHandler::new(LocalBackend::new(), GOLDEN_Z, GOLDEN_SEED);
+    handler.setup_bulk((0..16).collect()).unwrap();
+    let blocks = handler.backend().data_store.iter().flatten();
+    let dummies: Vec<&Block> = blocks.filter(|block| block.index == -1).collect();
+    assert!(!dummies.is_empty());
+    assert!(dummies
+        .iter()
+        .all(|block| block.data.iter().any(|&byte| byte != 0)));
+}
+
+// A block changed on the server after it was written no longer matches its bucket's
+// checksum, and the read reports the bucket as corrupt: a flipped byte in a plaintext
+// dummy, or an encrypted block zeroed out
+#[test]
+fn tampered_blocks_fail_their_checksum() {
+    type Damage = fn(&mut [testutil::server::path_oram::Block]);
+    let cases: [(Option<&[u8; KEY_BYTES]>, Damage); 2] = [
+        (None, |bucket| {
+            let dummy = bucket.iter_mut().find(|block| block.index == -1);
+            dummy.expect("the root holds a dummy").data[0] ^= 1;
+        }),
+        (Some(&TEST_KEY), |bucket| bucket[0].data.fill(0)),
+    ];
+    for (key, damage) in cases {
+        let server = testutil::InProcessServer::start();
+        let mut backend = server.backend("");
+        backend.set_checksums(true);
+        let mut handler = set_up(backend, 4, key);
+        handler.read_expecting(0, Some(0));
+
+        // Every path starts at the root
+        server.path_oram().tamper("", 0, damage);
+        match handler.read(1) {
+            Err(OramError::Corruption { index }) => assert_eq!(index, 0),
+            other => panic!("tampered read gave {:?}", other),
+        }
+    }
+}
+
+#[test]
+fn flush_writes_only_inside_snapshot_dir() {
+    let dir = ScratchDir::new("snapshot_dir");
+    let snapshot_dir = dir.0.join("snapshots");
+    std::fs::create_dir(&snapshot_dir).unwrap();
+    let server = testutil::InProcessServer::start_with(|| {
+        let mut path_oram = testutil::server::MyPathOram::default();
+        path_oram.set_snapshot_dir(Some(snapshot_dir.clone()));
+        path_oram
+    });
+    let mut handler = set_up(server.backend(""), 8, None);
+    let backend = handler.backend_mut();
+
+    assert!(backend.flush("run.server".into()).unwrap());
+    let restored = testutil::server::MyPathOram::load_snapshot(
+        &snapshot_dir.join("run.server"),
+        1,
+        testutil::server::DEFAULT_MAX_BLOCKS,
+    );
+    assert!(restored.is_ok());
+
+    let outside = dir.0.join("escaped.server");
+    for name in [
+        outside.to_string_lossy().into_owned(),
+        "../escaped.server".to_string(),
+        "sub/../../escaped.server".to_string(),
+    ] {
+        let status = backend.flush(name.clone()).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument, "{}", name);
+    }
+    assert!(!outside.exists());
+    assert!(!dir.0.join("escaped.server.tmp").exists());
+}
+
+// A snapshot whose header doesn't describe a tree, or describes one the file doesn't hold
+// or the server may not allocate, is refused before anything is allocated for it
+#[test]
+fn snapshots_with_bad_headers_are_rejected() {
+    use testutil::server::{MyPathOram, DEFAULT_MAX_BLOCKS};
+
+    let dir = ScratchDir::new("bad_snapshots");
+    let server = testutil::InProcessServer::start_with(|| {
+        let mut path_oram = MyPathOram::default();
+        path_oram.set_snapshot_dir(Some(dir.0.clone()));
+        path_oram
+    });
+    let mut handler = set_up(server.backend(""), 8, None);
+    assert!(handler.backend_mut().flush("good.server".into()).unwrap());
+    let good = std::fs::read(dir.0.join("good.server")).unwrap();
+    // 8 addresses take a 4-leaf tree: 7 buckets of Z blocks
+    let path = dir.0.join("bad.server");
+    let load = |bytes: &[u8], max_blocks| {
+        std::fs::write(&path, bytes).unwrap();
+        MyPathOram::load_snapshot(&path, 1, max_blocks)
+    };
+    assert!(load(&good, DEFAULT_MAX_BLOCKS).is_ok());
+
+    // The default namespace's header follows the tree count and its (empty) name
+    let with_header = |num_buckets: u64, bucket_size: i32| {
+        let mut bytes = good.clone();
+        bytes[8..16].copy_from_slice(&num_buckets.to_le_bytes());
+        bytes[16..20].copy_from_slice(&bucket_size.to_le_bytes());
+        bytes
+    };
+    let with_count = |count: u32| {
+        let mut bytes = good.clone();
+        bytes[..4].copy_from_slice(&count.to_le_bytes());
+        bytes
+    };
+    for (bytes, max_blocks, what) in [
+        (
+            with_header(u64::MAX, GOLDEN_Z),
+            DEFAULT_MAX_BLOCKS,
+            "huge bucket count",
+        ),
+        (
+            with_header(1 << 40, GOLDEN_Z),
+            DEFAULT_MAX_BLOCKS,
+            "bucket count over the limit",
+        ),
+        (with_header(7, 0), DEFAULT_MAX_BLOCKS, "zero bucket size"),
+        (
+            with_header(6, GOLDEN_Z),
+            DEFAULT_MAX_BLOCKS,
+            "not 2^k - 1 buckets",
+        ),
+        (
+            with_header(3, GOLDEN_Z),
+            DEFAULT_MAX_BLOCKS,
+            "fewer buckets than the file holds",
+        ),
+        (
+            good[..good.len() - 1].to_vec(),
+            DEFAULT_MAX_BLOCKS,
+            "truncated",
+        ),
+        (good.clone(), 7 * GOLDEN_Z as usize - 1, "over max_blocks"),
+        (
+            with_count(2),
+            DEFAULT_MAX_BLOCKS,
+            "more trees than the file holds",
+        ),
+        (
+            with_count(0),
+            DEFAULT_MAX_BLOCKS,
+            "bytes past the last tree",
+        ),
+    ] {
+        let e = load(&bytes, max_blocks).expect_err(what);
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData, "{}", what);
+    }
+}
+
+// Swaps every level of `handler` (its position-map levels too) over to `backends`, in
+// order from the data level down.
+fn move_levels<B: OramBackend>(handler: &mut PathORAMHandler<B>, mut backends: Vec<B>) {
+    let mut level = Some(handler);
+    while let Some(handler) = level {
+        handler.backend = backends.remove(0);
+        level = handler.posmap.as_deref_mut();
+    }
+    assert!(backends.is_empty(), "more backends than levels");
+}
+
+// A snapshot holds every namespace under its name, so a server restored from it carries
+// on with each client's tree, including the trees of a recursive position map
+#[test]
+fn snapshots_restore_every_namespace() {
+    let dir = ScratchDir::new("namespace_snapshot");
+    let path = dir.0.join("all.server");
+    let server = testutil::InProcessServer::start();
+
+    let mut flat = set_up(server.backend(""), 16, None);
+    flat.write(3, 30).unwrap();
+    let mut recursive = PathORAMHandler::new(server.backend("rec"), GOLDEN_Z, GOLDEN_SEED);
+    let mut level = 0;
+    recursive
+        .setup_recursive((100..164).collect(), 4, 2, &mut || {
+            level += 1;
+            server.backend(&format!("rec/posmap{}", level))
+        })
+        .unwrap();
+    recursive.write(5, 50).unwrap();
+    server.path_oram().save_snapshot(&path).unwrap();
+
+    let restored = testutil::InProcessServer::start_with(|| {
+        testutil::server::MyPathOram::load_snapshot(&path, 4, testutil::server::DEFAULT_MAX_BLOCKS)
+            .unwrap()
+    });
+    let mut namespaces = vec!["rec".to_string()];
+    namespaces.extend((1..=level).map(|level| format!("rec/posmap{}", level)));
+    move_levels(&mut flat, vec![restored.backend("")]);
+    move_levels(
+        &mut recursive,
+        namespaces.iter().map(|ns| restored.backend(ns)).collect(),
+    );
+    for a in 0..16 {
+        flat.read_expecting(a, Some(if a == 3 { 30 } else { a }));
+    }
+    for a in 0..64 {
+        recursive.read_expecting(a, Some(if a == 5 { 50 } else { 100 + a }));
+    }
+    flat.validate_invariant().unwrap();
+    recursive.validate_invariant().unwrap();
+}
+
+// compare-recursion --server runs the same reads over the server with the position-map
+// pipeline on and off, and only the timing may differ between the two
+#[test]
+fn pipeline_comparison_runs_both_modes_over_the_server() {
+    let server = testutil::InProcessServer::start();
+    let compare = CompareArgs {
+        n: 6,
+        z: GOLDEN_Z,
+        ops: 100,
+        seed: GOLDEN_SEED,
+        fanout: 4,
+        cutoff: 2,
+        server: true,
+    };
+    let mut namespaces = Vec::new();
+    let [(_, pipelined), (_, sequential)] = compare_pipelining(&compare, "cmp", &mut |ns| {
+        namespaces.push(ns.clone());
+        server.backend(&ns)
+    })
+    .unwrap();
+    assert!(pipelined > 2.0, "{} round trips per read", pipelined);
+    assert_eq!(pipelined, sequential);
+    assert!(namespaces.contains(&"cmp/pipelined/posmap1".to_string()));
+    assert!(namespaces.contains(&"cmp/sequential/posmap1".to_string()));
+}
+
+// One bucket of dummies for a tree of i64 values
+fn dummy_bucket() -> Vec<Block> {
+    let dummy = Block {
+        index: -1,
+        data: vec![0; 8],
+    };
+    vec![dummy; GOLDEN_Z as usize]
+}
+
+// A bulk setup over gRPC streams the whole tree in one SetupBulk call, which replaces the
+// all-zero tree the server set up
+#[test]
+fn bulk_setup_streams_the_tree_to_the_server() {
+    let server = testutil::InProcessServer::start();
+    let mut handler = PathORAMHandler::new(server.backend("bulk"), GOLDEN_Z, GOLDEN_SEED);
+    handler.setup_bulk((100..116).collect()).unwrap();
+    assert!(handler.backend().bulk.is_none());
+    for a in 0..16 {
+        handler.read_expecting(a, Some(100 + a));
+    }
+    handler.validate_invariant().unwrap();
+}
+
+// A stream that ends without its `last` chunk is aborted and leaves the tree as it was
+#[test]
+fn unfinished_bulk_stream_keeps_the_old_tree() {
+    let server = testutil::InProcessServer::start();
+    let mut handler = set_up(server.backend("bulk"), 16, None);
+    let mut backend = server.backend("bulk");
+    backend.begin_bulk().unwrap();
+    backend.send_buckets(0, 1, dummy_bucket()).unwrap();
+    let upload = backend.bulk.take().unwrap();
+    let status = upload.finish(&backend.rt, None).unwrap_err();
+    assert_eq!(status.code(), tonic::Code::Aborted);
+    for a in 0..16 {
+        handler.read_expecting(a, Some(a));
+    }
+}
+
+// Every chunk of a stream must name the namespace the first one did, so a stream can't
+// load one tree with another's buckets
+#[test]
+fn bulk_chunks_for_another_namespace_are_rejected() {
+    let server = testutil::InProcessServer::start();
+    let mut handler = set_up(server.backend("bulk"), 16, None);
+    let _other = set_up(server.backend("other"), 16, None);
+    let mut backend = server.backend("bulk");
+    backend.begin_bulk().unwrap();
+    backend.send_buckets(0, 1, dummy_bucket()).unwrap();
+    backend.set_namespace("other".to_string());
+    let status = backend
+        .send_buckets(1, 1, dummy_bucket())
+        .and_then(|()| backend.finish_bulk())
+        .unwrap_err();
+    assert_eq!(status.code(), tonic::Code::InvalidArgument);
+    for a in 0..16 {
+        handler.read_expecting(a, Some(a));
+    }
+}
+
+#[test]
+fn flush_needs_a_snapshot_dir() {
+    let server = testutil::InProcessServer::start_with(testutil::server::MyPathOram::default);
+    let mut handler = set_up(server.backend(""), 8, None);
+    let status = handler
+        .backend_mut()
+        .flush("run.server".into())
+        .unwrap_err();
+    assert_eq!(status.code(), tonic::Code::FailedPrecondition);
+}
+
+#[test]
+fn setup_rejects_an_empty_tree() {
+    let server = testutil::InProcessServer::start();
+    let mut backend = server.backend("");
+    let status = backend.setup(0, GOLDEN_Z, 16).unwrap_err();
+    assert_eq!(status.code(), tonic::Code::InvalidArgument);
+}
+
+#[test]
+fn setup_of_one_layer_holds_one_bucket() {
+    let server = testutil::InProcessServer::start();
+    let mut backend = server.backend("");
+    assert!(backend.setup(1, GOLDEN_Z, 16).unwrap());
+    let stats = backend.stats().unwrap();
+    assert_eq!(stats.num_buckets, 1);
+    assert_eq!(stats.total_blocks, GOLDEN_Z as i64);
+    assert!(backend.print().unwrap());
+}
+
+// Trees too big to allocate are turned away before the server locks the tree, which
+// stays usable afterwards.
+#[test]
+fn setup_rejects_trees_over_the_block_limit() {
+    let server = testutil::InProcessServer::start_with(|| {
+        let mut path_oram = testutil::server::MyPathOram::default();
+        path_oram.set_max_blocks(1 << 10);
+        path_oram
+    });
+    let mut backend = server.backend("");
+    for (num_layers, bucket_size) in [(63, GOLDEN_Z), (10, GOLDEN_Z), (1, i32::MAX)] {
+        let status = backend.setup(num_layers, bucket_size, 16).unwrap_err();
+        assert_eq!(
+            status.code(),
+            tonic::Code::InvalidArgument,
+            "L={} Z={}",
+            num_layers,
+            bucket_size
+        );
+    }
+    assert!(backend.setup(8, GOLDEN_Z, 16).unwrap());
+    assert_eq!(backend.stats().unwrap().num_buckets, 255);
+}
+
+#[test]
+fn write_returns_the_value_it_replaces() {
+    let mut handler = set_up(LocalBackend::new(), 16, None);
+    assert_eq!(handler.write(3, 30).unwrap(), Some(3));
+    assert_eq!(handler.write(3, 31).unwrap(), Some(30));
+    // Once the block has been evicted back out of the stash too
+    for a in 0..16 {
+        handler.read(a).unwrap();
+    }
+    assert_eq!(handler.write(3, 32).unwrap(), Some(31));
+}
+
+#[cfg(target_pointer_width = "64")]
+#[test]
+fn oversized_counts_report_their_own_value() {
+    let e = convert::to_i64(usize::MAX).unwrap_err();
+    assert_eq!(
+        e.to_string(),
+        format!("count {} is out of range", usize::MAX)
+    );
+}
+
+// Index math for a tree of 2^40 leaves, far past where 32-bit shifts overflow. Only the
+// height is set; nothing that big is allocated.
+#[test]
+fn path_indices_of_a_40_level_tree() {
+    let mut handler = PathORAMHandler::new(LocalBackend::new(), GOLDEN_Z, GOLDEN_SEED);
+    handler.l = 40;
+    let last = (1_i64 << 40) - 1;
+    assert_eq!(handler.get_index(0, 40), last);
+    assert_eq!(handler.get_index(last, 40), (1 << 41) - 2);
+    assert_eq!(handler.get_index(last, 31), (1 << 32) - 2);
+    assert_eq!(handler.get_index(1 << 39, 1), 2);
+    let path: Vec<i64> = handler.path_bucket_indices(last).collect();
+    assert_eq!(path.len(), 41);
+    for (l, index) in path.into_iter().enumerate() {
+        assert_eq!(index, (1 << (l + 1)) - 2, "level {}", l);
+    }
+    assert_eq!(handler.common_depth(0, last), 0);
+    assert_eq!(handler.common_depth(last - 1, last), 39);
+}
+
+// Bad writes and reads fail with INVALID_ARGUMENT and leave the tree as it was. Run against
+// the server and against `LocalBackend`, which must follow the same rules.
+fn assert_rejects_bad_requests(backend: &mut impl OramBackend) {
+    for (num_layers, bucket_size, block_size) in [(0, GOLDEN_Z, 16), (3, 0, 16), (3, -1, 16)] {
+        let status = backend
+            .setup(num_layers, bucket_size, block_size)
+            .unwrap_err();
+        assert_eq!(
+            status.code(),
+            tonic::Code::InvalidArgument,
+            "setup L={} Z={} B={}",
+            num_layers,
+            bucket_size,
+            block_size
+        );
+    }
+    assert!(backend.setup(3, GOLDEN_Z, 16).unwrap());
+    let indices = vec![0, 1];
+    let full = indices.len() * GOLDEN_Z as usize;
+    let written = |count: usize, width: usize| {
+        (0..count)
+            .map(|i| Block {
+                index: i as i64,
+                data: vec![1; width],
+            })
+            .collect::<Vec<_>>()
+    };
+
+    let bad_writes = [
+        (indices.clone(), written(0, 16)),
+        (indices.clone(), written(full - 1, 16)),
+        (indices.clone(), written(full + 1, 16)),
+        (indices.clone(), written(full, 15)),
+        (vec![0, 7], written(full, 16)),
+        (vec![-1, 1], written(full, 16)),
+    ];
+    for (indices, blocks) in bad_writes {
+        let count = blocks.len();
+        let status = backend.write_path(indices.clone(), blocks).unwrap_err();
+        assert_eq!(
+            status.code(),
+            tonic::Code::InvalidArgument,
+            "{} blocks to {:?}",
+            count,
+            indices
+        );
+    }
+    let status = backend.read_path(vec![0, 7]).unwrap_err();
+    assert_eq!(status.code(), tonic::Code::InvalidArgument);
+    let blocks = backend.read_path(vec![0, 1, 2, 3, 4, 5, 6]).unwrap();
+    assert!(blocks.iter().all(|block| block.index == -1));
+
+    assert!(backend
+        .write_path(indices.clone(), written(full, 16))
+        .unwrap());
+    assert_eq!(backend.read_path(indices).unwrap(), written(full, 16));
+}
+
+#[test]
+fn server_rejects_bad_requests() {
+    let server = testutil::InProcessServer::start();
+    assert_rejects_bad_requests(&mut server.backend(""));
+}
+
+#[test]
+fn local_backend_rejects_bad_requests() {
+    assert_rejects_bad_requests(&mut LocalBackend::new());
+}
+
+#[test]
+fn addresses_outside_the_tree_are_rejected() {
+    let n = 16;
+    let mut handler = set_up(LocalBackend::new(), n, None);
+    for a in [n, -1] {
+        assert!(matches!(
+            handler.read(a),
+            Err(OramError::AddressOutOfRange { a: got, n: 16 }) if got == a
+        ));
+        assert!(matches!(
+            handler.write(a, 99),
+            Err(OramError::AddressOutOfRange { a: got, n: 16 }) if got == a
+        ));
+    }
+    handler.read_expecting(n - 1, Some(n - 1));
+    handler.validate_invariant().unwrap();
+}
+
+// The single-bucket tree (N = 1, L = 0) and the three-bucket one (N = 2, L = 1)
+#[test]
+fn path_indices_of_the_smallest_trees() {
+    let mut handler = set_up(LocalBackend::new(), 1, None);
+    assert_eq!(handler.l, 0);
+    assert_eq!(handler.get_index(0, 0), 0);
+    assert_eq!(handler.path_bucket_indices(0).collect::<Vec<_>>(), [0]);
+    assert_eq!(handler.write(0, 7).unwrap(), Some(0));
+    handler.read_expecting(0, Some(7));
+
+    let mut handler = set_up(LocalBackend::new(), 2, None);
+    assert_eq!(handler.l, 1);
+    assert_eq!(handler.get_index(0, 1), 1);
+    assert_eq!(handler.get_index(1, 1), 2);
+    assert_eq!(handler.path_bucket_indices(0).collect::<Vec<_>>(), [0, 1]);
+    assert_eq!(handler.path_bucket_indices(1).collect::<Vec<_>>(), [0, 2]);
+    assert_eq!(handler.write(1, 7).unwrap(), Some(1));
+    handler.read_expecting(1, Some(7));
+    handler.read_expecting(0, Some(0));
+}
+
+// One bucket of 1 MiB blocks is a 5 MiB message, over tonic's default 4 MiB limit.
+#[test]
+fn large_buckets_need_a_raised_message_limit() {
+    let (z, block_size) = (5, 1 << 20);
+    let bucket: Vec<Block> = (0..z)
+        .map(|i| Block {
+            index: i,
+            data: vec![i as u8; block_size],
+        })
+        .collect();
+
+    let server = testutil::InProcessServer::start();
+    let mut backend = server.backend("");
+    assert!(backend.setup(1, z as i32, block_size as i32).unwrap());
+    let status = backend.write_path(vec![0], bucket.clone()).unwrap_err();
+    assert_eq!(status.code(), tonic::Code::OutOfRange);
+
+    let server = testutil::InProcessServer::start_with_message_limit(8 << 20);
+    let mut backend = server.backend("");
+    assert!(backend.setup(1, z as i32, block_size as i32).unwrap());
+    assert!(backend.write_path(vec![0], bucket.clone()).unwrap());
+    assert_eq!(backend.read_path(vec![0]).unwrap(), bucket);
+}
+
+// -1 is also the dummy index, but only an index marks a slot empty
+#[test]
+fn minus_one_is_an_ordinary_value() {
+    for mut handler in [
+        set_up(LocalBackend::new(), 16, None),
+        set_up(LocalBackend::new(), 16, Some(&TEST_KEY)),
+    ] {
+        assert_eq!(handler.write(5, -1).unwrap(), Some(5));
+        handler.read_expecting(5, Some(-1));
+        for a in 0..16 {
+            handler.read(a).unwrap();
+        }
+        handler.read_expecting(5, Some(-1));
+        handler.validate_invariant().unwrap();
+    }
+}
+
+// Stash sizes after each of a fixed run of accesses, and the tree the run leaves behind.
+fn seeded_run(seed: u64) -> (Vec<usize>, Vec<Vec<Block>>) {
+    let n = 1 << 8;
+    let mut handler = PathORAMHandler::new(LocalBackend::new(), GOLDEN_Z, seed);
+    handler.setup((0..n).collect()).unwrap();
+    let mut sizes = Vec::new();
+    for i in 0..2000 {
+        let a = (i * 37) % n;
+        if i % 3 == 0 {
+            handler.write(a, i).unwrap();
+        } else {
+            handler.read(a).unwrap();
+        }
+        sizes.push(handler.stash_size());
+    }
+    (sizes, handler.backend_mut().data_store.clone())
+}
+
+#[test]
+fn same_seed_gives_the_same_run() {
+    assert_eq!(seeded_run(GOLDEN_SEED), seeded_run(GOLDEN_SEED));
+    assert_eq!(seeded_run(1), seeded_run(1));
+}
+
+// N = 4 gives a tree of three levels (L = 2): root 0, then 1 and 2, then leaves 3 to 6.
+#[test]
+fn path_indices_of_a_three_level_tree() {
+    let handler = set_up(LocalBackend::new(), 4, None);
+    assert_eq!(handler.l, 2);
+    let paths: Vec<Vec<i64>> = (0..4)
+        .map(|x| handler.path_bucket_indices(x).collect())
+        .collect();
+    assert_eq!(paths, [[0, 1, 3], [0, 1, 4], [0, 2, 5], [0, 2, 6]]);
+
+    // And an access reads exactly the path of the block's leaf
+    let mut handler = set_up(MockBackend::new(), 4, None);
+    handler.backend_mut().take_calls();
+    for a in 0..4 {
+        let path = paths[handler.leaf_of(a) as usize].clone();
+        handler.read(a).unwrap();
+        let calls = handler.backend_mut().take_calls();
+        let BackendCall::ReadPath(read) = &calls[0] else {
+            panic!("expected a path read first, got {:?}", calls);
+        };
+        let mut read = read.clone();
+        read.sort_unstable();
+        assert_eq!(read, path, "address {}", a);
+    }
+}
+
+// The real server over TLS on a loopback port, with the certificate from testdata/tls
+// (see generate.sh there), and a client that connects with `--ca-cert` and `--domain`.
+#[test]
+fn setup_and_read_over_tls() {
+    use tonic::transport::server::TcpIncoming;
+    use tonic::transport::{Identity, Server, ServerTlsConfig};
+
+    let tls = Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/tls");
+    let identity = Identity::from_pem(
+        std::fs::read(tls.join("server.pem")).unwrap(),
+        std::fs::read(tls.join("server.key")).unwrap(),
+    );
+    let rt = Runtime::new().unwrap();
+    let listener = rt
+        .block_on(tokio::net::TcpListener::bind("127.0.0.1:0"))
+        .unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let incoming = {
+        let _runtime = rt.enter();
+        TcpIncoming::from_listener(listener, true, None).unwrap()
+    };
+    rt.spawn(
+        Server::builder()
+            .tls_config(ServerTlsConfig::new().identity(identity))
+            .unwrap()
+            .add_service(
+                testutil::server::path_oram::path_oram_server::PathOramServer::new(
+                    testutil::server::MyPathOram::default(),
+                ),
+            )
+            .serve_with_incoming(incoming),
+    );
+
+    let args = Args::try_parse_from([
+        "client",
+        "--n",
+        "4",
+        "--z",
+        "2",
+        "--b",
+        "8",
+        "--host",
+        "127.0.0.1",
+        "--port",
+        &port.to_string(),
+        "--ca-cert",
+        tls.join("ca.pem").to_str().unwrap(),
+        "--domain",
+        "localhost",
+    ])
+    .unwrap();
+    let client = connect(&rt, &args).unwrap();
+    let backend = GrpcBackend::new(client, rt.handle().clone());
+    let mut handler = set_up(backend, 16, None);
+    handler.read_expecting(7, Some(7));
+    assert_eq!(handler.write(7, 70).unwrap(), Some(7));
+    handler.read_expecting(7, Some(70));
+}
+
+#[test]
+fn move_to_an_empty_address() {
+    let mut handler = set_up(LocalBackend::new(), 16, None);
+    handler.delete(5).unwrap();
+    assert_eq!(handler.move_block(1, 5).unwrap(), None);
+    handler.read_expecting(1, None);
+    handler.read_expecting(5, Some(1));
+    handler.validate_invariant().unwrap();
+}
+
+#[test]
+fn move_overwrites_and_returns_the_old_value() {
+    let mut handler = set_up(LocalBackend::new(), 16, None);
+    assert_eq!(handler.move_block(1, 2).unwrap(), Some(2));
+    handler.read_expecting(1, None);
+    handler.read_expecting(2, Some(1));
+    handler.validate_invariant().unwrap();
+}
+
+#[test]
+fn move_onto_itself_keeps_the_value() {
+    let mut handler = set_up(LocalBackend::new(), 16, None);
+    assert_eq!(handler.move_block(3, 3).unwrap(), Some(3));
+    handler.read_expecting(3, Some(3));
+    handler.validate_invariant().unwrap();
+}
+
+// A move is one access: both paths are read before anything changes, so a failed read
+// leaves both addresses alone, and a failed write-back keeps the moved value in the stash
+#[test]
+fn failed_moves_lose_no_value() {
+    let mut handler = set_up(LocalBackend::new(), 16, Some(&TEST_KEY));
+    let (bucket, saved) = garble_leaf_bucket(&mut handler, 2);
+    assert!(handler.move_block(1, 2).is_err());
+    handler.backend_mut().data_store[bucket] = saved;
+    handler.read_expecting(1, Some(1));
+    handler.read_expecting(2, Some(2));
+
+    let mut handler = set_up(FlakyBackend::default(), 16, None);
+    handler.backend_mut().fail_writes = true;
+    assert!(matches!(handler.move_block(1, 2), Err(OramError::Rpc(_))));
+    handler.backend_mut().fail_writes = false;
+    handler.read_expecting(1, None);
+    handler.read_expecting(2, Some(1));
+    handler.validate_invariant().unwrap();
+}
+
+// Futures launched together over the in-process server, gathered with `join_all`
+#[test]
+fn pipelined_futures_apply_in_queue_order() {
+    use futures_util::future::join_all;
+    use std::pin::Pin;
+    type Access = Pin<Box<dyn Future<Output = Result<Option<i64>, OramError>>>>;
+
+    let server = testutil::InProcessServer::start();
+    let handler = set_up(server.backend(""), 16, None);
+    let oram = AsyncPathORAM::new(handler, 64, Duration::from_millis(20));
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+
+    let reads = rt.block_on(join_all((0..16).map(|a| oram.read_future(a))));
+    for (a, value) in reads.into_iter().enumerate() {
+        assert_eq!(value.unwrap(), Some(a as i64));
+    }
+
+    let same_address: Vec<Access> = vec![
+        Box::pin(oram.write_future(3, 30)),
+        Box::pin(oram.read_future(3)),
+        Box::pin(oram.write_future(3, 31)),
+        Box::pin(oram.read_future(3)),
+    ];
+    let same_address = rt.block_on(join_all(same_address));
+    let values: Vec<_> = same_address.into_iter().map(Result::unwrap).collect();
+    assert_eq!(values, [Some(3), Some(30), Some(30), Some(31)]);
+
+    // A bad address fails alone, not the accesses batched with it
+    let mixed: Vec<Access> = vec![
+        Box::pin(oram.read_future(1)),
+        Box::pin(oram.read_future(16)),
+        Box::pin(oram.write_future(2, 20)),
+    ];
+    let mixed = rt.block_on(join_all(mixed));
+    assert_eq!(mixed[0].as_ref().unwrap(), &Some(1));
+    assert!(matches!(
+        mixed[1],
+        Err(OramError::AddressOutOfRange { a: 16, n: 16 })
+    ));
+    assert_eq!(mixed[2].as_ref().unwrap(), &Some(2));
+    assert_eq!(rt.block_on(oram.read_future(2)).unwrap(), Some(20));
+}
+
+// Zeroes the sealed block holding `a`, the way a server would forge an empty slot.
+// Returns its bucket, or None if `a` isn't in the tree (it's stashed).
+fn zero_sealed_block(handler: &mut PathORAMHandler<LocalBackend>, a: i64) -> Option<i64> {
+    for bucket in 0..handler.backend_mut().data_store.len() {
+        for i in 0..GOLDEN_Z as usize {
+            let block = handler.backend_mut().data_store[bucket][i].clone();
+            if handler.open(bucket as i64, block).unwrap().index == a {
+                handler.backend_mut().data_store[bucket][i].data.fill(0);
+                return Some(bucket as i64);
+            }
+        }
+    }
+    None
+}
+
+#[test]
+fn encrypted_setup_seals_every_block() {
+    let mut handler = set_up(LocalBackend::new(), 16, Some(&TEST_KEY));
+    let store = &handler.backend_mut().data_store;
+    assert!(store
+        .iter()
+        .flatten()
+        .all(|block| block.data.iter().any(|&byte| byte != 0)));
+}
+
+// A real block zeroed by the server must fail to open rather than read as empty, with a
+// flat position map and, where no `written` marks back it up, a recursive one
+#[test]
+fn zeroed_sealed_blocks_are_rejected() {
+    let n = 16;
+    let flat = set_up(LocalBackend::new(), n, Some(&TEST_KEY));
+    let mut recursive = PathORAMHandler::new(LocalBackend::new(), GOLDEN_Z, GOLDEN_SEED);
+    recursive.set_key(&TEST_KEY);
+    recursive
+        .setup_recursive((0..n).collect(), 2, 2, &mut LocalBackend::new)
+        .unwrap();
+    for mut handler in [flat, recursive] {
+        let (a, bucket) = (0..n)
+            .find_map(|a| Some((a, zero_sealed_block(&mut handler, a)?)))
+            .expect("every block is stashed");
+        match handler.read(a) {
+            Err(OramError::Corruption { index }) => assert_eq!(index, bucket),
+            other => panic!("read of a zeroed block gave {:?}", other),
+        }
+    }
+}
+
+// The shadow model starts from the values setup wrote, which a preloaded stash can replace
+#[test]
+fn shadow_check_conflicts_with_stash_preloads() {
+    let base = ["client", "--n", "4", "--z", "2", "--b", "8"];
+    let both = Args::try_parse_from(base.iter().chain(&[
+        "--shadow-check",
+        "--preload-stash",
+        "stash.txt",
+    ]));
+    assert_eq!(
+        both.unwrap_err().kind(),
+        clap::error::ErrorKind::ArgumentConflict
+    );
+    assert!(Args::try_parse_from(base.iter().chain(&["--shadow-check"])).is_ok());
+}
+
+// Leaves handed out in a fixed order, for driving the tree into a known state.
+struct ScriptedLeaves(std::collections::VecDeque<i64>);
+
+impl LeafChooser for ScriptedLeaves {
+    fn next_leaf(&mut self, num_leaves: i64) -> i64 {
+        let leaf = self.0.pop_front().expect("script ran out of leaves");
+        assert!(leaf < num_leaves, "scripted leaf {} outside the tree", leaf);
+        leaf
+    }
+}
+
+// Setup draws the initial leaves 0..4 and then remaps addresses 0, 1, 2 and 3 to leaves
+// 3, 3, 1 and 0 as it writes them. Writing 2 back along the path to leaf 2 sinks 0 and 1
+// (both bound for leaf 3) to level 1; writing 3 back along the path to leaf 3 sinks them
+// to the leaf, while 2 and 3, whose leaves only share the root with 3's, stay up there.
+#[test]
+fn scripted_leaves_build_a_known_tree() {
+    let dir = ScratchDir::new("scripted_tree");
+    let server = testutil::InProcessServer::start_with(|| {
+        let mut path_oram = testutil::server::MyPathOram::default();
+        path_oram.set_print_dir(Some(dir.0.clone()));
+        path_oram
+    });
+    let script = ScriptedLeaves([0, 1, 2, 3, 3, 3, 1, 0].into());
+    let mut handler = PathORAMHandler::with_leaf_chooser(
+        server.backend(""),
+        GOLDEN_Z,
+        GOLDEN_SEED,
+        Box::new(script),
+    );
+    handler.setup(vec![0x10, 0x11, 0x12, 0x13]).unwrap();
+    assert_eq!(handler.pmap, [3, 3, 1, 0]);
+    assert!(handler.stash.is_empty());
+
+    handler
+        .backend_mut()
+        .print_to_file("tree.txt".into())
+        .unwrap();
+    let tree = std::fs::read_to_string(dir.0.join("tree.txt")).unwrap();
+    let expected: String = [
+        "         (12000000..,2)",
+        "         (13000000..,3)",
+        "",
+        "   (_,_)       (_,_)",
+        "   (_,_)       (_,_)",
+        "",
+        "(_,_) (_,_) (_,_) (10000000..,0)",
+        "(_,_) (_,_) (_,_) (11000000..,1)",
+        "",
+    ]
+    .iter()
+    .map(|line| format!("{}\n", line))
+    .collect();
+    assert_eq!(tree, expected);
+}
+
+// The 16-byte record from the `BlockCodec` docs
+#[derive(Debug, PartialEq)]
+struct Account {
+    id: u64,
+    balance: i64,
+}
+
+impl BlockCodec for Account {
+    fn to_bytes(&self) -> Vec<u8> {
+        [self.id.to_le_bytes(), self.balance.to_le_bytes()].to_bytes()
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, OramError> {
+        let [id, balance] = <[[u8; 8]; 2]>::from_bytes(bytes)?;
+        Ok(Account {
+            id: u64::from_le_bytes(id),
+            balance: i64::from_le_bytes(balance),
+        })
+    }
+}
+
+// Records, strings and byte arrays go through `TypedOram` and come back as written; a
+// value wider than B is refused and leaves the stored one alone
+#[test]
+fn typed_values_round_trip() {
+    let mut handler = PathORAMHandler::new(LocalBackend::new(), GOLDEN_Z, GOLDEN_SEED);
+    handler.set_block_size(40);
+    handler.setup((0..16).collect()).unwrap();
+
+    let mut accounts: TypedOram<_, Account> = TypedOram::new(handler);
+    // Setup stored 7 as an i64, which reads as an id with a zero balance
+    let old = accounts
+        .write(
+            7,
+            &Account {
+                id: 7,
+                balance: -40,
+            },
+        )
+        .unwrap();
+    assert_eq!(old, Some(Account { id: 7, balance: 0 }));
+    assert_eq!(
+        accounts.read(7).unwrap(),
+        Some(Account {
+            id: 7,
+            balance: -40
+        })
+    );
+
+    let mut strings: TypedOram<_, String> = TypedOram::new(accounts.into_inner());
+    let text = "nul\0inside".to_string();
+    strings.write(8, &text).unwrap();
+    assert_eq!(strings.read(8).unwrap(), Some(text.clone()));
+    let oversized = "x".repeat(40);
+    assert!(matches!(
+        strings.write(8, &oversized),
+        Err(OramError::Codec(_))
+    ));
+    assert_eq!(strings.read(8).unwrap(), Some(text));
+
+    let mut arrays: TypedOram<_, [u8; 32]> = TypedOram::new(strings.into_inner());
+    let bytes: [u8; 32] = std::array::from_fn(|i| i as u8 * 7);
+    arrays.write(9, &bytes).unwrap();
+    assert_eq!(arrays.read(9).unwrap(), Some(bytes));
+
+    let mut wide: TypedOram<_, [u8; 48]> = TypedOram::new(arrays.into_inner());
+    assert!(matches!(wide.write(9, &[1; 48]), Err(OramError::Codec(_))));
+    let mut handler = wide.into_inner();
+    handler.validate_invariant().unwrap();
+    handler.read_expecting(3, Some(3));
+}
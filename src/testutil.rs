@@ -0,0 +1,125 @@
+// In-process harness: the real server (`MyPathOram` from server.rs) behind a tonic channel
+// made of in-memory pipes, so `PathORAMHandler` can be driven end to end over gRPC
+// without binding a port. Every connection the channel opens gets its own
+// `tokio::io::duplex` pair, handed to the server through an mpsc queue standing in for
+// a listener, so reconnects work the same as over TCP.
+//
+// Only compiled for tests.
+
+use std::io;
+use std::sync::Arc;
+
+use hyper_util::rt::TokioIo;
+use tokio::runtime::Runtime;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tonic::service::interceptor::InterceptedService;
+use tonic::transport::{Endpoint, Server, Uri};
+use tower::service_fn;
+
+use crate::path_oram::path_oram_client::PathOramClient;
+use crate::{GrpcBackend, OramClient, ProxyHeaders};
+
+// The server binary, compiled in as a module. Its generated proto types are its own, which
+// is fine: they only ever meet the client's on the wire. What only its `main` uses is
+// marked as allowed to go unused under cfg(test).
+#[path = "server.rs"]
+pub mod server;
+
+// Bytes buffered in each direction of an in-memory connection.
+const PIPE_BYTES: usize = 1 << 20;
+
+// A running in-process server and a client channel to it. Backends made from it use its
+// runtime, so they must not outlive it.
+pub struct InProcessServer {
+    rt: Runtime,
+    client: OramClient,
+    path_oram: Arc<server::MyPathOram>,
+}
+
+impl InProcessServer {
+    // A fresh server with the default configuration.
+    pub fn start() -> Self {
+        InProcessServer::start_with(server::MyPathOram::default)
+    }
+
+    // Serves the server `make` builds, e.g. one with write coalescing enabled or restored
+    // from a snapshot. `make` runs inside the harness runtime, so it can spawn tasks.
+    pub fn start_with(make: impl FnOnce() -> server::MyPathOram) -> Self {
+        InProcessServer::serve(make, None)
+    }
+
+    // A fresh server whose messages, and the client's, may be up to `limit` bytes, as with
+    // --max-message-bytes on both ends.
+    pub fn start_with_message_limit(limit: usize) -> Self {
+        InProcessServer::serve(server::MyPathOram::default, Some(limit))
+    }
+
+    fn serve(make: impl FnOnce() -> server::MyPathOram, message_limit: Option<usize>) -> Self {
+        let rt = Runtime::new().expect("failed to start the harness runtime");
+        let path_oram = {
+            let _runtime = rt.enter();
+            Arc::new(make())
+        };
+        let (connections, incoming) =
+            mpsc::unbounded_channel::<io::Result<tokio::io::DuplexStream>>();
+        let mut service =
+            server::path_oram::path_oram_server::PathOramServer::from_arc(Arc::clone(&path_oram));
+        if let Some(limit) = message_limit {
+            service = service
+                .max_decoding_message_size(limit)
+                .max_encoding_message_size(limit);
+        }
+        rt.spawn(
+            Server::builder()
+                .add_service(service)
+                .serve_with_incoming(UnboundedReceiverStream::new(incoming)),
+        );
+
+        // The URI is never resolved; the connector below ignores it
+        let channel = rt
+            .block_on(
+                Endpoint::from_static("http://in-process.invalid").connect_with_connector(
+                    service_fn(move |_: Uri| {
+                        let (client_io, server_io) = tokio::io::duplex(PIPE_BYTES);
+                        let accepted = connections.send(Ok(server_io));
+                        async move {
+                            accepted.map_err(|_| {
+                                io::Error::new(io::ErrorKind::ConnectionRefused, "server stopped")
+                            })?;
+                            Ok::<_, io::Error>(TokioIo::new(client_io))
+                        }
+                    }),
+                ),
+            )
+            .expect("in-memory connection failed");
+        let mut client =
+            PathOramClient::new(InterceptedService::new(channel, ProxyHeaders::default()));
+        if let Some(limit) = message_limit {
+            client = client
+                .max_decoding_message_size(limit)
+                .max_encoding_message_size(limit);
+        }
+        InProcessServer {
+            rt,
+            client,
+            path_oram,
+        }
+    }
+
+    // The server being served, e.g. for taking a snapshot of it.
+    pub fn path_oram(&self) -> &server::MyPathOram {
+        &self.path_oram
+    }
+
+    pub fn client(&self) -> OramClient {
+        self.client.clone()
+    }
+
+    // A backend on the tree named `namespace` ("" is the default).
+    pub fn backend(&self, namespace: &str) -> GrpcBackend {
+        let mut backend = GrpcBackend::new(self.client(), self.rt.handle().clone());
+        backend.set_namespace(namespace.to_string());
+        backend
+    }
+}
@@ -0,0 +1,11 @@
+#!/bin/sh
+# Regenerates the TLS test fixtures: a CA and a certificate for `localhost` signed by it,
+# both valid for 100 years. Run from this directory.
+set -e
+openssl req -x509 -newkey rsa:2048 -nodes -days 36500 -subj "/CN=Path ORAM test CA" \
+    -keyout ca.key -out ca.pem
+openssl req -newkey rsa:2048 -nodes -subj "/CN=localhost" -keyout server.key -out server.csr
+printf "subjectAltName=DNS:localhost\nbasicConstraints=CA:FALSE\n" > server.ext
+openssl x509 -req -in server.csr -CA ca.pem -CAkey ca.key -CAcreateserial -days 36500 \
+    -extfile server.ext -out server.pem
+rm -f ca.key ca.srl server.csr server.ext